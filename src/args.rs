@@ -1,7 +1,12 @@
-use clap::{AppSettings, Args as ClapArgs, IntoApp, Parser, Subcommand};
+use clap::{AppSettings, ArgEnum, Args as ClapArgs, IntoApp, Parser, Subcommand};
 use clap_complete::Shell;
 
+use crate::components::gitlab::types::IssueReference;
+use crate::logging::LogFormat;
+use crate::plan::{DiffStyle, OutputFormat};
+
 use std::io::stdout;
+use std::path::PathBuf;
 
 use anyhow::Result;
 
@@ -14,17 +19,198 @@ pub struct Args {
     #[clap(short, long, parse(from_occurrences))]
     pub verbose: u8,
 
+    /// Format of the console and log file output
+    #[clap(long, arg_enum, default_value = "text")]
+    pub log_format: LogFormat,
+
+    /// Additionally write debug logs to a rotating log file
+    #[clap(long, value_name = "FILE", parse(from_os_str))]
+    pub log_file: Option<PathBuf>,
+
+    /// Size in MiB after which the log file is rotated
+    #[clap(long, value_name = "MIB", default_value = "10")]
+    pub log_file_max_size: u64,
+
+    /// Format of the plan output
+    #[clap(long, arg_enum, default_value = "text")]
+    pub output: OutputFormat,
+
+    /// Diff style of the text plan output
+    #[clap(long, arg_enum, default_value = "unified")]
+    pub diff_style: DiffStyle,
+
+    /// Mask emails in plan output and logs, the event log keeps full detail
+    #[clap(long)]
+    pub redact: bool,
+
+    /// Only show resources that have changes
+    #[clap(long)]
+    pub changes_only: bool,
+
+    /// Group planned changes by resource type instead of traversal order
+    #[clap(long)]
+    pub group_by_type: bool,
+
+    /// Cache gathered state on disk to speed up consecutive runs
+    #[clap(long)]
+    pub cache: bool,
+
+    /// Seconds for which cached Keycloak state is used
+    #[clap(long, default_value = "3600")]
+    pub keycloak_cache_ttl: u64,
+
+    /// Seconds for which cached GitLab user ids are used
+    #[clap(long, default_value = "86400")]
+    pub gitlab_cache_ttl: u64,
+
+    /// Seconds for which the cached GitLab subgroup tree is used
+    #[clap(long, default_value = "3600")]
+    pub gitlab_groups_cache_ttl: u64,
+
+    /// Persist a membership snapshot and report churn since the previous one
+    #[clap(long, value_name = "FILE", parse(from_os_str))]
+    pub snapshot: Option<PathBuf>,
+
+    /// Write the consolidated state as JSON to a file after gathering
+    #[clap(long, value_name = "FILE", parse(from_os_str))]
+    pub dump_state: Option<PathBuf>,
+
+    /// Write plan and apply events as JSON lines to a file
+    #[clap(long, value_name = "FILE", parse(from_os_str))]
+    pub events: Option<PathBuf>,
+
+    /// Create or update a comment with the markdown report on a GitLab issue
+    #[clap(long, value_name = "PROJECT#IID")]
+    pub report_issue: Option<IssueReference>,
+
+    /// Write the per component totals, requests and cache hits as JSON to a file
+    #[clap(long, value_name = "FILE", parse(from_os_str))]
+    pub metrics: Option<PathBuf>,
+
+    /// Exit with a distinct code if any change was planned
+    #[clap(long)]
+    pub fail_on_drift: bool,
+
+    /// Exit like terraform with 0 without changes, 1 on any failure and 2 if changes were planned
+    #[clap(long, conflicts_with = "fail_on_drift")]
+    pub detailed_exitcode: bool,
+
+    /// Write a JUnit XML report with one test case per checked section
+    #[clap(long, value_name = "FILE", parse(from_os_str))]
+    pub junit: Option<PathBuf>,
+
+    /// Write generated files like authorized_keys fragments to a directory
+    #[clap(long, value_name = "DIR", parse(from_os_str))]
+    pub output_dir: Option<PathBuf>,
+
+    /// Propose the generated files that changed as a merge request on a GitLab project
+    #[clap(long, value_name = "PROJECT", requires = "output_dir")]
+    pub merge_request: Option<String>,
+
+    /// Only run the given GitLab enforcements, can be specified multiple times
+    #[clap(long, arg_enum, value_name = "CLASS", multiple_occurrences = true)]
+    pub enforcement: Vec<Enforcement>,
+
     #[clap(subcommand)]
     pub command: Command,
 }
 
+/// GitLab enforcements that can be scheduled separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum Enforcement {
+    /// Team group and infrastructure project members, cheap
+    Members,
+    /// Access levels and settings of all groups and projects below the root group, expensive
+    Sweep,
+    /// Container registry cleanup
+    Registry,
+    /// Blocking the authors of issues labeled as spam in the packaging group
+    Spam,
+}
+
+/// Components that plan and apply changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum ComponentKind {
+    Keycloak,
+    Gitlab,
+    Grafana,
+    Matrix,
+    Vault,
+    Wiki,
+    Files,
+}
+
+/// Components a plan or apply runs. Keycloak and GitLab are always gathered,
+/// as the others depend on their state.
+#[derive(Debug, ClapArgs)]
+pub struct ComponentSelection {
+    /// Only run the given components, separated with commas
+    #[clap(long, arg_enum, value_name = "COMPONENT", use_value_delimiter = true)]
+    pub only: Vec<ComponentKind>,
+
+    /// Do not run the given components, separated with commas
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "COMPONENT",
+        use_value_delimiter = true,
+        conflicts_with = "only"
+    )]
+    pub skip: Vec<ComponentKind>,
+}
+
+impl ComponentSelection {
+    pub fn includes(&self, kind: ComponentKind) -> bool {
+        (self.only.is_empty() || self.only.contains(&kind)) && !self.skip.contains(&kind)
+    }
+}
+
+/// Part of the group tree the GitLab sweep walks.
+#[derive(Debug, ClapArgs)]
+pub struct GitlabScope {
+    /// Only sweep the given group and its descendants, by full path
+    #[clap(long, value_name = "PATH", global = true)]
+    pub group: Option<String>,
+
+    /// Only sweep the given projects, by full path, can be specified multiple times
+    #[clap(long, value_name = "PATH", global = true, multiple_occurrences = true)]
+    pub project: Vec<String>,
+}
+
 #[derive(Debug, Subcommand)]
 pub enum Command {
     /// Generate and show an execution plan
-    Plan,
+    Plan {
+        /// Plan the team group members and generated files from the cached Keycloak state and the
+        /// --snapshot of a previous run, without any network access or credentials
+        #[clap(long)]
+        offline: bool,
+        /// Review the planned changes one by one and apply only the approved ones afterwards
+        #[clap(long, conflicts_with = "offline")]
+        interactive: bool,
+        /// Write the planned changes as JSON plan to a file, to apply them with apply --plan
+        #[clap(
+            long,
+            value_name = "FILE",
+            parse(from_os_str),
+            conflicts_with = "interactive"
+        )]
+        out: Option<PathBuf>,
+        #[clap(flatten)]
+        selection: ComponentSelection,
+        #[clap(subcommand)]
+        command: Option<PlanCommand>,
+    },
 
     /// Builds or changes infrastructure
-    Apply,
+    Apply {
+        /// Apply only the changes of a plan written with plan --out, refusing to apply anything
+        /// if the live state diverged since
+        #[clap(long, value_name = "FILE", parse(from_os_str))]
+        plan: Option<PathBuf>,
+        #[clap(flatten)]
+        selection: ComponentSelection,
+    },
 
     /// Keycloak module commands
     Keycloak {
@@ -34,15 +220,83 @@ pub enum Command {
 
     /// Gitlab module commands
     Gitlab {
+        #[clap(flatten)]
+        scope: GitlabScope,
+        #[clap(subcommand)]
+        command: GitlabCommand,
+    },
+
+    /// Grafana module commands
+    Grafana {
         #[clap(subcommand)]
         action: Action,
     },
 
+    /// Matrix module commands
+    Matrix {
+        #[clap(subcommand)]
+        action: Action,
+    },
+
+    /// Vault module commands
+    Vault {
+        #[clap(subcommand)]
+        action: Action,
+    },
+
+    /// Wiki module commands
+    Wiki {
+        #[clap(subcommand)]
+        action: Action,
+    },
+
+    /// Generated files module commands, requires --output-dir
+    Files {
+        #[clap(subcommand)]
+        action: Action,
+    },
+
+    /// Gather only the named resource and show its drift
+    Diff {
+        /// Resource type, e.g. gitlab_project_setting
+        resource_type: String,
+        /// Resource address, e.g. archlinux/packaging/packages/linux
+        address: String,
+    },
+
+    /// Print membership counts per team and group, with deltas to the snapshot
+    Stats,
+
     /// Generate shell completions
     #[clap(name = "completions")]
     Completions(Completions),
 }
 
+#[derive(Debug, Subcommand)]
+pub enum GitlabCommand {
+    #[clap(flatten)]
+    Action(Action),
+
+    /// Report packaging projects whose .SRCINFO is missing, malformed or would change the project description
+    VerifySrcinfo {
+        /// Packaging projects by name or full path, all if none are given
+        projects: Vec<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PlanCommand {
+    /// Compare the planned changes of two event logs written with --events or JSON plans
+    Diff {
+        /// Event log or JSON plan of the old run
+        #[clap(parse(from_os_str))]
+        old: PathBuf,
+        /// Event log or JSON plan of the new run
+        #[clap(parse(from_os_str))]
+        new: PathBuf,
+    },
+}
+
 #[derive(Debug, ClapArgs)]
 pub struct Completions {
     /// Target shell
@@ -50,7 +304,7 @@ pub struct Completions {
     pub shell: Shell,
 }
 
-#[derive(Debug, Subcommand)]
+#[derive(Debug, Clone, Copy, Subcommand)]
 pub enum Action {
     /// Generate and show an execution plan
     Plan,
@@ -0,0 +1,210 @@
+//! This module implements an optional on-disk cache for gathered state.
+//!
+//! Each source (Keycloak, GitLab user ids, GitLab group tree) is stored as a
+//! separate JSON file in the user cache directory and only used while it is
+//! younger than its TTL. Files are named by the instance the state was
+//! gathered from and readable by the owner only, as they hold emails and SSH
+//! keys.
+
+use std::env;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::components::gitlab::core::gitlab_host;
+use crate::metrics;
+use crate::state::DEFAULT_GITLAB_ROOT_GROUP;
+use crate::util::unix_timestamp;
+
+#[derive(Debug, Clone, Copy)]
+pub enum CacheSource {
+    Keycloak,
+    GitLab,
+    GitLabGroups,
+}
+
+impl CacheSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Keycloak => "keycloak",
+            Self::GitLab => "gitlab",
+            Self::GitLabGroups => "gitlab_groups",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry<T> {
+    created: u64,
+    data: T,
+}
+
+pub struct Cache {
+    dir: PathBuf,
+    /// Keycloak url and realm the state is gathered from
+    keycloak_instance: String,
+    /// GitLab host and root group the state is gathered from
+    gitlab_instance: String,
+    keycloak_ttl: Duration,
+    gitlab_ttl: Duration,
+    gitlab_groups_ttl: Duration,
+}
+
+impl Cache {
+    pub fn new(
+        keycloak_ttl: Duration,
+        gitlab_ttl: Duration,
+        gitlab_groups_ttl: Duration,
+    ) -> Result<Cache> {
+        let dir = dirs_next::cache_dir()
+            .context("Failed to determine user cache directory")?
+            .join("gluebuddy");
+        let keycloak_instance = format!(
+            "{}/{}",
+            env::var("GLUEBUDDY_KEYCLOAK_URL").unwrap_or_default(),
+            env::var("GLUEBUDDY_KEYCLOAK_REALM").unwrap_or_default()
+        );
+        let gitlab_instance = format!(
+            "{}/{}",
+            gitlab_host(),
+            env::var("GLUEBUDDY_GITLAB_ROOT_GROUP")
+                .unwrap_or_else(|_| DEFAULT_GITLAB_ROOT_GROUP.to_string())
+        );
+        Ok(Cache {
+            dir,
+            keycloak_instance,
+            gitlab_instance,
+            keycloak_ttl,
+            gitlab_ttl,
+            gitlab_groups_ttl,
+        })
+    }
+
+    pub fn load<T: DeserializeOwned>(&self, source: CacheSource) -> Result<Option<T>> {
+        let path = self.path(source);
+        if !path.exists() {
+            debug!("No cached {} state at {}", source.as_str(), path.display());
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read cache file {}", path.display()))?;
+        let entry: CacheEntry<T> = match serde_json::from_str(&content) {
+            Ok(entry) => entry,
+            Err(err) => {
+                debug!("Ignoring invalid cache file {}: {}", path.display(), err);
+                return Ok(None);
+            }
+        };
+
+        let age = unix_timestamp()?.saturating_sub(entry.created);
+        if age > self.ttl(source).as_secs() {
+            debug!(
+                "Cached {} state is {}s old and expired",
+                source.as_str(),
+                age
+            );
+            return Ok(None);
+        }
+
+        info!("Using cached {} state ({}s old)", source.as_str(), age);
+        metrics::count_cache_hit();
+        Ok(Some(entry.data))
+    }
+
+    pub fn store<T: Serialize>(&self, source: CacheSource, data: &T) -> Result<()> {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)
+            .with_context(|| format!("Failed to create cache directory {}", self.dir.display()))?;
+        fs::set_permissions(&self.dir, fs::Permissions::from_mode(0o700)).with_context(|| {
+            format!("Failed to restrict cache directory {}", self.dir.display())
+        })?;
+
+        let path = self.path(source);
+        let entry = CacheEntry {
+            created: unix_timestamp()?,
+            data,
+        };
+        let content = serde_json::to_string(&entry)?;
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)
+            .and_then(|mut file| {
+                // Files of earlier versions were created with the default umask
+                file.set_permissions(fs::Permissions::from_mode(0o600))?;
+                file.write_all(content.as_bytes())
+            })
+            .with_context(|| format!("Failed to write cache file {}", path.display()))?;
+        debug!("Stored {} state in {}", source.as_str(), path.display());
+        Ok(())
+    }
+
+    fn ttl(&self, source: CacheSource) -> Duration {
+        match source {
+            CacheSource::Keycloak => self.keycloak_ttl,
+            CacheSource::GitLab => self.gitlab_ttl,
+            CacheSource::GitLabGroups => self.gitlab_groups_ttl,
+        }
+    }
+
+    fn path(&self, source: CacheSource) -> PathBuf {
+        let instance = match source {
+            CacheSource::Keycloak => &self.keycloak_instance,
+            CacheSource::GitLab | CacheSource::GitLabGroups => &self.gitlab_instance,
+        };
+        self.dir.join(file_name(source, instance))
+    }
+}
+
+/// Names the cache file of the source after the instance, keeping only
+/// characters that are safe in file names.
+fn file_name(source: CacheSource, instance: &str) -> String {
+    let instance: String = instance
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect();
+    format!("{}-{}.json", source.as_str(), instance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(
+        CacheSource::Keycloak,
+        "https://accounts.archlinux.org/archlinux",
+        "keycloak-https___accounts.archlinux.org_archlinux.json"
+    )]
+    #[case(
+        CacheSource::GitLabGroups,
+        "gitlab.archlinux.org/archlinux",
+        "gitlab_groups-gitlab.archlinux.org_archlinux.json"
+    )]
+    #[case(
+        CacheSource::GitLab,
+        "gitlab.staging.archlinux.org/archlinux",
+        "gitlab-gitlab.staging.archlinux.org_archlinux.json"
+    )]
+    fn file_name_test(#[case] source: CacheSource, #[case] instance: &str, #[case] expected: &str) {
+        assert_eq!(file_name(source, instance), expected);
+    }
+}
@@ -1,2 +1,26 @@
+pub mod files;
 pub mod gitlab;
+pub mod grafana;
 pub mod keycloak;
+pub mod matrix;
+pub mod vault;
+pub mod wiki;
+
+use crate::args::Action;
+use crate::cache::Cache;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+/// A service gluebuddy plans and applies changes for.
+#[async_trait(?Send)]
+pub trait Component {
+    /// Gathers the state shared with the other components, which runs for all
+    /// configured components before any of them plans changes.
+    async fn gather(&self, _cache: Option<&Cache>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Plans the changes of the component, applying them for [`Action::Apply`].
+    async fn run(&self, action: Action) -> Result<()>;
+}
@@ -0,0 +1,8 @@
+pub mod authorized_keys;
+pub mod core;
+pub mod keyring;
+pub mod ldif;
+pub mod postfix;
+pub mod wireguard;
+
+pub use crate::components::files::core::Files;
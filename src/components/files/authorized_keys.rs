@@ -0,0 +1,94 @@
+//! This module generates one `authorized_keys` fragment per team from the
+//! SSH public key attributes of the team members. Which hosts include which
+//! fragment is decided in the infrastructure repository, e.g. the DevOps
+//! fragment is deployed to all hosts.
+
+use crate::components::files::core::{GeneratedFile, Layout};
+use crate::state::{State, User};
+
+use std::path::PathBuf;
+
+const SSH_PUBLIC_KEY_ATTRIBUTE: &str = "ssh_public_key";
+const AUTHORIZED_KEYS_DIRECTORY: &str = "authorized_keys";
+
+/// Formats the key with the username as comment, dropping the original one.
+fn format_key(key: &str, user: &User) -> Option<String> {
+    let mut parts = key.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(key_type), Some(key)) => Some(format!("{} {} {}", key_type, key, user.username)),
+        _ => None,
+    }
+}
+
+pub fn generate(state: &State) -> Vec<GeneratedFile> {
+    state
+        .teams
+        .iter()
+        .map(|team| GeneratedFile {
+            path: PathBuf::from(AUTHORIZED_KEYS_DIRECTORY).join(team.name.to_lowercase()),
+            layout: Layout::Lines,
+            lines: state
+                .team_members(team)
+                .into_iter()
+                .flat_map(|user| {
+                    user.attribute_values(SSH_PUBLIC_KEY_ATTRIBUTE)
+                        .iter()
+                        .filter_map(move |key| format_key(key, user))
+                })
+                .collect(),
+            rule: format!("team {} ssh access", team.name),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generate_test() {
+        let mut state = State::default();
+        let mut alice = User::new("alice".to_string());
+        alice.groups = ["/Arch Linux Staff/DevOps".to_string()].into();
+        alice.attributes.insert(
+            SSH_PUBLIC_KEY_ATTRIBUTE.to_string(),
+            vec![
+                "ssh-ed25519 AAAA alice@laptop".to_string(),
+                "broken".to_string(),
+            ],
+        );
+        let mut bob = User::new("bob".to_string());
+        bob.groups = ["/Arch Linux Staff/Developers".to_string()].into();
+        bob.attributes.insert(
+            SSH_PUBLIC_KEY_ATTRIBUTE.to_string(),
+            vec!["ssh-rsa BBBB".to_string()],
+        );
+        for user in [alice, bob] {
+            state.users.insert(user.username.clone(), user);
+        }
+
+        let files = generate(&state);
+        let lines: Vec<(PathBuf, Vec<&str>)> = files
+            .iter()
+            .map(|file| {
+                (
+                    file.path.clone(),
+                    file.lines.iter().map(|line| line.as_str()).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                (
+                    PathBuf::from("authorized_keys/staff"),
+                    vec!["ssh-ed25519 AAAA alice", "ssh-rsa BBBB bob"]
+                ),
+                (
+                    PathBuf::from("authorized_keys/devops"),
+                    vec!["ssh-ed25519 AAAA alice"]
+                ),
+            ]
+        );
+    }
+}
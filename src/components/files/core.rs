@@ -0,0 +1,254 @@
+//! This module defines the generation of files from the gathered state, e.g.
+//! for services that are configured from a checkout of the infrastructure
+//! repository.
+//!
+//! ## Features
+//!
+//! - diff the lines of each generated file against the file on disk
+//! - write all files that changed when applying
+//! - collect the changed files to propose them as a merge request
+
+use crate::args::Action;
+use crate::components::files::{authorized_keys, keyring, ldif, postfix, wireguard};
+use crate::components::Component;
+use crate::plan::{Plan, PlanEntry, PlanSummary, Resource};
+use crate::state::State;
+
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+const GENERATED_HEADER: &str = "# Generated by gluebuddy, do not edit manually";
+
+/// How the entries of a generated file are laid out on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// One entry per line
+    Lines,
+    /// Multi-line entries separated by an empty line, e.g. LDIF
+    Records,
+}
+
+/// A file generated from the gathered state as a set of entries.
+#[derive(Debug, PartialEq, Eq)]
+pub struct GeneratedFile {
+    /// Path relative to the output directory
+    pub path: PathBuf,
+    pub layout: Layout,
+    pub lines: BTreeSet<String>,
+    pub rule: String,
+}
+
+impl GeneratedFile {
+    /// Renders the file with a header, comment lines on disk are ignored.
+    pub fn render(&self) -> String {
+        let mut content = format!("{}\n", GENERATED_HEADER);
+        for line in &self.lines {
+            if let Layout::Records = self.layout {
+                content.push('\n');
+            }
+            content.push_str(line);
+            content.push('\n');
+        }
+        content
+    }
+}
+
+/// A generated file that was written when applying.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ChangedFile {
+    /// Path relative to the output directory
+    pub path: PathBuf,
+    pub content: String,
+}
+
+pub struct Files {
+    directory: PathBuf,
+    ldap_base_dn: String,
+    state: Arc<Mutex<State>>,
+    plan: Arc<Plan>,
+    changed: Mutex<Vec<ChangedFile>>,
+}
+
+impl Files {
+    pub fn new(directory: PathBuf, state: Arc<Mutex<State>>, plan: Arc<Plan>) -> Files {
+        let ldap_base_dn = env::var("GLUEBUDDY_LDAP_BASE_DN")
+            .unwrap_or_else(|_| ldif::DEFAULT_BASE_DN.to_string());
+        Files {
+            directory,
+            ldap_base_dn,
+            state,
+            plan,
+            changed: Mutex::new(vec![]),
+        }
+    }
+
+    /// Takes all files written since the last call.
+    pub async fn take_changed_files(&self) -> Vec<ChangedFile> {
+        self.changed.lock().await.drain(..).collect()
+    }
+
+    /// Returns the written file when applying changes.
+    fn update_file(&self, action: &Action, file: &GeneratedFile) -> Result<Option<ChangedFile>> {
+        let path = self.directory.join(&file.path);
+        let display_path = file.path.display().to_string();
+        debug!("Reconciling generated file {}", path.display());
+
+        let current = read_lines(&path, file.layout)?;
+        let label = format!("File '{}' lines", display_path);
+        let mut summary = PlanSummary::new(&label);
+
+        let mut entries = vec![];
+        for line in file.lines.difference(&current) {
+            entries.push(PlanEntry::add(
+                Resource::file_line(&display_path, &line.replace('\n', ", ")),
+                &file.rule,
+            ));
+            summary.add += 1;
+        }
+        for line in current.difference(&file.lines) {
+            entries.push(PlanEntry::destroy(
+                Resource::file_line(&display_path, &line.replace('\n', ", ")),
+                &file.rule,
+            ));
+            summary.destroy += 1;
+        }
+        for entry in &entries {
+            self.plan.record(entry)?;
+        }
+
+        let mut changed = None;
+        if self.plan.applies_all(action, &entries) {
+            if summary.has_changes() || !path.exists() {
+                let content = file.render();
+                write_file(&path, &content)?;
+                changed = Some(ChangedFile {
+                    path: file.path.clone(),
+                    content,
+                });
+            }
+            for entry in &entries {
+                self.plan.record_applied(entry)?;
+            }
+        }
+
+        self.plan.finish_section(&summary)?;
+        Ok(changed)
+    }
+}
+
+#[async_trait(?Send)]
+impl Component for Files {
+    async fn run(&self, action: Action) -> Result<()> {
+        self.plan.start_component("Files");
+        let files = {
+            let state = self.state.lock().await;
+            let mut files = authorized_keys::generate(&state);
+            files.push(wireguard::generate(&state));
+            files.push(ldif::generate(&state, &self.ldap_base_dn));
+            files.push(postfix::generate(&state));
+            files.push(keyring::generate(&state));
+            files
+        };
+        for file in &files {
+            if let Some(changed) = self.update_file(&action, file)? {
+                self.changed.lock().await.push(changed);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads all entries of the file, ignoring empty and comment lines.
+fn read_lines(path: &Path, layout: Layout) -> Result<BTreeSet<String>> {
+    if !path.exists() {
+        return Ok(BTreeSet::new());
+    }
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let lines = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'));
+    match layout {
+        Layout::Lines => Ok(lines
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect()),
+        Layout::Records => {
+            let mut records = BTreeSet::new();
+            let mut record: Vec<&str> = vec![];
+            for line in lines.chain([""]) {
+                if !line.is_empty() {
+                    record.push(line);
+                } else if !record.is_empty() {
+                    records.insert(record.join("\n"));
+                    record.clear();
+                }
+            }
+            Ok(records)
+        }
+    }
+}
+
+/// Writes the file atomically by renaming a temporary file into place.
+fn write_file(path: &Path, content: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+    }
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, content)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    fs::rename(&temporary, path).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_lines_test() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = GeneratedFile {
+            path: PathBuf::from("authorized_keys/devops"),
+            layout: Layout::Lines,
+            lines: ["ssh-ed25519 AAAA alice", "ssh-ed25519 BBBB bob"]
+                .iter()
+                .map(|line| line.to_string())
+                .collect(),
+            rule: "team DevOps ssh access".to_string(),
+        };
+        let path = directory.path().join(&file.path);
+
+        assert_eq!(read_lines(&path, file.layout).unwrap(), BTreeSet::new());
+        write_file(&path, &file.render()).unwrap();
+        assert_eq!(read_lines(&path, file.layout).unwrap(), file.lines);
+    }
+
+    #[test]
+    fn read_records_test() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = GeneratedFile {
+            path: PathBuf::from("ldap/staff.ldif"),
+            layout: Layout::Records,
+            lines: ["dn: uid=alice\nuid: alice", "dn: uid=bob\nuid: bob"]
+                .iter()
+                .map(|line| line.to_string())
+                .collect(),
+            rule: "staff ldap directory".to_string(),
+        };
+        let path = directory.path().join(&file.path);
+
+        write_file(&path, &file.render()).unwrap();
+        assert_eq!(read_lines(&path, file.layout).unwrap(), file.lines);
+    }
+}
@@ -0,0 +1,100 @@
+//! This module generates the canonical list of PGP fingerprints of all active
+//! packagers as candidates for the archlinux-keyring. Lines removed from the
+//! file on disk flag keys that are not backed by an active packager anymore
+//! and need to be revoked in the keyring.
+
+use crate::components::files::core::{GeneratedFile, Layout};
+use crate::state::{State, User};
+
+use std::path::PathBuf;
+
+use tracing::warn;
+
+const PGP_FINGERPRINT_ATTRIBUTE: &str = "pgp_fingerprint";
+const KEYRING_FILE: &str = "keyring/packager-fingerprints";
+
+/// Normalizes the fingerprint to 40 uppercase hex digits without spaces.
+fn normalize_fingerprint(fingerprint: &str) -> Option<String> {
+    let fingerprint: String = fingerprint
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase();
+    match fingerprint.len() == 40 && fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+        true => Some(fingerprint),
+        false => None,
+    }
+}
+
+fn format_fingerprints(user: &User) -> Vec<String> {
+    user.attribute_values(PGP_FINGERPRINT_ATTRIBUTE)
+        .iter()
+        .filter_map(|fingerprint| match normalize_fingerprint(fingerprint) {
+            Some(fingerprint) => Some(format!("{} {}", fingerprint, user.username)),
+            None => {
+                warn!(
+                    "Packager {} has an invalid PGP fingerprint {}",
+                    user.username, fingerprint
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+pub fn generate(state: &State) -> GeneratedFile {
+    GeneratedFile {
+        path: PathBuf::from(KEYRING_FILE),
+        layout: Layout::Lines,
+        lines: state
+            .packagers()
+            .into_iter()
+            .flat_map(format_fingerprints)
+            .collect(),
+        rule: "packager keys are backed by an active packager".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(
+        "0123 4567 89ab cdef 0123  4567 89AB CDEF 0123 4567",
+        Some("0123456789ABCDEF0123456789ABCDEF01234567")
+    )]
+    #[case("0123456789ABCDEF", None)]
+    #[case("0123456789ABCDEF0123456789ABCDEF0123456Z", None)]
+    fn normalize_fingerprint_test(#[case] fingerprint: &str, #[case] expected: Option<&str>) {
+        assert_eq!(
+            normalize_fingerprint(fingerprint),
+            expected.map(str::to_string)
+        );
+    }
+
+    #[test]
+    fn generate_test() {
+        let mut state = State::default();
+        for (username, group) in [
+            ("alice", "/Arch Linux Staff/Package Maintainers"),
+            ("bob", "/Arch Linux Staff/DevOps"),
+        ] {
+            let mut user = User::new(username.to_string());
+            user.groups = [group.to_string()].into();
+            user.attributes.insert(
+                PGP_FINGERPRINT_ATTRIBUTE.to_string(),
+                vec!["0123456789abcdef0123456789abcdef01234567".to_string()],
+            );
+            state.users.insert(user.username.clone(), user);
+        }
+
+        let file = generate(&state);
+        assert_eq!(file.path, PathBuf::from("keyring/packager-fingerprints"));
+        assert_eq!(
+            file.lines.into_iter().collect::<Vec<String>>(),
+            vec!["0123456789ABCDEF0123456789ABCDEF01234567 alice".to_string()]
+        );
+    }
+}
@@ -0,0 +1,110 @@
+//! This module generates an LDIF export of all staff members and teams for
+//! legacy services that can neither speak OIDC nor SAML. The infrastructure
+//! repository imports it into a read-only directory, so that offboarded staff
+//! drop out of those services on the next run as well.
+
+use crate::components::files::core::{GeneratedFile, Layout};
+use crate::state::{State, User};
+
+use std::path::PathBuf;
+
+pub const DEFAULT_BASE_DN: &str = "dc=archlinux,dc=org";
+const LDIF_FILE: &str = "ldap/staff.ldif";
+
+fn user_dn(user: &User, base_dn: &str) -> String {
+    format!("uid={},ou=people,{}", user.username, base_dn)
+}
+
+fn format_user(user: &User, base_dn: &str) -> String {
+    let mut lines = vec![
+        format!("dn: {}", user_dn(user, base_dn)),
+        "objectClass: inetOrgPerson".to_string(),
+        format!("uid: {}", user.username),
+        format!("cn: {}", user.username),
+        format!("sn: {}", user.username),
+    ];
+    if let Some(email) = &user.email {
+        lines.push(format!("mail: {}", email));
+    }
+    lines.join("\n")
+}
+
+fn format_group(name: &str, members: &[&User], base_dn: &str) -> String {
+    let mut members: Vec<String> = members
+        .iter()
+        .map(|user| format!("member: {}", user_dn(user, base_dn)))
+        .collect();
+    members.sort();
+    let mut lines = vec![
+        format!("dn: cn={},ou=groups,{}", name.to_lowercase(), base_dn),
+        "objectClass: groupOfNames".to_string(),
+        format!("cn: {}", name.to_lowercase()),
+    ];
+    lines.extend(members);
+    lines.join("\n")
+}
+
+pub fn generate(state: &State, base_dn: &str) -> GeneratedFile {
+    let mut lines: Vec<String> = state
+        .staff()
+        .into_iter()
+        .map(|user| format_user(user, base_dn))
+        .collect();
+    for team in &state.teams {
+        let members = state.team_members(team);
+        // groupOfNames requires at least one member
+        if !members.is_empty() {
+            lines.push(format_group(&team.name, &members, base_dn));
+        }
+    }
+    GeneratedFile {
+        path: PathBuf::from(LDIF_FILE),
+        layout: Layout::Records,
+        lines: lines.into_iter().collect(),
+        rule: "staff ldap directory".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Team;
+
+    #[test]
+    fn generate_test() {
+        let mut state = State::default();
+        state.teams = vec![
+            Team::new("DevOps", "/Arch Linux Staff/DevOps"),
+            Team::new("Security", "/Arch Linux Staff/Security Team"),
+        ];
+        let mut alice = User::new("alice".to_string());
+        alice.email = Some("alice@archlinux.org".to_string());
+        alice.groups = ["/Arch Linux Staff/DevOps".to_string()].into();
+        let mut bob = User::new("bob".to_string());
+        bob.enabled = false;
+        bob.groups = ["/Arch Linux Staff/DevOps".to_string()].into();
+        for user in [alice, bob] {
+            state.users.insert(user.username.clone(), user);
+        }
+
+        let file = generate(&state, DEFAULT_BASE_DN);
+        assert_eq!(file.path, PathBuf::from("ldap/staff.ldif"));
+        assert_eq!(
+            file.lines.into_iter().collect::<Vec<String>>(),
+            vec![
+                "dn: cn=devops,ou=groups,dc=archlinux,dc=org\n\
+                 objectClass: groupOfNames\n\
+                 cn: devops\n\
+                 member: uid=alice,ou=people,dc=archlinux,dc=org"
+                    .to_string(),
+                "dn: uid=alice,ou=people,dc=archlinux,dc=org\n\
+                 objectClass: inetOrgPerson\n\
+                 uid: alice\n\
+                 cn: alice\n\
+                 sn: alice\n\
+                 mail: alice@archlinux.org"
+                    .to_string(),
+            ]
+        );
+    }
+}
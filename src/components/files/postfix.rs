@@ -0,0 +1,63 @@
+//! This module generates the postfix virtual alias map of the `@archlinux.org`
+//! addresses of all staff members, forwarding them to the address from their
+//! `mail_forward` attribute. Offboarded staff drop out of the map, so that mail
+//! to their address bounces instead of reaching a former member.
+
+use crate::components::files::core::{GeneratedFile, Layout};
+use crate::state::{State, User};
+
+use std::path::PathBuf;
+
+const MAIL_FORWARD_ATTRIBUTE: &str = "mail_forward";
+const MAIL_DOMAIN: &str = "archlinux.org";
+const POSTFIX_VIRTUAL_FILE: &str = "postfix/virtual";
+
+fn format_alias(user: &User) -> Option<String> {
+    let forward = user.attribute(MAIL_FORWARD_ATTRIBUTE)?;
+    Some(format!("{}@{} {}", user.username, MAIL_DOMAIN, forward))
+}
+
+pub fn generate(state: &State) -> GeneratedFile {
+    GeneratedFile {
+        path: PathBuf::from(POSTFIX_VIRTUAL_FILE),
+        layout: Layout::Lines,
+        lines: state.staff().into_iter().filter_map(format_alias).collect(),
+        rule: "staff mail aliases".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generate_test() {
+        let mut state = State::default();
+        for (username, group, forward) in [
+            (
+                "alice",
+                "/Arch Linux Staff/DevOps",
+                Some("alice@example.com"),
+            ),
+            ("bob", "/Arch Linux Staff/Developers", None),
+            ("carol", "/External Contributors", Some("carol@example.com")),
+        ] {
+            let mut user = User::new(username.to_string());
+            user.groups = [group.to_string()].into();
+            if let Some(forward) = forward {
+                user.attributes.insert(
+                    MAIL_FORWARD_ATTRIBUTE.to_string(),
+                    vec![forward.to_string()],
+                );
+            }
+            state.users.insert(user.username.clone(), user);
+        }
+
+        let file = generate(&state);
+        assert_eq!(file.path, PathBuf::from("postfix/virtual"));
+        assert_eq!(
+            file.lines.into_iter().collect::<Vec<String>>(),
+            vec!["alice@archlinux.org alice@example.com".to_string()]
+        );
+    }
+}
@@ -0,0 +1,93 @@
+//! This module generates the WireGuard peers of all DevOps members from their
+//! public key and assigned IP attributes. The peers are written as a YAML list
+//! with one peer per line, so that the infrastructure repository can template
+//! the `[Peer]` sections from it and offboarded admins drop out automatically.
+
+use crate::components::files::core::{GeneratedFile, Layout};
+use crate::state::{State, User};
+
+use std::path::PathBuf;
+
+use tracing::warn;
+
+const WIREGUARD_PUBLIC_KEY_ATTRIBUTE: &str = "wireguard_public_key";
+const WIREGUARD_IP_ATTRIBUTE: &str = "wireguard_ip";
+const WIREGUARD_PEERS_FILE: &str = "wireguard/devops_peers.yml";
+
+fn format_peer(user: &User) -> Option<String> {
+    let public_key = user.attribute(WIREGUARD_PUBLIC_KEY_ATTRIBUTE)?;
+    let ip = match user.attribute(WIREGUARD_IP_ATTRIBUTE) {
+        Some(ip) => ip,
+        None => {
+            warn!(
+                "DevOps member {} has a WireGuard public key but no {} attribute",
+                user.username, WIREGUARD_IP_ATTRIBUTE
+            );
+            return None;
+        }
+    };
+    Some(format!(
+        "- {{ name: \"{}\", public_key: \"{}\", allowed_ips: \"{}\" }}",
+        user.username, public_key, ip
+    ))
+}
+
+pub fn generate(state: &State) -> GeneratedFile {
+    GeneratedFile {
+        path: PathBuf::from(WIREGUARD_PEERS_FILE),
+        layout: Layout::Lines,
+        lines: state.devops().into_iter().filter_map(format_peer).collect(),
+        rule: "devops wireguard access".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generate_test() {
+        let mut state = State::default();
+        for (username, group, attributes) in [
+            (
+                "alice",
+                "/Arch Linux Staff/DevOps",
+                vec![
+                    ("wireguard_public_key", "AAAA="),
+                    ("wireguard_ip", "10.0.0.2/32"),
+                ],
+            ),
+            (
+                "bob",
+                "/Arch Linux Staff/DevOps",
+                vec![("wireguard_public_key", "BBBB=")],
+            ),
+            (
+                "carol",
+                "/Arch Linux Staff/Developers",
+                vec![
+                    ("wireguard_public_key", "CCCC="),
+                    ("wireguard_ip", "10.0.0.3/32"),
+                ],
+            ),
+        ] {
+            let mut user = User::new(username.to_string());
+            user.groups = [group.to_string()].into();
+            for (name, value) in attributes {
+                user.attributes
+                    .insert(name.to_string(), vec![value.to_string()]);
+            }
+            state.users.insert(user.username.clone(), user);
+        }
+
+        let file = generate(&state);
+        assert_eq!(file.path, PathBuf::from("wireguard/devops_peers.yml"));
+        assert_eq!(
+            file.lines.into_iter().collect::<Vec<String>>(),
+            vec![
+                "- { name: \"alice\", public_key: \"AAAA=\", allowed_ips: \"10.0.0.2/32\" }"
+                    .to_string()
+            ]
+        );
+    }
+}
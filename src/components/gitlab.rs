@@ -1,4 +1,12 @@
+pub mod commits;
 pub mod core;
+pub mod policy;
+pub mod registry;
+pub mod settings;
+pub mod spam;
+pub mod srcinfo;
+pub mod throttle;
+pub mod tokens;
 pub mod types;
 
 pub use crate::components::gitlab::core::GitLabGlue;
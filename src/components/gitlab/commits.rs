@@ -0,0 +1,90 @@
+//! The commits endpoint that changes several repository files in a single
+//! commit, which the gitlab crate is missing.
+
+use gitlab::api::common::NameOrId;
+use gitlab::api::endpoint_prelude::*;
+use serde::Serialize;
+use serde_json::json;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommitActionKind {
+    Create,
+    Update,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CommitAction {
+    pub action: CommitActionKind,
+    pub file_path: String,
+    pub content: String,
+}
+
+impl CommitAction {
+    /// Returns how the file is committed given its content at the target
+    /// ref, which is None if it does not exist there. Unchanged files are not
+    /// committed at all.
+    pub fn for_file(file_path: &str, content: &str, current: Option<&str>) -> Option<CommitAction> {
+        let action = match current {
+            None => CommitActionKind::Create,
+            Some(current) if current == content => return None,
+            Some(_) => CommitActionKind::Update,
+        };
+        Some(CommitAction {
+            action,
+            file_path: file_path.to_string(),
+            content: content.to_string(),
+        })
+    }
+}
+
+/// Commits all actions to the branch, which is created from the start branch
+/// if missing. With `force` the branch is replaced by a single commit on top
+/// of the start branch.
+pub struct CreateCommit<'a> {
+    pub project: NameOrId<'a>,
+    pub branch: &'a str,
+    pub start_branch: &'a str,
+    pub commit_message: &'a str,
+    pub actions: &'a [CommitAction],
+    pub force: bool,
+}
+
+impl<'a> Endpoint for CreateCommit<'a> {
+    fn method(&self) -> Method {
+        Method::POST
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!("projects/{}/repository/commits", self.project).into()
+    }
+
+    fn body(&self) -> Result<Option<(&'static str, Vec<u8>)>, BodyError> {
+        let body = json!({
+            "branch": self.branch,
+            "start_branch": self.start_branch,
+            "commit_message": self.commit_message,
+            "actions": self.actions,
+            "force": self.force,
+        });
+        Ok(Some((
+            "application/json",
+            serde_json::to_vec(&body).expect("JSON values always serialize"),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(None, Some(CommitActionKind::Create))]
+    #[case(Some("alice\n"), Some(CommitActionKind::Update))]
+    #[case(Some("alice\nbob\n"), None)]
+    fn for_file_test(#[case] current: Option<&str>, #[case] expected: Option<CommitActionKind>) {
+        let action = CommitAction::for_file("authorized_keys/devops", "alice\nbob\n", current);
+        assert_eq!(action.map(|action| action.action), expected);
+    }
+}
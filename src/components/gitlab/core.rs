@@ -5,279 +5,1133 @@
 //! - ensure the integrity of the Arch Linux root group
 //!   - add staff members with minimal access
 //!   - ensure nobody except devops has higher privileges
-
-use crate::args::Action;
-use crate::state::{State, User};
-
+//! - clean up container registries, keeping only the most recent tags
+//! - block external authors of issues labeled as spam and close their issues
+//! - create the team groups of new Keycloak teams
+//! - keep the description and avatar of team groups consistent
+
+use crate::args::{Action, Enforcement, GitlabScope};
+use crate::cache::{Cache, CacheSource};
+use crate::components::files::core::ChangedFile;
+use crate::components::Component;
+use crate::state::{State, Team, TeamGitLabGroup, User, DEFAULT_GITLAB_ROOT_GROUP};
+
+use crate::components::gitlab::commits::{CommitAction, CreateCommit};
+use crate::components::gitlab::policy::{ProjectPolicy, ProjectSettings};
+use crate::components::gitlab::registry::*;
+use crate::components::gitlab::settings::*;
+use crate::components::gitlab::spam::*;
+use crate::components::gitlab::srcinfo::*;
+use crate::components::gitlab::throttle::ThrottledGitlab;
+use crate::components::gitlab::tokens::PersonalAccessTokenSelf;
 use crate::components::gitlab::types::*;
+use crate::error::{self, ConfigError, ErrorClass};
+use crate::plan::{Plan, PlanEntry, PlanSummary, Resource};
+use crate::snapshot::Snapshot;
 
+use crate::http_client;
+use crate::metrics;
 use crate::util;
 
+use std::collections::{BTreeMap, HashMap};
 use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use anyhow::{bail, Context, Result};
-use log::{debug, error, info, trace, warn};
-use tokio::sync::{Mutex, MutexGuard};
+use async_trait::async_trait;
+use futures::stream::{self, FuturesUnordered, StreamExt, TryStreamExt};
+use serde::de::DeserializeOwned;
+use tokio::sync::Mutex;
+use tracing::{debug, error, info, instrument, trace, warn};
 
-use gitlab::api::{AsyncQuery, Query};
-use gitlab::{AsyncGitlab, Gitlab, GitlabBuilder};
+use gitlab::api::{AsyncQuery, Endpoint, Query};
+use gitlab::Gitlab;
 
-use gitlab::api::common::AccessLevel;
+use gitlab::api::common::{AccessLevel, VisibilityLevel};
 use gitlab::api::groups::projects::GroupProjectsOrderBy;
 use gitlab::api::groups::subgroups::GroupSubgroupsOrderBy;
+use gitlab::api::projects::merge_requests::MergeRequestState;
 use gitlab::api::users::ExternalProvider;
 
-const DEFAULT_ARCH_LINUX_GROUP_ACCESS_LEVEL: AccessLevel = AccessLevel::Minimal;
-const DEFAULT_STAFF_GROUP_ACCESS_LEVEL: AccessLevel = AccessLevel::Reporter;
-const DEVOPS_INFRASTRUCTURE_ACCESS_LEVEL: AccessLevel = AccessLevel::Developer;
+const DEFAULT_HOST: &str = "gitlab.archlinux.org";
+
 const MAX_ACCESS_LEVEL: AccessLevel = AccessLevel::Developer;
 
 const GITLAB_OWNER: &str = "archceo";
 const GITLAB_BOT: &str = "archbot";
 
+const RULE_STAFF_ONLY_GROUPS: &str = "only staff may be group members";
+const RULE_STAFF_AND_EXTERNALS_ONLY_PROJECTS: &str =
+    "only staff and external contributors may be project members";
+const RULE_INFRASTRUCTURE_VIA_GROUPS: &str = "infrastructure access is granted via groups only";
+const RULE_PROJECT_SETTINGS_POLICY: &str = "project settings policy";
+const RULE_REGISTRY_RETENTION: &str = "keep only the most recent container registry tags";
+const RULE_SPAM: &str = "external authors of spam issues are blocked";
+const RULE_TEAM_GROUPS: &str = "every team has a team group";
+const RULE_TEAM_GROUP_SETTINGS: &str = "team groups carry the standard description and avatar";
+
+/// Tag that is never removed by the container registry cleanup.
+const REGISTRY_LATEST_TAG: &str = "latest";
+
+const DEFAULT_IDENTITY_PROVIDER: &str = "saml";
+
+/// Scope the GitLab token needs for reading and writing all resources.
+const REQUIRED_TOKEN_SCOPE: &str = "api";
+
+/// Maximum number of concurrent GitLab user lookups while gathering.
+const GITLAB_USER_LOOKUP_CONCURRENCY: usize = 8;
+/// Maximum number of concurrent GitLab listings while traversing groups.
+const GITLAB_TRAVERSAL_CONCURRENCY: usize = 8;
+
+/// Identifies the issue comment gluebuddy updates with its latest report.
+const REPORT_MARKER: &str = "<!-- gluebuddy report -->";
+
 const MAIN_BRANCH: &str = "main";
+/// Branch gluebuddy commits generated file changes to, reset to main
+/// whenever no merge request of it is open.
+const GENERATED_FILES_BRANCH: &str = "gluebuddy/generated-files";
 const ALL_TAGS: &str = "*";
 
+/// Group below the root group that contains the packaging projects.
+const PACKAGES_GROUP: &str = "packaging/packages";
+/// Group below the root group whose issue trackers are cleaned from spam.
+const PACKAGING_GROUP: &str = "packaging";
+
+/// Resource types that can be gathered on their own for `gluebuddy diff`.
+const DIFFABLE_RESOURCE_TYPES: &[&str] = &["gitlab_project_setting"];
+
 pub struct GitLabGlue {
-    client: AsyncGitlab,
+    client: ThrottledGitlab,
     state: Arc<Mutex<State>>,
+    plan: Arc<Plan>,
+    identity_providers: Vec<String>,
+    /// Group whose whole tree is enforced, the team groups live below it
+    root_group: String,
+    registry_keep_tags: Option<usize>,
+    /// Label bug wranglers put on spam issues
+    spam_label: Option<String>,
+    /// Whether to create team groups for new Keycloak teams
+    provision_team_groups: bool,
+    /// Access level overrides of team groups by path
+    access_levels: HashMap<String, AccessLevel>,
+    /// Avatar every team group carries
+    team_avatar: Option<TeamAvatar>,
+    /// Expected project settings by namespace
+    project_policy: ProjectPolicy,
+    member_access_levels: Mutex<BTreeMap<String, BTreeMap<String, u64>>>,
+    /// Subgroups by the full path of their parent group
+    subgroups: Mutex<BTreeMap<String, Vec<Group>>>,
+    /// Whether the subgroups were loaded from the cache
+    subgroups_cached: AtomicBool,
+    /// Enforcements to run, all if empty
+    enforcements: Vec<Enforcement>,
+    /// Group the sweep starts at instead of the root group
+    scope_group: Option<String>,
+    /// Projects the sweep is limited to, all if empty
+    scope_projects: Vec<String>,
+    /// Group members by username of the snapshot used when planning offline
+    snapshot_access_levels: Option<BTreeMap<String, BTreeMap<String, String>>>,
 }
 
 impl GitLabGlue {
-    pub async fn new(state: Arc<Mutex<State>>) -> Result<GitLabGlue> {
-        let token = &env::var("GLUEBUDDY_GITLAB_TOKEN")
-            .context("Missing env var GLUEBUDDY_GITLAB_TOKEN")?;
-        let client = GitlabBuilder::new("gitlab.archlinux.org", token)
-            .build_async()
-            .await?;
-        Ok(GitLabGlue { client, state })
+    pub async fn new(state: Arc<Mutex<State>>, plan: Arc<Plan>) -> Result<GitLabGlue> {
+        let token = &util::secret("GLUEBUDDY_GITLAB_TOKEN")?;
+        let host = gitlab_host();
+        let mut builder = http_client::builder()?;
+        if let Ok(path) = env::var("GLUEBUDDY_GITLAB_CA_BUNDLE") {
+            let pem = fs::read(&path)
+                .with_context(|| format!("Failed to read GitLab CA bundle {}", path))?;
+            let certificate = reqwest::Certificate::from_pem(&pem)
+                .with_context(|| format!("Invalid GitLab CA bundle {}", path))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+        if env::var("GLUEBUDDY_GITLAB_INSECURE_TLS").is_ok_and(|value| value == "true") {
+            warn!("Not verifying the TLS certificate of {}", host);
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        let client = builder
+            .build()
+            .context("Failed to build GitLab HTTP client")?;
+        let client = ThrottledGitlab::new(client, &host, token)?;
+        Self::with_client(client, state, plan).await
+    }
+
+    /// Creates a glue that plans the team group members from the members in
+    /// the snapshot of a previous run and never contacts GitLab.
+    pub async fn offline(
+        state: Arc<Mutex<State>>,
+        plan: Arc<Plan>,
+        snapshot: &Snapshot,
+    ) -> Result<GitLabGlue> {
+        let client = ThrottledGitlab::offline(&gitlab_host())?;
+        let mut glue = Self::with_client(client, state, plan).await?;
+        glue.snapshot_access_levels = Some(snapshot.gitlab_access_levels.clone());
+        // The snapshot only has the members of groups
+        glue.enforcements = vec![Enforcement::Members];
+        Ok(glue)
+    }
+
+    async fn with_client(
+        client: ThrottledGitlab,
+        state: Arc<Mutex<State>>,
+        plan: Arc<Plan>,
+    ) -> Result<GitLabGlue> {
+        let identity_providers = identity_providers();
+        let root_group = env::var("GLUEBUDDY_GITLAB_ROOT_GROUP")
+            .unwrap_or_else(|_| DEFAULT_GITLAB_ROOT_GROUP.to_string());
+        let access_levels = match env::var("GLUEBUDDY_GITLAB_ACCESS_LEVELS") {
+            Ok(overrides) => util::parse_access_level_overrides(&overrides).map_err(|err| {
+                ConfigError::Invalid {
+                    name: "GLUEBUDDY_GITLAB_ACCESS_LEVELS".to_string(),
+                    reason: format!("{:#}", err),
+                }
+            })?,
+            Err(_) => HashMap::new(),
+        };
+        let teams: Vec<Team> = Team::defaults(&root_group)
+            .into_iter()
+            .map(|team| team.with_access_level_overrides(&access_levels))
+            .collect();
+        let provision_team_groups =
+            env::var("GLUEBUDDY_GITLAB_PROVISION_TEAM_GROUPS").is_ok_and(|value| value == "true");
+        let teams_group = format!("{}/teams/", root_group);
+        for group in access_levels.keys() {
+            // Groups of teams that are discovered later can not be checked yet
+            let is_team_group = teams
+                .iter()
+                .flat_map(|team| &team.gitlab_groups)
+                .any(|team_group| &team_group.path == group)
+                || (provision_team_groups && group.starts_with(&teams_group));
+            if !is_team_group {
+                warn!("Ignoring access level of {}, it is no team group", group);
+            }
+        }
+        state.lock().await.teams = teams;
+        let team_avatar = match env::var("GLUEBUDDY_GITLAB_TEAM_AVATAR") {
+            Ok(path) => Some(TeamAvatar {
+                file_name: Path::new(&path)
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .with_context(|| format!("Invalid team avatar {}", path))?
+                    .to_string(),
+                content: fs::read(&path)
+                    .with_context(|| format!("Failed to read team avatar {}", path))?,
+            }),
+            Err(_) => None,
+        };
+        let registry_keep_tags = match env::var("GLUEBUDDY_GITLAB_REGISTRY_KEEP_TAGS") {
+            Ok(keep) => Some(keep.parse().map_err(|_| ConfigError::Invalid {
+                name: "GLUEBUDDY_GITLAB_REGISTRY_KEEP_TAGS".to_string(),
+                reason: "expected a number".to_string(),
+            })?),
+            Err(_) => None,
+        };
+        Ok(GitLabGlue {
+            client,
+            state,
+            plan,
+            identity_providers,
+            root_group,
+            registry_keep_tags,
+            spam_label: env::var("GLUEBUDDY_GITLAB_SPAM_LABEL").ok(),
+            provision_team_groups,
+            access_levels,
+            team_avatar,
+            project_policy: ProjectPolicy::from_env()?,
+            member_access_levels: Mutex::new(BTreeMap::new()),
+            subgroups: Mutex::new(BTreeMap::new()),
+            subgroups_cached: AtomicBool::new(false),
+            enforcements: vec![],
+            scope_group: None,
+            scope_projects: vec![],
+            snapshot_access_levels: None,
+        })
+    }
+
+    /// Restricts runs to the given enforcements, so that cheap and expensive
+    /// ones can be scheduled separately.
+    pub fn with_enforcements(mut self, enforcements: &[Enforcement]) -> GitLabGlue {
+        self.enforcements = enforcements.to_vec();
+        self
+    }
+
+    /// Restricts the sweep to a group subtree or single projects, which must
+    /// lie below the root group and the scoped group.
+    pub fn with_scope(mut self, scope: &GitlabScope) -> Result<GitLabGlue> {
+        let group = scope
+            .group
+            .as_deref()
+            .map(|group| group.trim_matches('/').to_string());
+        if let Some(group) = &group {
+            if !is_within(group, &self.root_group) {
+                return Err(ConfigError::Invalid {
+                    name: "--group".to_string(),
+                    reason: format!("{} is not below the root group {}", group, self.root_group),
+                }
+                .into());
+            }
+        }
+        let outer = group.as_deref().unwrap_or(&self.root_group);
+        let projects: Vec<String> = scope
+            .project
+            .iter()
+            .map(|project| project.trim_matches('/').to_string())
+            .collect();
+        if let Some(project) = projects
+            .iter()
+            .find(|project| !is_within(project, outer) || project.as_str() == outer)
+        {
+            return Err(ConfigError::Invalid {
+                name: "--project".to_string(),
+                reason: format!("{} is not a project below {}", project, outer),
+            }
+            .into());
+        }
+        self.scope_group = group;
+        self.scope_projects = projects;
+        Ok(self)
+    }
+
+    /// Highest access level of members in the swept group or project.
+    fn max_access_level(&self, path: &str) -> AccessLevel {
+        self.project_policy
+            .max_access_level(path)
+            .unwrap_or(MAX_ACCESS_LEVEL)
+    }
+
+    fn enforces(&self, enforcement: Enforcement) -> bool {
+        self.enforcements.is_empty() || self.enforcements.contains(&enforcement)
+    }
+
+    /// Verifies that the token may do everything gluebuddy needs, so that a
+    /// misconfigured token fails before planning instead of halfway through.
+    pub async fn preflight(&self) -> Result<()> {
+        let endpoint = gitlab::api::users::CurrentUser::builder().build().unwrap();
+        let user: CurrentUser = endpoint
+            .query_async(&self.client)
+            .await
+            .context("Failed to authenticate to GitLab, check GLUEBUDDY_GITLAB_TOKEN")?;
+        if !user.is_admin {
+            bail!(
+                "GitLab user {} is no administrator, which is required to look up users by their identity",
+                user.username
+            );
+        }
+
+        // Token introspection is only available on recent GitLab versions
+        let token: PersonalAccessToken =
+            match PersonalAccessTokenSelf.query_async(&self.client).await {
+                Ok(token) => token,
+                Err(err) => {
+                    debug!("Skipping GitLab token scope check: {}", err);
+                    return Ok(());
+                }
+            };
+        if !token
+            .scopes
+            .iter()
+            .any(|scope| scope == REQUIRED_TOKEN_SCOPE)
+        {
+            bail!(
+                "GitLab token {} lacks the {} scope, it only has {}",
+                token.name,
+                REQUIRED_TOKEN_SCOPE,
+                token.scopes.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Creates or updates the gluebuddy report comment on the given issue.
+    pub async fn post_report(&self, issue: &IssueReference, report: &str) -> Result<()> {
+        let body = format!("{}\n{}", REPORT_MARKER, report);
+
+        let endpoint = gitlab::api::projects::issues::notes::IssueNotes::builder()
+            .project(issue.project.as_str())
+            .issue(issue.iid)
+            .build()
+            .unwrap();
+        let notes: Vec<IssueNote> = gitlab::api::paged(endpoint, gitlab::api::Pagination::All)
+            .query_async(&self.client)
+            .await
+            .with_context(|| format!("Failed to get notes of {}#{}", issue.project, issue.iid))?;
+
+        match notes
+            .iter()
+            .find(|note| note.body.starts_with(REPORT_MARKER))
+        {
+            Some(note) => {
+                debug!(
+                    "update report note {} on {}#{}",
+                    note.id, issue.project, issue.iid
+                );
+                let endpoint = gitlab::api::projects::issues::notes::EditIssueNote::builder()
+                    .project(issue.project.as_str())
+                    .issue(issue.iid)
+                    .note(note.id)
+                    .body(body)
+                    .build()
+                    .unwrap();
+                gitlab::api::ignore(endpoint)
+                    .query_async(&self.client)
+                    .await?;
+            }
+            None => {
+                debug!("create report note on {}#{}", issue.project, issue.iid);
+                let endpoint = gitlab::api::projects::issues::notes::CreateIssueNote::builder()
+                    .project(issue.project.as_str())
+                    .issue(issue.iid)
+                    .body(body)
+                    .build()
+                    .unwrap();
+                gitlab::api::ignore(endpoint)
+                    .query_async(&self.client)
+                    .await?;
+            }
+        }
+        info!("Posted report to {}#{}", issue.project, issue.iid);
+        Ok(())
+    }
+
+    /// Commits the generated files that differ from the repository in a
+    /// single commit to a branch of the project and opens a merge request for
+    /// it, unless one is open already.
+    pub async fn propose_generated_files(
+        &self,
+        project: &str,
+        files: &[ChangedFile],
+    ) -> Result<()> {
+        if files.is_empty() {
+            debug!("No generated files changed, skipping merge request");
+            return Ok(());
+        }
+
+        let endpoint = gitlab::api::projects::merge_requests::MergeRequests::builder()
+            .project(project)
+            .source_branch(GENERATED_FILES_BRANCH)
+            .state(MergeRequestState::Opened)
+            .build()
+            .unwrap();
+        let open: Vec<MergeRequest> = endpoint
+            .query_async(&self.client)
+            .await
+            .with_context(|| format!("Failed to get merge requests of {}", project))?;
+
+        // Without an open merge request the branch is reset to main, so that
+        // changes never stack on a stale branch
+        let target = match open.is_empty() {
+            true => MAIN_BRANCH,
+            false => GENERATED_FILES_BRANCH,
+        };
+        let mut actions = vec![];
+        for file in files {
+            let path = file.path.to_string_lossy();
+            let current = self.get_repository_file(project, &path, target).await?;
+            if let Some(action) = CommitAction::for_file(&path, &file.content, current.as_deref()) {
+                actions.push(action);
+            }
+        }
+        if actions.is_empty() {
+            debug!("Generated files match {} of {}", target, project);
+            return Ok(());
+        }
+
+        let commit_message = "Update generated files from Keycloak";
+        debug!(
+            "commit {} files to {}",
+            actions.len(),
+            GENERATED_FILES_BRANCH
+        );
+        let endpoint = CreateCommit {
+            project: project.into(),
+            branch: GENERATED_FILES_BRANCH,
+            start_branch: MAIN_BRANCH,
+            commit_message,
+            actions: &actions,
+            force: open.is_empty(),
+        };
+        gitlab::api::ignore(endpoint)
+            .query_async(&self.client)
+            .await
+            .with_context(|| format!("Failed to commit generated files to {}", project))?;
+
+        if let Some(merge_request) = open.first() {
+            info!("Updated merge request {}", merge_request.web_url);
+            return Ok(());
+        }
+
+        let paths: Vec<String> = actions
+            .iter()
+            .map(|action| format!("- `{}`", action.file_path))
+            .collect();
+        let endpoint = gitlab::api::projects::merge_requests::CreateMergeRequest::builder()
+            .project(project)
+            .source_branch(GENERATED_FILES_BRANCH)
+            .target_branch(MAIN_BRANCH)
+            .title(commit_message)
+            .description(format!(
+                "Generated by gluebuddy from the Keycloak state:\n\n{}",
+                paths.join("\n")
+            ))
+            .remove_source_branch(true)
+            .build()
+            .unwrap();
+        let merge_request: MergeRequest = endpoint
+            .query_async(&self.client)
+            .await
+            .with_context(|| format!("Failed to open merge request on {}", project))?;
+        info!("Opened merge request {}", merge_request.web_url);
+        Ok(())
+    }
+
+    /// Returns the content of a repository file at the ref, None if missing.
+    async fn get_repository_file(
+        &self,
+        project: &str,
+        path: &str,
+        ref_: &str,
+    ) -> Result<Option<String>> {
+        let endpoint = RepositoryFile {
+            project: project.into(),
+            file_path: path,
+            ref_,
+        };
+        let file: RepositoryFileContent = match endpoint.query_async(&self.client).await {
+            Ok(file) => file,
+            Err(err) => {
+                let err = anyhow::Error::new(err);
+                if error::classify(&err) == ErrorClass::NotFound {
+                    return Ok(None);
+                }
+                return Err(err.context(format!("Failed to get {} of {}", path, project)));
+            }
+        };
+        let content = base64::decode(file.content.replace('\n', ""))
+            .context("invalid base64")
+            .and_then(|content| String::from_utf8(content).context("invalid UTF-8"))
+            .with_context(|| format!("Failed to decode {} of {}", path, project))?;
+        Ok(Some(content))
+    }
+
+    /// Access levels of all group members observed during the run.
+    pub async fn member_access_levels(&self) -> BTreeMap<String, BTreeMap<String, u64>> {
+        self.member_access_levels.lock().await.clone()
     }
 
-    pub async fn gather(&self) -> Result<()> {
-        self.gather_gitlab_user_ids().await?;
+    async fn gather_state(&self, cache: Option<&Cache>) -> Result<()> {
+        if self.provision_team_groups {
+            let mut state = self.state.lock().await;
+            for team in state.discover_teams(&self.root_group, &self.access_levels) {
+                match team {
+                    Ok(team) => info!(
+                        "Discovered Keycloak team {} for GitLab group {}",
+                        team.name, team.gitlab_groups[0].path
+                    ),
+                    Err(err) => {
+                        error!("{:?}", err);
+                        self.plan.record_error(&err);
+                    }
+                }
+            }
+        }
+
+        let cached_ids: HashMap<String, u64> = match cache {
+            Some(cache) => cache.load(CacheSource::GitLab)?.unwrap_or_default(),
+            None => HashMap::new(),
+        };
+
+        self.gather_gitlab_user_ids(&cached_ids).await?;
+
+        if let Some(cache) = cache {
+            if let Some(subgroups) = cache.load(CacheSource::GitLabGroups)? {
+                *self.subgroups.lock().await = subgroups;
+                self.subgroups_cached.store(true, Ordering::Relaxed);
+            }
+
+            let state = self.state.lock().await;
+            let gitlab_ids: HashMap<&String, u64> = state
+                .users
+                .iter()
+                .filter_map(|(username, user)| user.gitlab_id.map(|id| (username, id)))
+                .collect();
+            // Storing ids loaded offline would extend the lifetime of the cache
+            if !self.client.is_offline() {
+                cache.store(CacheSource::GitLab, &gitlab_ids)?;
+            }
+        }
         Ok(())
     }
 
-    pub async fn gather_gitlab_user_ids(&self) -> Result<()> {
+    /// Stores the subgroup tree seen during the run, so that consecutive runs
+    /// don't have to list the subgroups of every group again. Project listings
+    /// are not cached as their settings are diffed on every run.
+    pub async fn store_group_tree(&self, cache: &Cache) -> Result<()> {
+        let subgroups = self.subgroups.lock().await;
+        // Storing a tree loaded from the cache would extend its lifetime
+        if subgroups.is_empty() || self.subgroups_cached.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        cache.store(CacheSource::GitLabGroups, &*subgroups)
+    }
+
+    pub async fn gather_gitlab_user_ids(&self, cached_ids: &HashMap<String, u64>) -> Result<()> {
         info!("Gathering GitLab state");
-        let mut state = self.state.lock().await;
-        for user in &mut state.users.values_mut() {
-            let username = &user.username;
-            let users_endpoint = gitlab::api::users::Users::builder()
-                .external_provider(
-                    ExternalProvider::builder()
-                        .uid(username)
-                        .name("saml")
-                        .build()
-                        .unwrap(),
-                )
-                .active(())
-                .external(false)
-                .build()
-                .unwrap();
-            let users: Vec<GitLabUser> = users_endpoint.query_async(&self.client).await?;
-            if users.is_empty() {
-                warn!("Failed to query GitLab user for {}", username);
-                continue;
-            } else if users.len() > 1 {
-                bail!(
-                    "Somehow got {} GitLab user results for {}",
-                    users.len(),
-                    username
-                )
+        // Collect the lookups first so the state is not locked during the requests
+        let mut lookups: Vec<(String, Vec<String>)> = {
+            let mut state = self.state.lock().await;
+            let mut lookups = vec![];
+            for user in state.users.values_mut() {
+                match cached_ids.get(&user.username) {
+                    Some(gitlab_id) => {
+                        trace!("Using cached GitLab id {} for {}", gitlab_id, user.username);
+                        user.gitlab_id = Some(*gitlab_id);
+                    }
+                    None => lookups.push((user.username.clone(), user.aliases.clone())),
+                }
             }
-            let gitlab_user = users
-                .first()
-                .with_context(|| format!("Failed to query GitLab user for {}", username))?;
-            debug!(
-                "Successfully retrieved user {} to GitLab id {}",
-                gitlab_user.username, gitlab_user.id
+            lookups
+        };
+        if self.client.is_offline() && !lookups.is_empty() {
+            warn!(
+                "Skipping {} users without cached GitLab id offline",
+                lookups.len()
             );
-            if user.username != gitlab_user.username {
+            lookups.clear();
+        }
+
+        let mut progress = util::Progress::new("Resolving GitLab users", lookups.len());
+        let mut lookups = stream::iter(lookups)
+            .map(|(username, aliases)| async move {
+                let result = self.find_gitlab_user(&username, &aliases).await;
+                (username, result)
+            })
+            .buffer_unordered(GITLAB_USER_LOOKUP_CONCURRENCY);
+        let mut resolved = vec![];
+        while let Some((username, result)) = lookups.next().await {
+            progress.inc();
+            if let Some(gitlab_user) = result? {
+                resolved.push((username, gitlab_user));
+            }
+        }
+
+        let mut state = self.state.lock().await;
+        for (username, gitlab_user) in resolved {
+            let user = match state.users.get_mut(&username) {
+                Some(user) => user,
+                None => continue,
+            };
+            if !user.is_known_as(&gitlab_user.username) {
                 error!(
                     "Username mismatch between keycloak and GitLab: {} vs {}",
                     user.username, gitlab_user.username
                 );
             }
             user.gitlab_id = Some(gitlab_user.id);
+            user.gitlab_username = Some(gitlab_user.username);
         }
+        state.index_gitlab_ids();
 
         Ok(())
     }
 
-    pub async fn run(&self, action: Action) -> Result<()> {
-        self.update_archlinux_group_recursively(&action).await?;
-        self.update_archlinux_group_members(&action).await?;
-        self.update_staff_group_members(&action).await?;
-        self.update_devops_group_members(&action).await?;
-        self.update_infrastructure_project_members(&action).await?;
-        Ok(())
+    /// Looks up the GitLab user by the username and all aliases in order of
+    /// the identity providers' priority.
+    async fn find_gitlab_user(
+        &self,
+        username: &str,
+        aliases: &[String],
+    ) -> Result<Option<GitLabUser>> {
+        let mut users: Vec<GitLabUser> = vec![];
+        'lookup: for uid in std::iter::once(username).chain(aliases.iter().map(String::as_str)) {
+            for provider in &self.identity_providers {
+                users = self.get_users_by_identity(provider, uid).await?;
+                if !users.is_empty() {
+                    trace!(
+                        "Found GitLab user for {} as {} via {}",
+                        username,
+                        uid,
+                        provider
+                    );
+                    break 'lookup;
+                }
+            }
+        }
+        if users.is_empty() {
+            warn!("Failed to query GitLab user for {}", username);
+            return Ok(None);
+        } else if users.len() > 1 {
+            bail!(
+                "Somehow got {} GitLab user results for {}",
+                users.len(),
+                username
+            )
+        }
+        let gitlab_user = users.remove(0);
+        debug!(
+            "Successfully retrieved user {} to GitLab id {}",
+            gitlab_user.username, gitlab_user.id
+        );
+        Ok(Some(gitlab_user))
     }
 
-    async fn update_archlinux_group_recursively(&self, action: &Action) -> Result<()> {
-        let group = "archlinux";
-        let endpoint = gitlab::api::groups::Group::builder()
-            .group(group)
+    async fn get_users_by_identity(
+        &self,
+        provider: &str,
+        username: &str,
+    ) -> Result<Vec<GitLabUser>> {
+        let endpoint = gitlab::api::users::Users::builder()
+            .external_provider(
+                ExternalProvider::builder()
+                    .uid(username)
+                    .name(provider)
+                    .build()
+                    .unwrap(),
+            )
+            .active(())
+            .external(false)
             .build()
             .unwrap();
-        let root: Group = endpoint.query_async(&self.client).await?;
+        let users: Vec<GitLabUser> = endpoint.query_async(&self.client).await?;
+        Ok(users)
+    }
 
-        let mut to_visit = vec![root];
+    /// Gathers only the named resource and plans its drift, resource types
+    /// whose policy depends on the Keycloak state are not supported.
+    pub async fn diff(&self, resource_type: &str, address: &str) -> Result<()> {
+        self.plan.start_component("GitLab");
+        match resource_type {
+            "gitlab_project_setting" => {
+                if !address.starts_with(&format!("{}/", self.root_group)) {
+                    bail!(
+                        "Project {} is not below the enforced group {}",
+                        address,
+                        self.root_group
+                    );
+                }
+                let endpoint = gitlab::api::projects::Project::builder()
+                    .project(address)
+                    .build()
+                    .unwrap();
+                let project: GroupProjects = endpoint
+                    .query_async(&self.client)
+                    .await
+                    .with_context(|| format!("Failed to get GitLab project {}", address))?;
+                let label = format!("GitLab '{}' project settings", project.name_with_namespace);
+                let mut summary = PlanSummary::new(&label);
+                if self.apply_project_settings(&Action::Plan, &project).await? {
+                    summary.change += 1;
+                }
+                self.plan.finish_section(&summary)?;
+            }
+            _ => bail!(ConfigError::Invalid {
+                name: "resource type".to_string(),
+                reason: format!(
+                    "{} can not be diffed on its own, supported: {}",
+                    resource_type,
+                    DIFFABLE_RESOURCE_TYPES.join(", ")
+                ),
+            }),
+        }
+        Ok(())
+    }
 
-        let state = self.state.lock().await;
+    /// Reports packaging projects whose `.SRCINFO` is missing, malformed or
+    /// would change the project description, all of them if none are given.
+    pub async fn verify_srcinfo(&self, projects: &[String]) -> Result<()> {
+        let packages_group = format!("{}/{}", self.root_group, PACKAGES_GROUP);
+        let projects: Vec<PackageProject> = match projects.is_empty() {
+            true => {
+                let endpoint = gitlab::api::groups::projects::GroupProjects::builder()
+                    .group(packages_group.as_str())
+                    .order_by(GroupProjectsOrderBy::Path)
+                    .build()
+                    .unwrap();
+                gitlab::api::paged(endpoint, gitlab::api::Pagination::All)
+                    .query_async(&self.client)
+                    .await?
+            }
+            false => {
+                let packages_group = &packages_group;
+                stream::iter(projects)
+                    .map(|project| async move {
+                        let path = match project.contains('/') {
+                            true => project.to_string(),
+                            false => format!("{}/{}", packages_group, project),
+                        };
+                        let endpoint = gitlab::api::projects::Project::builder()
+                            .project(path.as_str())
+                            .build()
+                            .unwrap();
+                        endpoint
+                            .query_async(&self.client)
+                            .await
+                            .with_context(|| format!("Failed to get GitLab project {}", path))
+                    })
+                    .buffered(GITLAB_TRAVERSAL_CONCURRENCY)
+                    .try_collect()
+                    .await?
+            }
+        };
 
-        while !to_visit.is_empty() {
-            match to_visit.pop() {
-                None => {}
-                Some(group) => {
-                    let subgroups = self.get_group_subgroups(&group.full_path).await?;
-                    for subgroup in subgroups {
-                        to_visit.push(subgroup);
-                    }
+        let findings: Vec<Option<String>> = stream::iter(&projects)
+            .map(|project| self.verify_project_srcinfo(project))
+            .buffered(GITLAB_TRAVERSAL_CONCURRENCY)
+            .try_collect()
+            .await?;
+        let findings: Vec<String> = findings.into_iter().flatten().collect();
+        for finding in &findings {
+            println!("{}", finding);
+        }
+        if !findings.is_empty() {
+            bail!(
+                "{} of {} projects failed the {} verification",
+                findings.len(),
+                projects.len(),
+                SRCINFO_PATH
+            );
+        }
+        info!("Verified {} of {} projects", SRCINFO_PATH, projects.len());
+        Ok(())
+    }
+
+    /// Returns why the `.SRCINFO` of the project fails the verification.
+    async fn verify_project_srcinfo(&self, project: &PackageProject) -> Result<Option<String>> {
+        let path = &project.path_with_namespace;
+        let branch = match &project.default_branch {
+            Some(branch) => branch,
+            None => return Ok(Some(format!("{}: missing {}", path, SRCINFO_PATH))),
+        };
+        let endpoint = RepositoryFile {
+            project: project.id.into(),
+            file_path: SRCINFO_PATH,
+            ref_: branch,
+        };
+        let file: RepositoryFileContent = match endpoint.query_async(&self.client).await {
+            Ok(file) => file,
+            Err(err) => {
+                let err = anyhow::Error::new(err);
+                if error::classify(&err) == ErrorClass::NotFound {
+                    return Ok(Some(format!("{}: missing {}", path, SRCINFO_PATH)));
+                }
+                return Err(err.context(format!("Failed to get {} of {}", SRCINFO_PATH, path)));
+            }
+        };
+
+        let srcinfo = base64::decode(file.content.replace('\n', ""))
+            .context("invalid base64")
+            .and_then(|content| String::from_utf8(content).context("invalid UTF-8"))
+            .and_then(|content| Srcinfo::parse(&content));
+        let srcinfo = match srcinfo {
+            Ok(srcinfo) => srcinfo,
+            Err(err) => {
+                return Ok(Some(format!(
+                    "{}: malformed {}: {}",
+                    path, SRCINFO_PATH, err
+                )))
+            }
+        };
+        let description = project.description.as_deref().unwrap_or_default();
+        if description != srcinfo.pkgdesc {
+            return Ok(Some(format!(
+                "{}: description would change from {:?} to {:?}",
+                path, description, srcinfo.pkgdesc
+            )));
+        }
+        Ok(None)
+    }
+
+    /// Removes all but the most recent tags of every container repository in
+    /// the root group. Untagged manifests are left to the garbage
+    /// collection of the registry as the API can not address them.
+    async fn cleanup_container_registries(&self, action: &Action, keep: usize) -> Result<()> {
+        let endpoint = GroupRegistryRepositories {
+            group: self.root_group.as_str().into(),
+        };
+        let repositories: Vec<RegistryRepository> =
+            gitlab::api::paged(endpoint, gitlab::api::Pagination::All)
+                .query_async(&self.client)
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to get container repositories of {}",
+                        self.root_group
+                    )
+                })?;
+
+        let mut storage: BTreeMap<u64, u64> = BTreeMap::new();
+        for repository in &repositories {
+            let size = self
+                .cleanup_container_repository(action, repository, keep)
+                .await?;
+            *storage.entry(repository.project_id).or_default() += size;
+        }
+        for (project_id, size) in storage {
+            info!(
+                "Container registry of project {} uses {:.1} MiB",
+                project_id,
+                size as f64 / (1024.0 * 1024.0)
+            );
+        }
+        Ok(())
+    }
+
+    /// Blocks the authors of open issues with the spam label in the packaging
+    /// group and closes all their open issues there. Authors known to
+    /// Keycloak are never blocked, their labels are left for a human.
+    async fn mitigate_spam(&self, action: &Action, label: &str) -> Result<()> {
+        let group = format!("{}/{}", self.root_group, PACKAGING_GROUP);
+        let endpoint = GroupOpenIssues {
+            group: group.as_str().into(),
+            label: Some(label),
+            author_id: None,
+        };
+        let issues: Vec<Issue> = gitlab::api::paged(endpoint, gitlab::api::Pagination::All)
+            .query_async(&self.client)
+            .await
+            .with_context(|| format!("Failed to get issues labeled {} in {}", label, group))?;
+        let authors: BTreeMap<u64, IssueAuthor> = issues
+            .into_iter()
+            .map(|issue| (issue.author.id, issue.author))
+            .collect();
+
+        let state = self.snapshot_state().await;
+        let mut summary = PlanSummary::new("GitLab spam accounts");
+        for author in authors.values() {
+            if state.user_from_gitlab_id(author.id).is_some()
+                || [GITLAB_OWNER, GITLAB_BOT].contains(&author.username.as_str())
+            {
+                warn!(
+                    "Not blocking {}, a known account authored issues labeled {}",
+                    author.username, label
+                );
+                continue;
+            }
 
-                    // TODO: disable request_access_enabled, API is missing to edit
-
-                    let label = format!("GitLab '{}' group members", group.full_name);
-                    let mut summary = PlanSummary::new(&label);
-                    let members = self.get_group_members(&group.full_path).await?;
-                    for member in &members {
-                        if is_archlinux_bot(member) {
-                            continue;
-                        }
-
-                        match state.staff_from_gitlab_id(member.id) {
-                            None => {
-                                if self
-                                    .remove_group_member(action, &state, member, &group.full_path)
-                                    .await?
-                                {
-                                    summary.destroy += 1;
-                                }
-                            }
-                            Some(user) => {
-                                if self
-                                    .edit_group_member_max_access_level(
-                                        action,
-                                        user,
-                                        member,
-                                        &group.full_path,
-                                        MAX_ACCESS_LEVEL,
-                                    )
-                                    .await?
-                                {
-                                    summary.change += 1;
-                                }
-                            }
-                        }
+            if author.state != "blocked" {
+                let entry = PlanEntry::change(
+                    Resource::gitlab_user_state(&author.username, &author.state),
+                    Resource::gitlab_user_state(&author.username, "blocked"),
+                    RULE_SPAM,
+                );
+                self.plan.record(&entry)?;
+                summary.change += 1;
+                if self.plan.applies(action, &entry) {
+                    let result = gitlab::api::ignore(BlockUser { user: author.id })
+                        .query_async(&self.client)
+                        .await
+                        .with_context(|| format!("Failed to block {}", author.username));
+                    if self.record_apply_result(&entry, result)? {
+                        let actual = self.get_user_state(author.id).await;
+                        self.plan.verify_applied(&entry, actual);
                     }
+                }
+            }
 
-                    println!("{}", summary);
-                    println!("{}", util::format_separator());
-
-                    let projects = self.get_group_projects(&group.full_path).await?;
-                    for project in projects {
-                        let label =
-                            format!("GitLab '{}' project settings", project.name_with_namespace);
-                        let mut summary = PlanSummary::new(&label);
-
-                        match self.apply_project_settings(action, &project).await? {
-                            false => {}
-                            true => {
-                                summary.change += 1;
-                            }
-                        }
-
-                        println!("{}", summary);
-                        println!("{}", util::format_separator());
-
-                        let label =
-                            format!("GitLab '{}' project members", project.name_with_namespace);
-                        let mut summary = PlanSummary::new(&label);
-                        let members = self
-                            .get_project_members(&project.path_with_namespace)
-                            .await?;
-
-                        for member in &members {
-                            if is_archlinux_bot(member) {
-                                continue;
-                            }
-
-                            match state.staff_with_externals_from_gitlab_id(member.id) {
-                                None => {
-                                    if self
-                                        .remove_project_member(
-                                            action,
-                                            member,
-                                            &project.path_with_namespace,
-                                        )
-                                        .await?
-                                    {
-                                        summary.destroy += 1;
-                                    }
-                                }
-                                Some(user) => {
-                                    if self
-                                        .edit_project_member_max_access_level(
-                                            action,
-                                            user,
-                                            member,
-                                            &project.path_with_namespace,
-                                            MAX_ACCESS_LEVEL,
-                                        )
-                                        .await?
-                                    {
-                                        summary.change += 1;
-                                    }
-                                }
-                            }
-                        }
-
-                        println!("{}", summary);
-                        println!("{}", util::format_separator());
+            let endpoint = GroupOpenIssues {
+                group: group.as_str().into(),
+                label: None,
+                author_id: Some(author.id),
+            };
+            let issues: Vec<Issue> = gitlab::api::paged(endpoint, gitlab::api::Pagination::All)
+                .query_async(&self.client)
+                .await
+                .with_context(|| format!("Failed to get issues of {}", author.username))?;
+            for issue in &issues {
+                let entry = PlanEntry::change(
+                    Resource::gitlab_issue_state(&issue.references.full, &issue.title, "opened"),
+                    Resource::gitlab_issue_state(&issue.references.full, &issue.title, "closed"),
+                    RULE_SPAM,
+                );
+                self.plan.record(&entry)?;
+                summary.change += 1;
+                if self.plan.applies(action, &entry) {
+                    let endpoint = CloseIssue {
+                        project: issue.project_id,
+                        issue: issue.iid,
+                    };
+                    let result = gitlab::api::ignore(endpoint)
+                        .query_async(&self.client)
+                        .await
+                        .with_context(|| format!("Failed to close {}", issue.references.full));
+                    if self.record_apply_result(&entry, result)? {
+                        let actual = self.get_issue_state(issue).await;
+                        self.plan.verify_applied(&entry, actual);
                     }
                 }
             }
         }
+        self.plan.finish_section(&summary)
+    }
+
+    /// Returns the size of all tags that are kept.
+    async fn cleanup_container_repository(
+        &self,
+        action: &Action,
+        repository: &RegistryRepository,
+        keep: usize,
+    ) -> Result<u64> {
+        let endpoint = RegistryRepositoryTags {
+            project: repository.project_id,
+            repository: repository.id,
+        };
+        let tags: Vec<RegistryTag> = gitlab::api::paged(endpoint, gitlab::api::Pagination::All)
+            .query_async(&self.client)
+            .await
+            .with_context(|| format!("Failed to get tags of {}", repository.path))?;
+
+        let mut details = vec![];
+        for tag in &tags {
+            let endpoint = RegistryRepositoryTag {
+                project: repository.project_id,
+                repository: repository.id,
+                tag: &tag.name,
+            };
+            let tag: RegistryTagDetails =
+                endpoint.query_async(&self.client).await.with_context(|| {
+                    format!("Failed to get tag {} of {}", tag.name, repository.path)
+                })?;
+            details.push(tag);
+        }
+        // RFC 3339 timestamps of the same registry sort chronologically
+        details.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+
+        let label = format!("GitLab '{}' registry tags", repository.path);
+        let mut summary = PlanSummary::new(&label);
+        let mut kept_size = 0;
+        for (index, tag) in details.iter().enumerate() {
+            if index < keep || tag.name.eq(REGISTRY_LATEST_TAG) {
+                kept_size += tag.total_size;
+                continue;
+            }
+            let entry = PlanEntry::destroy(
+                Resource::gitlab_registry_tag(&repository.path, &tag.name, &tag.created_at),
+                RULE_REGISTRY_RETENTION,
+            );
+            self.plan.record(&entry)?;
+            if self.plan.applies(action, &entry) {
+                let endpoint = DeleteRegistryRepositoryTag {
+                    project: repository.project_id,
+                    repository: repository.id,
+                    tag: &tag.name,
+                };
+                let result = gitlab::api::ignore(endpoint)
+                    .query_async(&self.client)
+                    .await
+                    .with_context(|| {
+                        format!("Failed to delete tag {} of {}", tag.name, repository.path)
+                    });
+                if self.record_apply_result(&entry, result)? {
+                    let actual = self.get_registry_tag(repository, &tag.name).await;
+                    self.plan.verify_applied(&entry, actual);
+                }
+            }
+            summary.destroy += 1;
+        }
+        self.plan.finish_section(&summary)?;
+        Ok(kept_size)
+    }
 
+    /// Gathers the members of all team GitLab groups without planning changes.
+    pub async fn gather_team_group_members(&self) -> Result<()> {
+        let teams = self.state.lock().await.teams.clone();
+        for team in &teams {
+            for group in &team.gitlab_groups {
+                self.get_group_members(&group.path).await?;
+            }
+        }
         Ok(())
     }
 
-    async fn update_archlinux_group_members(&self, action: &Action) -> Result<()> {
-        let group = "archlinux";
-        let archlinux_group_members = self.get_group_members(group).await?;
+    /// Copies the state, so that long enforcement loops do not hold the state
+    /// lock while waiting for GitLab.
+    async fn snapshot_state(&self) -> Arc<State> {
+        Arc::new(self.state.lock().await.clone())
+    }
+
+    async fn update_archlinux_group_recursively(&self, action: &Action) -> Result<()> {
+        if !self.scope_projects.is_empty() {
+            return self.update_scoped_projects(action).await;
+        }
+        let top = self.scope_group.as_deref().unwrap_or(&self.root_group);
+        let endpoint = gitlab::api::groups::Group::builder()
+            .group(top)
+            .build()
+            .unwrap();
+        let root: Group = endpoint.query_async(&self.client).await?;
+        let groups = self.get_group_tree(root).await?;
 
-        let mut summary = PlanSummary::new("GitLab 'Arch Linux' group members");
-        let state = self.state.lock().await;
+        let state = self.snapshot_state().await;
+        let mut progress = util::Progress::new("Traversing GitLab groups", groups.len());
 
-        for staff in state.staff() {
-            if let Some(gitlab_id) = staff.gitlab_id {
-                if !archlinux_group_members
-                    .iter()
-                    .map(|e| e.id)
-                    .any(|e| e == gitlab_id)
-                    && self
-                        .add_group_member(
-                            action,
-                            staff,
-                            group,
-                            DEFAULT_ARCH_LINUX_GROUP_ACCESS_LEVEL,
-                        )
-                        .await?
-                {
-                    summary.add += 1;
-                }
+        // Listings are fetched concurrently, but processed in order to keep the plan stable
+        let mut listings = stream::iter(&groups)
+            .map(|group| self.get_group_listing(group))
+            .buffered(GITLAB_TRAVERSAL_CONCURRENCY);
+        while let Some(listing) = listings.next().await {
+            let (group, members) = listing?;
+            progress.inc();
+
+            // TODO: disable request_access_enabled, API is missing to edit
+
+            self.update_group_members(action, &state, group, &members)
+                .await?;
+
+            // Projects are processed page by page while the listing continues,
+            // the packaging group alone has thousands of them
+            let endpoint = gitlab::api::groups::projects::GroupProjects::builder()
+                .group(group.full_path.as_str())
+                .order_by(GroupProjectsOrderBy::Path)
+                .build()
+                .unwrap();
+            let paged = gitlab::api::paged(endpoint, gitlab::api::Pagination::All);
+            let projects = paged
+                .iter_async::<_, GroupProjects>(&self.client)
+                .map(|project| async move {
+                    let project = project?;
+                    let members = self
+                        .get_project_members(&project.path_with_namespace)
+                        .await?;
+                    Ok::<_, anyhow::Error>((project, members))
+                })
+                .buffered(GITLAB_TRAVERSAL_CONCURRENCY);
+            futures::pin_mut!(projects);
+            while let Some(project) = projects.next().await {
+                let (project, members) = project?;
+                self.update_project(action, &state, &project, &members)
+                    .await?;
             }
         }
 
-        for member in &archlinux_group_members {
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(group = %group.full_path))]
+    async fn update_group_members(
+        &self,
+        action: &Action,
+        state: &State,
+        group: &Group,
+        members: &[GitLabMember],
+    ) -> Result<()> {
+        let label = format!("GitLab '{}' group members", group.full_name);
+        let mut summary = PlanSummary::new(&label);
+        for member in members {
             if is_archlinux_bot(member) {
                 continue;
             }
+
             match state.staff_from_gitlab_id(member.id) {
                 None => {
                     if self
-                        .remove_group_member(action, &state, member, group)
+                        .remove_group_member(
+                            action,
+                            member,
+                            &group.full_path,
+                            RULE_STAFF_ONLY_GROUPS,
+                        )
                         .await?
                     {
                         summary.destroy += 1;
@@ -285,12 +1139,12 @@ impl GitLabGlue {
                 }
                 Some(user) => {
                     if self
-                        .edit_group_member_access_level(
+                        .edit_group_member_max_access_level(
                             action,
                             user,
                             member,
-                            group,
-                            DEFAULT_ARCH_LINUX_GROUP_ACCESS_LEVEL,
+                            &group.full_path,
+                            self.max_access_level(&group.full_path),
                         )
                         .await?
                     {
@@ -300,42 +1154,68 @@ impl GitLabGlue {
             }
         }
 
-        println!("{}", summary);
-        println!("{}", util::format_separator());
+        self.plan.finish_section(&summary)
+    }
 
+    /// Sweeps only the projects of the scope, fetching them directly instead
+    /// of listing their groups.
+    async fn update_scoped_projects(&self, action: &Action) -> Result<()> {
+        let state = self.snapshot_state().await;
+        for path in &self.scope_projects {
+            let endpoint = gitlab::api::projects::Project::builder()
+                .project(path.as_str())
+                .build()
+                .unwrap();
+            let project: GroupProjects = endpoint
+                .query_async(&self.client)
+                .await
+                .with_context(|| format!("Failed to get GitLab project {}", path))?;
+            let members = self
+                .get_project_members(&project.path_with_namespace)
+                .await?;
+            self.update_project(action, &state, &project, &members)
+                .await?;
+        }
         Ok(())
     }
 
-    async fn update_staff_group_members(&self, action: &Action) -> Result<()> {
-        let group = "archlinux/teams/staff";
-        let archlinux_group_members = self.get_group_members(group).await?;
-
-        let mut summary = PlanSummary::new("GitLab 'Arch Linux/Teams/Staff' group members");
-        let state = self.state.lock().await;
-
-        for staff in state.staff() {
-            if let Some(gitlab_id) = staff.gitlab_id {
-                if !archlinux_group_members
-                    .iter()
-                    .map(|e| e.id)
-                    .any(|e| e == gitlab_id)
-                    && self
-                        .add_group_member(action, staff, group, DEFAULT_STAFF_GROUP_ACCESS_LEVEL)
-                        .await?
-                {
-                    summary.add += 1;
-                }
+    #[instrument(skip_all, fields(project = %project.path_with_namespace))]
+    async fn update_project(
+        &self,
+        action: &Action,
+        state: &State,
+        project: &GroupProjects,
+        members: &[GitLabMember],
+    ) -> Result<()> {
+        let label = format!("GitLab '{}' project settings", project.name_with_namespace);
+        let mut summary = PlanSummary::new(&label);
+
+        match self.apply_project_settings(action, project).await? {
+            false => {}
+            true => {
+                summary.change += 1;
             }
         }
 
-        for member in &archlinux_group_members {
+        self.plan.finish_section(&summary)?;
+
+        let label = format!("GitLab '{}' project members", project.name_with_namespace);
+        let mut summary = PlanSummary::new(&label);
+
+        for member in members {
             if is_archlinux_bot(member) {
                 continue;
             }
-            match state.staff_from_gitlab_id(member.id) {
+
+            match state.staff_with_externals_from_gitlab_id(member.id) {
                 None => {
                     if self
-                        .remove_group_member(action, &state, member, group)
+                        .remove_project_member(
+                            action,
+                            member,
+                            &project.path_with_namespace,
+                            RULE_STAFF_AND_EXTERNALS_ONLY_PROJECTS,
+                        )
                         .await?
                     {
                         summary.destroy += 1;
@@ -343,12 +1223,12 @@ impl GitLabGlue {
                 }
                 Some(user) => {
                     if self
-                        .edit_group_member_access_level(
+                        .edit_project_member_max_access_level(
                             action,
                             user,
                             member,
-                            group,
-                            DEFAULT_STAFF_GROUP_ACCESS_LEVEL,
+                            &project.path_with_namespace,
+                            self.max_access_level(&project.path_with_namespace),
                         )
                         .await?
                     {
@@ -358,28 +1238,75 @@ impl GitLabGlue {
             }
         }
 
-        println!("{}", summary);
-        println!("{}", util::format_separator());
+        self.plan.finish_section(&summary)?;
 
         Ok(())
     }
 
-    async fn update_devops_group_members(&self, action: &Action) -> Result<()> {
-        let mut summary = PlanSummary::new("GitLab 'Arch Linux/Teams/DevOps' group members");
-        let devops_group = "archlinux/teams/devops";
-        let group_members = self.get_group_members(devops_group).await?;
+    /// Returns the group and all its descendants ordered by path, listing the
+    /// subgroups of multiple groups concurrently.
+    async fn get_group_tree(&self, root: Group) -> Result<Vec<Group>> {
+        let mut groups = vec![];
+        let mut to_visit = vec![root];
+        let mut pending = FuturesUnordered::new();
+        loop {
+            while pending.len() < GITLAB_TRAVERSAL_CONCURRENCY {
+                match to_visit.pop() {
+                    Some(group) => pending.push(async move {
+                        let subgroups = self.get_group_subgroups(&group.full_path).await;
+                        (group, subgroups)
+                    }),
+                    None => break,
+                }
+            }
+            match pending.next().await {
+                Some((group, subgroups)) => {
+                    to_visit.extend(subgroups?);
+                    groups.push(group);
+                }
+                None => break,
+            }
+        }
+        groups.sort_by(|a, b| a.full_path.cmp(&b.full_path));
+        Ok(groups)
+    }
+
+    /// Fetches the members of the group, its projects are streamed separately.
+    async fn get_group_listing<'a>(
+        &self,
+        group: &'a Group,
+    ) -> Result<(&'a Group, Vec<GitLabMember>)> {
+        let members = self.get_group_members(&group.full_path).await?;
+        Ok((group, members))
+    }
 
-        let state = self.state.lock().await;
-        for staff in state.devops() {
-            if let Some(gitlab_id) = staff.gitlab_id {
+    #[instrument(skip_all, fields(group = %group.path))]
+    async fn update_team_group_members(
+        &self,
+        action: &Action,
+        team: &Team,
+        group: &TeamGitLabGroup,
+    ) -> Result<()> {
+        let group_members = match self.ensure_team_group(action, team, group).await? {
+            true => {
+                self.apply_group_settings(action, team, group).await?;
+                self.get_group_members(&group.path).await?
+            }
+            // Members of a group that is only planned to be created are planned as well
+            false if matches!(action, Action::Plan) => vec![],
+            false => return Ok(()),
+        };
+
+        let label = format!("GitLab '{}' group members", group.path);
+        let mut summary = PlanSummary::new(&label);
+        let rule = format!("team {} mapping", team.name);
+        let state = self.snapshot_state().await;
+
+        for user in state.team_members(team) {
+            if let Some(gitlab_id) = user.gitlab_id {
                 if !group_members.iter().map(|e| e.id).any(|e| e == gitlab_id)
                     && self
-                        .add_group_member(
-                            action,
-                            staff,
-                            devops_group,
-                            DEVOPS_INFRASTRUCTURE_ACCESS_LEVEL,
-                        )
+                        .add_group_member(action, user, &group.path, group.access_level, &rule)
                         .await?
                 {
                     summary.add += 1;
@@ -391,60 +1318,208 @@ impl GitLabGlue {
             if is_archlinux_bot(member) {
                 continue;
             }
-            match state.devops_from_gitlab_id(member.id) {
+            match state.team_member_from_gitlab_id(team, member.id) {
                 None => {
                     if self
-                        .remove_group_member(action, &state, member, devops_group)
+                        .remove_group_member(action, member, &group.path, &rule)
                         .await?
                     {
                         summary.destroy += 1;
                     }
                 }
-                Some(user) => match util::access_level_from_u64(member.access_level) {
-                    DEVOPS_INFRASTRUCTURE_ACCESS_LEVEL => {}
-                    _ => {
-                        if self
-                            .edit_group_member_access_level(
-                                action,
-                                user,
-                                member,
-                                devops_group,
-                                DEVOPS_INFRASTRUCTURE_ACCESS_LEVEL,
-                            )
-                            .await?
-                        {
-                            summary.change += 1;
-                        }
+                Some(user) => {
+                    if self
+                        .edit_group_member_access_level(
+                            action,
+                            user,
+                            member,
+                            &group.path,
+                            group.access_level,
+                            &rule,
+                        )
+                        .await?
+                    {
+                        summary.change += 1;
                     }
-                },
+                }
             }
         }
 
-        println!("{}", summary);
-        println!("{}", util::format_separator());
+        self.plan.finish_section(&summary)?;
 
         Ok(())
     }
 
+    /// Creates the team group below the teams group if it does not exist yet,
+    /// returns whether it exists afterwards.
+    async fn ensure_team_group(
+        &self,
+        action: &Action,
+        team: &Team,
+        group: &TeamGitLabGroup,
+    ) -> Result<bool> {
+        let teams_group = format!("{}/teams", self.root_group);
+        let name = match self.team_group_name(&group.path) {
+            Some(name) => name,
+            None => return Ok(true),
+        };
+        let exists = match &self.snapshot_access_levels {
+            Some(access_levels) => access_levels.contains_key(&group.path),
+            None => self.get_group(&group.path).await?.is_some(),
+        };
+        if exists {
+            return Ok(true);
+        }
+
+        let mut summary = PlanSummary::new(&format!("GitLab '{}' group", group.path));
+        let entry = PlanEntry::add(
+            Resource::gitlab_group(&group.path, "private", false),
+            RULE_TEAM_GROUPS,
+        );
+        self.plan.record(&entry)?;
+        summary.add += 1;
+        let mut created = false;
+        if self.plan.applies(action, &entry) {
+            let result = async {
+                let endpoint = gitlab::api::groups::Group::builder()
+                    .group(teams_group.as_str())
+                    .build()
+                    .unwrap();
+                let parent: Group = endpoint.query_async(&self.client).await?;
+                let endpoint = gitlab::api::groups::CreateGroup::builder()
+                    .name(name)
+                    .path(name)
+                    .parent_id(parent.id)
+                    .description(team_group_description(team))
+                    .visibility(VisibilityLevel::Private)
+                    .request_access_enabled(false)
+                    .build()
+                    .unwrap();
+                gitlab::api::ignore(endpoint)
+                    .query_async(&self.client)
+                    .await?;
+                Ok::<_, anyhow::Error>(())
+            }
+            .await
+            .with_context(|| format!("Failed to create GitLab group {}", group.path));
+            created = result.is_ok();
+            self.record_apply_result(&entry, result)?;
+        }
+        self.plan.finish_section(&summary)?;
+        Ok(created)
+    }
+
+    /// Keeps the description and, if configured, the avatar of a group below
+    /// the teams group consistent. The avatar is compared by its file name as
+    /// GitLab keeps the name of the uploaded file.
+    async fn apply_group_settings(
+        &self,
+        action: &Action,
+        team: &Team,
+        group: &TeamGitLabGroup,
+    ) -> Result<()> {
+        if self.client.is_offline() || self.team_group_name(&group.path).is_none() {
+            return Ok(());
+        }
+        let gitlab_group = self
+            .get_group(&group.path)
+            .await?
+            .with_context(|| format!("GitLab group {} does not exist", group.path))?;
+
+        let description = gitlab_group.description.as_str();
+        let expected_description = team_group_description(team);
+        let avatar = gitlab_group
+            .avatar_url
+            .as_deref()
+            .and_then(|url| url.rsplit('/').next())
+            .unwrap_or_default();
+        let expected_avatar = self
+            .team_avatar
+            .as_ref()
+            .map_or(avatar, |team_avatar| team_avatar.file_name.as_str());
+        if description == expected_description && avatar == expected_avatar {
+            return Ok(());
+        }
+
+        debug!("edit group settings for {}", group.path);
+        let mut summary = PlanSummary::new(&format!("GitLab '{}' group settings", group.path));
+        let entry = PlanEntry::change(
+            Resource::gitlab_group_settings(&group.path, description, avatar),
+            Resource::gitlab_group_settings(&group.path, &expected_description, expected_avatar),
+            RULE_TEAM_GROUP_SETTINGS,
+        );
+        self.plan.record(&entry)?;
+        summary.change += 1;
+        if self.plan.applies(action, &entry) {
+            let endpoint = EditGroupSettings {
+                group: gitlab_group.id,
+                description: &expected_description,
+                avatar: self
+                    .team_avatar
+                    .as_ref()
+                    .filter(|_| avatar != expected_avatar),
+            };
+            let result = gitlab::api::ignore(endpoint)
+                .query_async(&self.client)
+                .await
+                .with_context(|| format!("Failed to edit settings of GitLab group {}", group.path));
+            if self.record_apply_result(&entry, result)? {
+                let actual = self.get_group_settings(&group.path).await;
+                self.plan.verify_applied(&entry, actual);
+            }
+        }
+        self.plan.finish_section(&summary)
+    }
+
+    /// Returns the name of a group directly below the teams group.
+    fn team_group_name<'a>(&self, path: &'a str) -> Option<&'a str> {
+        path.strip_prefix(&format!("{}/teams/", self.root_group))
+            .filter(|name| !name.contains('/'))
+    }
+
+    async fn get_group(&self, group: &str) -> Result<Option<Group>> {
+        let endpoint = gitlab::api::groups::Group::builder()
+            .group(group)
+            .build()
+            .unwrap();
+        let result: Result<Group, _> = endpoint.query_async(&self.client).await;
+        match result {
+            Ok(group) => Ok(Some(group)),
+            Err(err) => {
+                let err = anyhow::Error::new(err);
+                match error::classify(&err) {
+                    ErrorClass::NotFound => Ok(None),
+                    _ => Err(err.context(format!("Failed to get GitLab group {}", group))),
+                }
+            }
+        }
+    }
+
     async fn update_infrastructure_project_members(&self, action: &Action) -> Result<()> {
-        let project = "archlinux/infrastructure";
+        let project = &format!("{}/infrastructure", self.root_group);
         let project_members = self.get_project_members(project).await?;
 
-        let mut summary = PlanSummary::new("GitLab 'Arch Linux/Infrastructure' project members");
+        let label = format!("GitLab '{}' project members", project);
+        let mut summary = PlanSummary::new(&label);
 
         for member in &project_members {
-            if self.remove_project_member(action, member, project).await? {
+            if self
+                .remove_project_member(action, member, project, RULE_INFRASTRUCTURE_VIA_GROUPS)
+                .await?
+            {
                 summary.destroy += 1;
             }
         }
 
-        println!("{}", summary);
-        println!("{}", util::format_separator());
+        self.plan.finish_section(&summary)?;
 
         Ok(())
     }
 
     async fn get_group_members(&self, group: &str) -> Result<Vec<GitLabMember>> {
+        if let Some(access_levels) = &self.snapshot_access_levels {
+            return self.get_snapshot_group_members(access_levels, group).await;
+        }
         let members_endpoint = gitlab::api::groups::members::GroupMembers::builder()
             .group(group)
             .build()
@@ -453,9 +1528,54 @@ impl GitLabGlue {
             gitlab::api::paged(members_endpoint, gitlab::api::Pagination::All)
                 .query_async(&self.client)
                 .await?;
+        self.member_access_levels.lock().await.insert(
+            group.to_string(),
+            gitlab_group_members
+                .iter()
+                .map(|member| (member.username.clone(), member.access_level))
+                .collect(),
+        );
         Ok(gitlab_group_members)
     }
 
+    /// Returns the group members of the snapshot, which only has usernames,
+    /// so members are identified by the GitLab ids of the known users.
+    async fn get_snapshot_group_members(
+        &self,
+        access_levels: &BTreeMap<String, BTreeMap<String, String>>,
+        group: &str,
+    ) -> Result<Vec<GitLabMember>> {
+        let members = access_levels
+            .get(group)
+            .with_context(|| format!("The snapshot has no members of GitLab group {}", group))?;
+        let state = self.snapshot_state().await;
+        members
+            .iter()
+            .map(|(username, access_level)| {
+                let access_level =
+                    util::access_level_from_str(access_level).with_context(|| {
+                        format!("Invalid access level {} in the snapshot", access_level)
+                    })?;
+                let id = state
+                    .users
+                    .values()
+                    .find(|user| {
+                        user.gitlab_username.as_deref() == Some(username.as_str())
+                            || user.is_known_as(username)
+                    })
+                    .and_then(|user| user.gitlab_id)
+                    .unwrap_or_default();
+                Ok(GitLabMember {
+                    id,
+                    username: username.clone(),
+                    name: username.clone(),
+                    email: None,
+                    access_level: access_level.as_u64(),
+                })
+            })
+            .collect()
+    }
+
     async fn get_project_members(&self, project: &str) -> Result<Vec<GitLabMember>> {
         let endpoint = gitlab::api::projects::members::ProjectMembers::builder()
             .project(project)
@@ -468,6 +1588,11 @@ impl GitLabGlue {
     }
 
     async fn get_group_subgroups(&self, group: &str) -> Result<Vec<Group>> {
+        if let Some(subgroups) = self.subgroups.lock().await.get(group) {
+            trace!("Using cached subgroups of {}", group);
+            metrics::count_cache_hit();
+            return Ok(subgroups.clone());
+        }
         let endpoint = gitlab::api::groups::subgroups::GroupSubgroups::builder()
             .group(group)
             .order_by(GroupSubgroupsOrderBy::Path)
@@ -476,28 +1601,183 @@ impl GitLabGlue {
         let subgroups: Vec<Group> = gitlab::api::paged(endpoint, gitlab::api::Pagination::All)
             .query_async(&self.client)
             .await?;
+        self.subgroups
+            .lock()
+            .await
+            .insert(group.to_string(), subgroups.clone());
         Ok(subgroups)
     }
 
-    async fn get_group_projects(&self, group: &str) -> Result<Vec<GroupProjects>> {
-        let endpoint = gitlab::api::groups::projects::GroupProjects::builder()
+    /// Records the outcome of applying a planned change and returns whether
+    /// it was applied. A failed write is logged and recorded, so that the
+    /// remaining plan is still applied.
+    fn record_apply_result(&self, entry: &PlanEntry, result: Result<()>) -> Result<bool> {
+        match result {
+            Ok(()) => {
+                self.plan.record_applied(entry)?;
+                Ok(true)
+            }
+            Err(err) => {
+                error!("{:?}", err);
+                self.plan.record_failed(entry, &err);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Queries a single resource, which is None if it does not exist.
+    async fn query_optional<E, T>(&self, endpoint: E) -> Result<Option<T>>
+    where
+        E: Endpoint + Sync,
+        T: DeserializeOwned + 'static,
+    {
+        let result: Result<T, _> = endpoint.query_async(&self.client).await;
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(err) => {
+                let err = anyhow::Error::new(err);
+                match error::classify(&err) {
+                    ErrorClass::NotFound => Ok(None),
+                    _ => Err(err),
+                }
+            }
+        }
+    }
+
+    async fn get_group_member_access(
+        &self,
+        group: &str,
+        username: &str,
+        user: u64,
+    ) -> Result<Option<Resource>> {
+        let endpoint = gitlab::api::groups::members::GroupMember::builder()
             .group(group)
-            .order_by(GroupProjectsOrderBy::Path)
+            .user(user)
             .build()
             .unwrap();
-        let projects: Vec<GroupProjects> =
-            gitlab::api::paged(endpoint, gitlab::api::Pagination::All)
-                .query_async(&self.client)
-                .await?;
-        Ok(projects)
+        let member: Option<GitLabMember> =
+            self.query_optional(endpoint).await.with_context(|| {
+                format!(
+                    "Failed to get member {} of GitLab group {}",
+                    username, group
+                )
+            })?;
+        Ok(member.map(|member| {
+            Resource::gitlab_member_access(
+                group,
+                username,
+                util::access_level_from_u64(member.access_level),
+            )
+        }))
+    }
+
+    async fn get_project_member_access(
+        &self,
+        project: &str,
+        username: &str,
+        user: u64,
+    ) -> Result<Option<Resource>> {
+        let endpoint = gitlab::api::projects::members::ProjectMember::builder()
+            .project(project)
+            .user(user)
+            .build()
+            .unwrap();
+        let member: Option<GitLabMember> =
+            self.query_optional(endpoint).await.with_context(|| {
+                format!(
+                    "Failed to get member {} of GitLab project {}",
+                    username, project
+                )
+            })?;
+        Ok(member.map(|member| {
+            Resource::gitlab_member_access(
+                project,
+                username,
+                util::access_level_from_u64(member.access_level),
+            )
+        }))
+    }
+
+    async fn get_registry_tag(
+        &self,
+        repository: &RegistryRepository,
+        tag: &str,
+    ) -> Result<Option<Resource>> {
+        let endpoint = RegistryRepositoryTag {
+            project: repository.project_id,
+            repository: repository.id,
+            tag,
+        };
+        let details: Option<RegistryTagDetails> = self
+            .query_optional(endpoint)
+            .await
+            .with_context(|| format!("Failed to get tag {} of {}", tag, repository.path))?;
+        Ok(details.map(|details| {
+            Resource::gitlab_registry_tag(&repository.path, &details.name, &details.created_at)
+        }))
+    }
+
+    async fn get_user_state(&self, user: u64) -> Result<Option<Resource>> {
+        let endpoint = gitlab::api::users::User::builder()
+            .user(user)
+            .build()
+            .unwrap();
+        let user: Option<IssueAuthor> = self
+            .query_optional(endpoint)
+            .await
+            .with_context(|| format!("Failed to get GitLab user {}", user))?;
+        Ok(user.map(|user| Resource::gitlab_user_state(&user.username, &user.state)))
+    }
+
+    async fn get_issue_state(&self, issue: &Issue) -> Result<Option<Resource>> {
+        let endpoint = gitlab::api::projects::issues::Issue::builder()
+            .project(issue.project_id)
+            .issue(issue.iid)
+            .build()
+            .unwrap();
+        let actual: Option<Issue> = self
+            .query_optional(endpoint)
+            .await
+            .with_context(|| format!("Failed to get {}", issue.references.full))?;
+        Ok(actual.map(|actual| {
+            Resource::gitlab_issue_state(&actual.references.full, &actual.title, &actual.state)
+        }))
+    }
+
+    async fn get_project_settings(&self, project: u64) -> Result<Option<Resource>> {
+        let endpoint = gitlab::api::projects::Project::builder()
+            .project(project)
+            .build()
+            .unwrap();
+        let project: GroupProjects = endpoint
+            .query_async(&self.client)
+            .await
+            .with_context(|| format!("Failed to get GitLab project {}", project))?;
+        Ok(Some(Resource::gitlab_project_settings(
+            &project.path_with_namespace,
+            &ProjectSettings::of(&project),
+        )))
     }
 
+    async fn get_group_settings(&self, group: &str) -> Result<Option<Resource>> {
+        Ok(self.get_group(group).await?.map(|gitlab_group| {
+            let avatar = gitlab_group
+                .avatar_url
+                .as_deref()
+                .and_then(|url| url.rsplit('/').next())
+                .unwrap_or_default();
+            Resource::gitlab_group_settings(group, &gitlab_group.description, avatar)
+        }))
+    }
+
+    #[instrument(skip_all, fields(user = %user.username))]
     async fn add_group_member(
         &self,
         action: &Action,
         user: &User,
         group: &str,
         access_level: AccessLevel,
+        rule: &str,
     ) -> Result<bool> {
         let staff_username = &user.username;
         if user.gitlab_id.is_none() {
@@ -512,63 +1792,86 @@ impl GitLabGlue {
             .with_context(|| format!("Failed to unwrap GitLab user for {}", staff_username))?;
 
         debug!("Adding user {} to GitLab group '{}'", user.username, group);
-        util::print_diff(
-            "",
-            util::format_gitlab_member_access(group, &user.username, access_level).as_str(),
-        )?;
-        if let Action::Apply = action {
+        let entry = PlanEntry::add(
+            Resource::gitlab_member_access(group, &user.username, access_level),
+            rule,
+        );
+        self.plan.record(&entry)?;
+        if self.plan.applies(action, &entry) {
             let endpoint = gitlab::api::groups::members::AddGroupMember::builder()
                 .group(group)
                 .user(gitlab_id)
                 .access_level(access_level)
                 .build()
                 .unwrap();
-            gitlab::api::ignore(endpoint)
+            let result = gitlab::api::ignore(endpoint)
                 .query_async(&self.client)
                 .await
-                .unwrap();
+                .with_context(|| {
+                    format!("Failed to add {} to GitLab group {}", user.username, group)
+                });
+            if self.record_apply_result(&entry, result)? {
+                let actual = self
+                    .get_group_member_access(group, &user.username, gitlab_id)
+                    .await;
+                self.plan.verify_applied(&entry, actual);
+            }
         }
         Ok(true)
     }
 
-    async fn remove_group_member<'a>(
+    #[instrument(skip_all, fields(user = %member.username))]
+    async fn remove_group_member(
         &self,
         action: &Action,
-        _state: &MutexGuard<'a, State>,
         member: &GitLabMember,
         group: &str,
+        rule: &str,
     ) -> Result<bool> {
         debug!("User {} must not be in group {}", &member.username, group);
-        util::print_diff(
-            util::format_gitlab_member_access(
+        let entry = PlanEntry::destroy(
+            Resource::gitlab_member_access(
                 group,
                 &member.username,
                 util::access_level_from_u64(member.access_level),
-            )
-            .as_str(),
-            "",
-        )?;
-        if let Action::Apply = action {
+            ),
+            rule,
+        );
+        self.plan.record(&entry)?;
+        if self.plan.applies(action, &entry) {
             let endpoint = gitlab::api::groups::members::RemoveGroupMember::builder()
                 .group(group)
                 .user(member.id)
                 .build()
                 .unwrap();
-            gitlab::api::ignore(endpoint)
+            let result = gitlab::api::ignore(endpoint)
                 .query_async(&self.client)
                 .await
-                .unwrap();
+                .with_context(|| {
+                    format!(
+                        "Failed to remove {} from GitLab group {}",
+                        member.username, group
+                    )
+                });
+            if self.record_apply_result(&entry, result)? {
+                let actual = self
+                    .get_group_member_access(group, &member.username, member.id)
+                    .await;
+                self.plan.verify_applied(&entry, actual);
+            }
         }
         Ok(true)
     }
 
-    async fn edit_group_member_access_level<'a>(
+    #[instrument(skip_all, fields(user = %user.username))]
+    async fn edit_group_member_access_level(
         &self,
         action: &Action,
         user: &User,
         group_member: &GitLabMember,
         group: &str,
         expected_access_level: AccessLevel,
+        rule: &str,
     ) -> Result<bool> {
         let access_level = util::access_level_from_u64(group_member.access_level);
         if access_level.eq(&expected_access_level) {
@@ -588,27 +1891,40 @@ impl GitLabGlue {
             access_level.as_str(),
             group,
         );
-        util::print_diff(
-            util::format_gitlab_member_access(group, &user.username, access_level).as_str(),
-            util::format_gitlab_member_access(group, &user.username, expected_access_level)
-                .as_str(),
-        )?;
-        if let Action::Apply = action {
+        let entry = PlanEntry::change(
+            Resource::gitlab_member_access(group, &user.username, access_level),
+            Resource::gitlab_member_access(group, &user.username, expected_access_level),
+            rule,
+        );
+        self.plan.record(&entry)?;
+        if self.plan.applies(action, &entry) {
             let endpoint = gitlab::api::groups::members::EditGroupMember::builder()
                 .group(group)
                 .user(group_member.id)
                 .access_level(expected_access_level)
                 .build()
                 .unwrap();
-            gitlab::api::ignore(endpoint)
+            let result = gitlab::api::ignore(endpoint)
                 .query_async(&self.client)
                 .await
-                .unwrap();
+                .with_context(|| {
+                    format!(
+                        "Failed to edit access level of {} in GitLab group {}",
+                        user.username, group
+                    )
+                });
+            if self.record_apply_result(&entry, result)? {
+                let actual = self
+                    .get_group_member_access(group, &user.username, group_member.id)
+                    .await;
+                self.plan.verify_applied(&entry, actual);
+            }
         }
         Ok(true)
     }
 
-    async fn edit_group_member_max_access_level<'a>(
+    #[instrument(skip_all, fields(user = %user.username))]
+    async fn edit_group_member_max_access_level(
         &self,
         action: &Action,
         user: &User,
@@ -628,16 +1944,26 @@ impl GitLabGlue {
             return Ok(false);
         }
 
-        self.edit_group_member_access_level(action, user, group_member, group, max_access_level)
-            .await
+        let rule = format!("max access level {}", max_access_level.as_str());
+        self.edit_group_member_access_level(
+            action,
+            user,
+            group_member,
+            group,
+            max_access_level,
+            &rule,
+        )
+        .await
     }
 
+    #[instrument(skip_all, fields(user = %user.username))]
     async fn add_project_member(
         &self,
         action: &Action,
         user: &User,
         project: &str,
         access_level: AccessLevel,
+        rule: &str,
     ) -> Result<bool> {
         let staff_username = &user.username;
         if user.gitlab_id.is_none() {
@@ -655,58 +1981,84 @@ impl GitLabGlue {
             "Adding user {} to GitLab project '{}'",
             user.username, project
         );
-        util::print_diff(
-            "",
-            util::format_gitlab_member_access(project, &user.username, access_level).as_str(),
-        )?;
-        if let Action::Apply = action {
+        let entry = PlanEntry::add(
+            Resource::gitlab_member_access(project, &user.username, access_level),
+            rule,
+        );
+        self.plan.record(&entry)?;
+        if self.plan.applies(action, &entry) {
             let endpoint = gitlab::api::projects::members::AddProjectMember::builder()
                 .project(project)
                 .user(gitlab_id)
                 .access_level(access_level)
                 .build()
                 .unwrap();
-            gitlab::api::ignore(endpoint)
+            let result = gitlab::api::ignore(endpoint)
                 .query_async(&self.client)
                 .await
-                .unwrap();
+                .with_context(|| {
+                    format!(
+                        "Failed to add {} to GitLab project {}",
+                        user.username, project
+                    )
+                });
+            if self.record_apply_result(&entry, result)? {
+                let actual = self
+                    .get_project_member_access(project, &user.username, gitlab_id)
+                    .await;
+                self.plan.verify_applied(&entry, actual);
+            }
         }
         Ok(true)
     }
 
-    async fn remove_project_member<'a>(
+    #[instrument(skip_all, fields(user = %member.username))]
+    async fn remove_project_member(
         &self,
         action: &Action,
         member: &GitLabMember,
         project: &str,
+        rule: &str,
     ) -> Result<bool> {
         debug!(
             "User {} must not be in project {}",
             &member.username, project
         );
-        util::print_diff(
-            util::format_gitlab_member_access(
+        let entry = PlanEntry::destroy(
+            Resource::gitlab_member_access(
                 project,
                 &member.username,
                 util::access_level_from_u64(member.access_level),
-            )
-            .as_str(),
-            "",
-        )?;
-        if let Action::Apply = action {
+            ),
+            rule,
+        );
+        self.plan.record(&entry)?;
+        if self.plan.applies(action, &entry) {
             let endpoint = gitlab::api::projects::members::RemoveProjectMember::builder()
                 .project(project)
                 .user(member.id)
                 .build()
                 .unwrap();
-            gitlab::api::ignore(endpoint)
+            let result = gitlab::api::ignore(endpoint)
                 .query_async(&self.client)
                 .await
-                .unwrap();
+                .with_context(|| {
+                    format!(
+                        "Failed to remove {} from GitLab project {}",
+                        member.username, project
+                    )
+                });
+            if self.record_apply_result(&entry, result)? {
+                let actual = self
+                    .get_project_member_access(project, &member.username, member.id)
+                    .await;
+                self.plan.verify_applied(&entry, actual);
+            }
         }
         Ok(true)
     }
 
+    #[instrument(skip_all, fields(user = %user.username))]
     async fn edit_project_member_access_level(
         &self,
         action: &Action,
@@ -714,6 +2066,7 @@ impl GitLabGlue {
         member: &GitLabMember,
         project: &str,
         access_level: AccessLevel,
+        rule: &str,
     ) -> Result<bool> {
         let staff_username = &user.username;
         if user.gitlab_id.is_none() {
@@ -731,29 +2084,43 @@ impl GitLabGlue {
             "Adding user {} to GitLab project '{}'",
             user.username, project
         );
-        util::print_diff(
-            util::format_gitlab_member_access(
+        let entry = PlanEntry::change(
+            Resource::gitlab_member_access(
                 project,
                 &user.username,
                 util::access_level_from_u64(member.access_level),
-            )
-            .as_str(),
-            util::format_gitlab_member_access(project, &user.username, access_level).as_str(),
-        )?;
-        if let Action::Apply = action {
+            ),
+            Resource::gitlab_member_access(project, &user.username, access_level),
+            rule,
+        );
+        self.plan.record(&entry)?;
+        if self.plan.applies(action, &entry) {
             let endpoint = gitlab::api::projects::members::EditProjectMember::builder()
                 .project(project)
                 .user(gitlab_id)
                 .access_level(access_level)
                 .build()
                 .unwrap();
-            gitlab::api::ignore(endpoint)
+            let result = gitlab::api::ignore(endpoint)
                 .query_async(&self.client)
-                .await?;
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to edit access level of {} in GitLab project {}",
+                        user.username, project
+                    )
+                });
+            if self.record_apply_result(&entry, result)? {
+                let actual = self
+                    .get_project_member_access(project, &user.username, gitlab_id)
+                    .await;
+                self.plan.verify_applied(&entry, actual);
+            }
         }
         Ok(true)
     }
 
+    #[instrument(skip_all, fields(user = %user.username))]
     async fn edit_project_member_max_access_level(
         &self,
         action: &Action,
@@ -774,8 +2141,16 @@ impl GitLabGlue {
             return Ok(false);
         }
 
-        self.edit_project_member_access_level(action, user, member, project, max_access_level)
-            .await
+        let rule = format!("max access level {}", max_access_level.as_str());
+        self.edit_project_member_access_level(
+            action,
+            user,
+            member,
+            project,
+            max_access_level,
+            &rule,
+        )
+        .await
     }
 
     async fn apply_project_settings(
@@ -783,45 +2158,96 @@ impl GitLabGlue {
         action: &Action,
         project: &GroupProjects,
     ) -> Result<bool> {
-        let expected_request_access_enabled = false;
-        let expected_snippets_access_level = ProjectFeatureAccessLevel::Disabled;
-
-        if project.request_access_enabled == expected_request_access_enabled
-            && project.snippets_access_level == expected_snippets_access_level
-        {
+        let settings = ProjectSettings::of(project);
+        let expected = self
+            .project_policy
+            .expected_settings(&project.path_with_namespace, &settings);
+        if settings == expected {
             return Ok(false);
         }
 
         debug!("edit project settings for {}", project.name_with_namespace);
-        util::print_diff(
-            util::format_gitlab_project_settings(
-                &project.path_with_namespace,
-                project.request_access_enabled,
-                project.snippets_access_level,
-            )
-            .as_str(),
-            util::format_gitlab_project_settings(
-                &project.path_with_namespace,
-                expected_request_access_enabled,
-                expected_snippets_access_level,
-            )
-            .as_str(),
-        )?;
-        if let Action::Apply = action {
+        let entry = PlanEntry::change(
+            Resource::gitlab_project_settings(&project.path_with_namespace, &settings),
+            Resource::gitlab_project_settings(&project.path_with_namespace, &expected),
+            RULE_PROJECT_SETTINGS_POLICY,
+        );
+        self.plan.record(&entry)?;
+        if self.plan.applies(action, &entry) {
             let endpoint = gitlab::api::projects::EditProject::builder()
                 .project(project.id)
-                .request_access_enabled(expected_request_access_enabled)
-                .snippets_access_level(expected_snippets_access_level.as_gitlab_type())
+                .request_access_enabled(expected.request_access_enabled)
+                .issues_access_level(expected.issues_access_level.as_gitlab_type())
+                .merge_requests_access_level(expected.merge_requests_access_level.as_gitlab_type())
+                .wiki_access_level(expected.wiki_access_level.as_gitlab_type())
+                .builds_access_level(expected.builds_access_level.as_gitlab_type())
+                .snippets_access_level(expected.snippets_access_level.as_gitlab_type())
+                .merge_method(expected.merge_method.as_gitlab_type())
                 .build()
                 .unwrap();
-            gitlab::api::ignore(endpoint)
+            let result = gitlab::api::ignore(endpoint)
                 .query_async(&self.client)
-                .await?;
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to edit settings of GitLab project {}",
+                        project.path_with_namespace
+                    )
+                });
+            if self.record_apply_result(&entry, result)? {
+                let actual = self.get_project_settings(project.id).await;
+                self.plan.verify_applied(&entry, actual);
+            }
         }
         Ok(true)
     }
 }
 
+#[async_trait(?Send)]
+impl Component for GitLabGlue {
+    async fn gather(&self, cache: Option<&Cache>) -> Result<()> {
+        self.plan.start_component("GitLab");
+        self.plan.gather_started()?;
+        self.gather_state(cache).await?;
+        self.plan.gather_finished()
+    }
+
+    async fn run(&self, action: Action) -> Result<()> {
+        self.plan.start_component("GitLab");
+        if self.enforces(Enforcement::Sweep) {
+            self.update_archlinux_group_recursively(&action).await?;
+        }
+        if self.enforces(Enforcement::Members) {
+            let teams = self.state.lock().await.teams.clone();
+            for team in &teams {
+                debug!("Reconciling GitLab groups of team {}", team.name);
+                for group in &team.gitlab_groups {
+                    self.update_team_group_members(&action, team, group).await?;
+                }
+            }
+            if !self.client.is_offline() {
+                self.update_infrastructure_project_members(&action).await?;
+            }
+        }
+        if let (Some(keep), true) = (
+            self.registry_keep_tags,
+            self.enforces(Enforcement::Registry),
+        ) {
+            self.cleanup_container_registries(&action, keep).await?;
+        }
+        if let (Some(label), true) = (&self.spam_label, self.enforces(Enforcement::Spam)) {
+            self.mitigate_spam(&action, label).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the path is the group itself or lies below it.
+fn is_within(path: &str, group: &str) -> bool {
+    path.strip_prefix(group)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
 fn is_archlinux_bot(member: &GitLabMember) -> bool {
     if member.username.eq(GITLAB_OWNER) {
         return true;
@@ -840,6 +2266,30 @@ fn is_archlinux_bot(member: &GitLabMember) -> bool {
     false
 }
 
+/// Standard description of the team groups, derived from the Keycloak group.
+fn team_group_description(team: &Team) -> String {
+    format!(
+        "Members of the {} Keycloak group, managed by gluebuddy",
+        team.keycloak_group.trim_matches('/')
+    )
+}
+
+pub fn gitlab_host() -> String {
+    env::var("GLUEBUDDY_GITLAB_HOST").unwrap_or_else(|_| DEFAULT_HOST.to_string())
+}
+
+/// Identity providers used to look up GitLab users, in order of priority.
+fn identity_providers() -> Vec<String> {
+    match env::var("GLUEBUDDY_GITLAB_IDENTITY_PROVIDERS") {
+        Ok(providers) => providers
+            .split(',')
+            .map(|provider| provider.trim().to_string())
+            .filter(|provider| !provider.is_empty())
+            .collect(),
+        Err(_) => vec![DEFAULT_IDENTITY_PROVIDER.to_string()],
+    }
+}
+
 fn get_protected_branch(
     client: &Gitlab,
     project: &GroupProjects,
@@ -925,6 +2375,50 @@ mod tests {
 
     const SOME_KNOWN_BOTS: &str = "project_10185_bot2,project_19591_bot,project_19796_bot,renovate";
 
+    #[rstest]
+    #[case(
+        "/Arch Linux Staff/",
+        "Members of the Arch Linux Staff Keycloak group, managed by gluebuddy"
+    )]
+    #[case(
+        "/Arch Linux Staff/DevOps",
+        "Members of the Arch Linux Staff/DevOps Keycloak group, managed by gluebuddy"
+    )]
+    fn team_group_description_test(#[case] keycloak_group: &str, #[case] expected: &str) {
+        assert_eq!(
+            team_group_description(&Team::new("Team", keycloak_group)),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case("archlinux", "archlinux", true)]
+    #[case("archlinux/packaging/packages", "archlinux", true)]
+    #[case(
+        "archlinux/packaging/packages/linux",
+        "archlinux/packaging/packages",
+        true
+    )]
+    #[case("archlinux/packaging-tools", "archlinux/packaging", false)]
+    #[case("archlinux", "archlinux/packaging", false)]
+    #[case("archlinux-mirror/linux", "archlinux", false)]
+    fn is_within_test(#[case] path: &str, #[case] group: &str, #[case] expected: bool) {
+        assert_eq!(is_within(path, group), expected);
+    }
+
+    #[rstest]
+    #[case("archlinux/infrastructure#42", Some(("archlinux/infrastructure", 42)))]
+    #[case("archlinux/infrastructure", None)]
+    #[case("#42", None)]
+    #[case("archlinux/infrastructure#latest", None)]
+    fn issue_reference_test(#[case] reference: &str, #[case] expected: Option<(&str, u64)>) {
+        let expected = expected.map(|(project, iid)| IssueReference {
+            project: project.to_string(),
+            iid,
+        });
+        assert_eq!(reference.parse::<IssueReference>().ok(), expected);
+    }
+
     #[rstest]
     #[case(None, GITLAB_OWNER, true)]
     #[case(None, GITLAB_BOT, true)]
@@ -955,4 +2449,18 @@ mod tests {
         };
         assert_eq!(is_archlinux_bot(&member), expected);
     }
+
+    #[rstest]
+    #[case(None, vec![DEFAULT_IDENTITY_PROVIDER])]
+    #[case(Some("openid_connect"), vec!["openid_connect"])]
+    #[case(Some("openid_connect, saml"), vec!["openid_connect", "saml"])]
+    #[case(Some("ldapmain,,saml"), vec!["ldapmain", "saml"])]
+    #[serial]
+    fn identity_providers_test(#[case] providers_env: Option<&str>, #[case] expected: Vec<&str>) {
+        match providers_env {
+            None => env::remove_var("GLUEBUDDY_GITLAB_IDENTITY_PROVIDERS"),
+            Some(x) => env::set_var("GLUEBUDDY_GITLAB_IDENTITY_PROVIDERS", x),
+        }
+        assert_eq!(identity_providers(), expected);
+    }
 }
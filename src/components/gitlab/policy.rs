@@ -0,0 +1,326 @@
+//! The policy of expected project settings and member access levels, which
+//! can be overridden per namespace with a TOML file.
+
+use crate::components::gitlab::types::{
+    GroupProjects, ProjectFeatureAccessLevel, ProjectMergeMethod,
+};
+use crate::error::ConfigError;
+use crate::util;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use gitlab::api::common::AccessLevel;
+use serde::{Deserialize, Deserializer};
+
+const POLICY_VAR: &str = "GLUEBUDDY_GITLAB_POLICY";
+
+/// The enforced settings of a project.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProjectSettings {
+    pub request_access_enabled: bool,
+    pub issues_access_level: ProjectFeatureAccessLevel,
+    pub merge_requests_access_level: ProjectFeatureAccessLevel,
+    pub wiki_access_level: ProjectFeatureAccessLevel,
+    pub builds_access_level: ProjectFeatureAccessLevel,
+    pub snippets_access_level: ProjectFeatureAccessLevel,
+    pub merge_method: ProjectMergeMethod,
+}
+
+impl ProjectSettings {
+    pub fn of(project: &GroupProjects) -> ProjectSettings {
+        ProjectSettings {
+            request_access_enabled: project.request_access_enabled,
+            issues_access_level: project.issues_access_level,
+            merge_requests_access_level: project.merge_requests_access_level,
+            wiki_access_level: project.wiki_access_level,
+            builds_access_level: project.builds_access_level,
+            snippets_access_level: project.snippets_access_level,
+            merge_method: project.merge_method,
+        }
+    }
+}
+
+/// Expected project settings and the highest access level of members, unset
+/// ones are not enforced.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectSettingsRule {
+    pub request_access_enabled: Option<bool>,
+    pub issues_access_level: Option<ProjectFeatureAccessLevel>,
+    pub merge_requests_access_level: Option<ProjectFeatureAccessLevel>,
+    pub wiki_access_level: Option<ProjectFeatureAccessLevel>,
+    pub builds_access_level: Option<ProjectFeatureAccessLevel>,
+    pub snippets_access_level: Option<ProjectFeatureAccessLevel>,
+    pub merge_method: Option<ProjectMergeMethod>,
+    #[serde(default, deserialize_with = "deserialize_access_level")]
+    pub max_access_level: Option<AccessLevel>,
+}
+
+fn deserialize_access_level<'de, D>(deserializer: D) -> Result<Option<AccessLevel>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let access_level = String::deserialize(deserializer)?;
+    util::access_level_from_str(&access_level)
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom(format!("unknown access level {}", access_level)))
+}
+
+impl ProjectSettingsRule {
+    /// The settings enforced without a policy file.
+    fn builtin() -> ProjectSettingsRule {
+        ProjectSettingsRule {
+            request_access_enabled: Some(false),
+            snippets_access_level: Some(ProjectFeatureAccessLevel::Disabled),
+            ..ProjectSettingsRule::default()
+        }
+    }
+
+    /// Returns this rule with the settings the other rule sets replaced.
+    fn overridden_by(self, other: &ProjectSettingsRule) -> ProjectSettingsRule {
+        ProjectSettingsRule {
+            request_access_enabled: other.request_access_enabled.or(self.request_access_enabled),
+            issues_access_level: other.issues_access_level.or(self.issues_access_level),
+            merge_requests_access_level: other
+                .merge_requests_access_level
+                .or(self.merge_requests_access_level),
+            wiki_access_level: other.wiki_access_level.or(self.wiki_access_level),
+            builds_access_level: other.builds_access_level.or(self.builds_access_level),
+            snippets_access_level: other.snippets_access_level.or(self.snippets_access_level),
+            merge_method: other.merge_method.or(self.merge_method),
+            max_access_level: other.max_access_level.or(self.max_access_level),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectPolicy {
+    /// Overrides of the builtin settings for all projects
+    #[serde(default)]
+    defaults: ProjectSettingsRule,
+    /// Overrides by namespace, applying to all projects below it
+    #[serde(default)]
+    namespaces: BTreeMap<String, ProjectSettingsRule>,
+}
+
+impl ProjectPolicy {
+    /// Loads the policy file named by `GLUEBUDDY_GITLAB_POLICY`, if set.
+    pub fn from_env() -> Result<ProjectPolicy> {
+        match std::env::var(POLICY_VAR) {
+            Ok(path) => ProjectPolicy::load(Path::new(&path)),
+            Err(_) => Ok(ProjectPolicy::default()),
+        }
+    }
+
+    fn load(path: &Path) -> Result<ProjectPolicy> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read policy file {}", path.display()))?;
+        ProjectPolicy::parse(&content).map_err(|reason| {
+            ConfigError::Invalid {
+                name: POLICY_VAR.to_string(),
+                reason,
+            }
+            .into()
+        })
+    }
+
+    fn parse(content: &str) -> Result<ProjectPolicy, String> {
+        toml::from_str(content).map_err(|err| err.to_string())
+    }
+
+    /// Returns the rule of the path, where the overrides of more specific
+    /// namespaces take precedence. A namespace only applies to itself if
+    /// `inclusive` is set, projects are always below their namespaces.
+    fn rule(&self, path: &str, inclusive: bool) -> ProjectSettingsRule {
+        let mut namespaces: Vec<(&String, &ProjectSettingsRule)> = self
+            .namespaces
+            .iter()
+            .filter(|(namespace, _)| {
+                path.strip_prefix(namespace.as_str())
+                    .is_some_and(|rest| rest.starts_with('/') || (inclusive && rest.is_empty()))
+            })
+            .collect();
+        namespaces.sort_by_key(|(namespace, _)| namespace.len());
+        namespaces.into_iter().fold(
+            ProjectSettingsRule::builtin().overridden_by(&self.defaults),
+            |rule, (_, namespace_rule)| rule.overridden_by(namespace_rule),
+        )
+    }
+
+    /// Returns the settings the project is expected to have, settings none
+    /// of the rules set keep their current value.
+    pub fn expected_settings(&self, path: &str, current: &ProjectSettings) -> ProjectSettings {
+        let rule = self.rule(path, false);
+        ProjectSettings {
+            request_access_enabled: rule
+                .request_access_enabled
+                .unwrap_or(current.request_access_enabled),
+            issues_access_level: rule
+                .issues_access_level
+                .unwrap_or(current.issues_access_level),
+            merge_requests_access_level: rule
+                .merge_requests_access_level
+                .unwrap_or(current.merge_requests_access_level),
+            wiki_access_level: rule.wiki_access_level.unwrap_or(current.wiki_access_level),
+            builds_access_level: rule
+                .builds_access_level
+                .unwrap_or(current.builds_access_level),
+            snippets_access_level: rule
+                .snippets_access_level
+                .unwrap_or(current.snippets_access_level),
+            merge_method: rule.merge_method.unwrap_or(current.merge_method),
+        }
+    }
+
+    /// Returns the highest access level members of the group or project may
+    /// have, if the policy limits it.
+    pub fn max_access_level(&self, path: &str) -> Option<AccessLevel> {
+        self.rule(path, true).max_access_level
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    const POLICY: &str = r#"
+[defaults]
+merge_method = "merge"
+
+[namespaces."archlinux/packaging"]
+merge_method = "rebase_merge"
+
+[namespaces."archlinux/packaging/packages"]
+merge_method = "ff"
+snippets_access_level = "private"
+
+[namespaces."archlinux/infrastructure"]
+request_access_enabled = true
+"#;
+
+    fn settings(
+        request_access_enabled: bool,
+        snippets_access_level: ProjectFeatureAccessLevel,
+        merge_method: ProjectMergeMethod,
+    ) -> ProjectSettings {
+        ProjectSettings {
+            request_access_enabled,
+            issues_access_level: ProjectFeatureAccessLevel::Enabled,
+            merge_requests_access_level: ProjectFeatureAccessLevel::Enabled,
+            wiki_access_level: ProjectFeatureAccessLevel::Enabled,
+            builds_access_level: ProjectFeatureAccessLevel::Enabled,
+            snippets_access_level,
+            merge_method,
+        }
+    }
+
+    #[rstest]
+    #[case(
+        "",
+        "archlinux/packaging/packages/linux",
+        settings(
+            false,
+            ProjectFeatureAccessLevel::Disabled,
+            ProjectMergeMethod::FastForward
+        )
+    )]
+    #[case(
+        POLICY,
+        "archlinux/packaging/packages/linux",
+        settings(
+            false,
+            ProjectFeatureAccessLevel::Private,
+            ProjectMergeMethod::FastForward
+        )
+    )]
+    #[case(
+        POLICY,
+        "archlinux/packaging/keyring",
+        settings(
+            false,
+            ProjectFeatureAccessLevel::Disabled,
+            ProjectMergeMethod::RebaseMerge
+        )
+    )]
+    #[case(
+        POLICY,
+        "archlinux/infrastructure",
+        settings(false, ProjectFeatureAccessLevel::Disabled, ProjectMergeMethod::Merge)
+    )]
+    #[case(
+        POLICY,
+        "archlinux/infrastructure/ansible",
+        settings(true, ProjectFeatureAccessLevel::Disabled, ProjectMergeMethod::Merge)
+    )]
+    #[case(
+        POLICY,
+        "archlinux/packaging-tools",
+        settings(false, ProjectFeatureAccessLevel::Disabled, ProjectMergeMethod::Merge)
+    )]
+    fn expected_settings_test(
+        #[case] policy: &str,
+        #[case] path: &str,
+        #[case] expected: ProjectSettings,
+    ) {
+        let current = settings(
+            true,
+            ProjectFeatureAccessLevel::Enabled,
+            ProjectMergeMethod::FastForward,
+        );
+        let policy = ProjectPolicy::parse(policy).unwrap();
+        assert_eq!(policy.expected_settings(path, &current), expected);
+    }
+
+    #[test]
+    fn expected_feature_access_levels_test() {
+        let policy = ProjectPolicy::parse(
+            "[namespaces.\"archlinux/packaging/packages\"]\nwiki_access_level = \"disabled\"\nbuilds_access_level = \"private\"\n",
+        )
+        .unwrap();
+        let current = settings(
+            false,
+            ProjectFeatureAccessLevel::Disabled,
+            ProjectMergeMethod::Merge,
+        );
+        let expected = policy.expected_settings("archlinux/packaging/packages/linux", &current);
+        assert_eq!(
+            expected.wiki_access_level,
+            ProjectFeatureAccessLevel::Disabled
+        );
+        assert_eq!(
+            expected.builds_access_level,
+            ProjectFeatureAccessLevel::Private
+        );
+        assert_eq!(
+            expected.issues_access_level,
+            ProjectFeatureAccessLevel::Enabled
+        );
+    }
+
+    #[rstest]
+    #[case("archlinux/packaging", Some(AccessLevel::Developer))]
+    #[case("archlinux/packaging/packages/linux", Some(AccessLevel::Reporter))]
+    #[case("archlinux/packaging/packages", Some(AccessLevel::Reporter))]
+    #[case("archlinux/infrastructure", None)]
+    fn max_access_level_test(#[case] path: &str, #[case] expected: Option<AccessLevel>) {
+        let policy = ProjectPolicy::parse(
+            "[namespaces.\"archlinux/packaging\"]\nmax_access_level = \"developer\"\n\n[namespaces.\"archlinux/packaging/packages\"]\nmax_access_level = \"reporter\"\n",
+        )
+        .unwrap();
+        assert_eq!(policy.max_access_level(path), expected);
+    }
+
+    #[rstest]
+    #[case("[defaults]\nmerge_method = \"squash\"\n")]
+    #[case("[defaults]\npages_access_level = \"disabled\"\n")]
+    #[case("[namespaces]\narchlinux = true\n")]
+    #[case("[defaults]\nmax_access_level = \"admin\"\n")]
+    fn parse_invalid_test(#[case] policy: &str) {
+        assert!(ProjectPolicy::parse(policy).is_err());
+    }
+}
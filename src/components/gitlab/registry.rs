@@ -0,0 +1,89 @@
+//! Container registry endpoints that are not provided by the gitlab crate.
+
+use gitlab::api::common::{self, NameOrId};
+use gitlab::api::endpoint_prelude::*;
+
+/// Lists the container repositories of all projects of a group.
+pub struct GroupRegistryRepositories<'a> {
+    pub group: NameOrId<'a>,
+}
+
+impl<'a> Endpoint for GroupRegistryRepositories<'a> {
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!("groups/{}/registry/repositories", self.group).into()
+    }
+}
+
+impl<'a> Pageable for GroupRegistryRepositories<'a> {}
+
+/// Lists the tags of a container repository.
+pub struct RegistryRepositoryTags {
+    pub project: u64,
+    pub repository: u64,
+}
+
+impl Endpoint for RegistryRepositoryTags {
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!(
+            "projects/{}/registry/repositories/{}/tags",
+            self.project, self.repository
+        )
+        .into()
+    }
+}
+
+impl Pageable for RegistryRepositoryTags {}
+
+/// Gets the details of a tag, including its creation date and size.
+pub struct RegistryRepositoryTag<'a> {
+    pub project: u64,
+    pub repository: u64,
+    pub tag: &'a str,
+}
+
+impl<'a> Endpoint for RegistryRepositoryTag<'a> {
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!(
+            "projects/{}/registry/repositories/{}/tags/{}",
+            self.project,
+            self.repository,
+            common::path_escaped(self.tag)
+        )
+        .into()
+    }
+}
+
+/// Deletes a tag of a container repository.
+pub struct DeleteRegistryRepositoryTag<'a> {
+    pub project: u64,
+    pub repository: u64,
+    pub tag: &'a str,
+}
+
+impl<'a> Endpoint for DeleteRegistryRepositoryTag<'a> {
+    fn method(&self) -> Method {
+        Method::DELETE
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!(
+            "projects/{}/registry/repositories/{}/tags/{}",
+            self.project,
+            self.repository,
+            common::path_escaped(self.tag)
+        )
+        .into()
+    }
+}
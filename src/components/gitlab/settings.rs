@@ -0,0 +1,89 @@
+//! Group settings endpoint that can upload an avatar, which the gitlab crate
+//! does not support.
+
+use gitlab::api::endpoint_prelude::*;
+
+const MULTIPART_BOUNDARY: &str = "gluebuddy-5c1b0d9e7f3a4c2b8e6d";
+const MULTIPART_CONTENT_TYPE: &str = "multipart/form-data; boundary=gluebuddy-5c1b0d9e7f3a4c2b8e6d";
+
+/// An image file uploaded as avatar.
+pub struct TeamAvatar {
+    pub file_name: String,
+    pub content: Vec<u8>,
+}
+
+/// Edits the description of a group and optionally uploads its avatar.
+pub struct EditGroupSettings<'a> {
+    pub group: u64,
+    pub description: &'a str,
+    pub avatar: Option<&'a TeamAvatar>,
+}
+
+impl<'a> Endpoint for EditGroupSettings<'a> {
+    fn method(&self) -> Method {
+        Method::PUT
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!("groups/{}", self.group).into()
+    }
+
+    fn body(&self) -> Result<Option<(&'static str, Vec<u8>)>, BodyError> {
+        let avatar = match self.avatar {
+            Some(avatar) => avatar,
+            None => {
+                let mut params = FormParams::default();
+                params.push("description", self.description);
+                return params.into_body();
+            }
+        };
+
+        let mut body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"description\"\r\n\r\n{description}\r\n\
+             --{boundary}\r\nContent-Disposition: form-data; name=\"avatar\"; filename=\"{file_name}\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n",
+            boundary = MULTIPART_BOUNDARY,
+            description = self.description,
+            file_name = avatar.file_name,
+        )
+        .into_bytes();
+        body.extend_from_slice(&avatar.content);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", MULTIPART_BOUNDARY).as_bytes());
+        Ok(Some((MULTIPART_CONTENT_TYPE, body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_group_settings_body_test() {
+        let avatar = TeamAvatar {
+            file_name: "archlinux.png".to_string(),
+            content: vec![0x89, b'P', b'N', b'G'],
+        };
+        let endpoint = EditGroupSettings {
+            group: 42,
+            description: "DevOps team",
+            avatar: Some(&avatar),
+        };
+        let (content_type, body) = endpoint.body().unwrap().unwrap();
+        assert_eq!(
+            content_type,
+            format!("multipart/form-data; boundary={}", MULTIPART_BOUNDARY)
+        );
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("name=\"description\"\r\n\r\nDevOps team\r\n"));
+        assert!(body.contains("filename=\"archlinux.png\""));
+        assert!(body.ends_with(&format!("PNG\r\n--{}--\r\n", MULTIPART_BOUNDARY)));
+
+        let endpoint = EditGroupSettings {
+            avatar: None,
+            ..endpoint
+        };
+        let (content_type, body) = endpoint.body().unwrap().unwrap();
+        assert_eq!(content_type, "application/x-www-form-urlencoded");
+        assert_eq!(body, b"description=DevOps+team");
+    }
+}
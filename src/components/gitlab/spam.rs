@@ -0,0 +1,70 @@
+//! Issue and user endpoints used to mitigate spam that are not provided by
+//! the gitlab crate.
+
+use gitlab::api::common::NameOrId;
+use gitlab::api::endpoint_prelude::*;
+
+/// Lists the open issues of a group, optionally with a label or by an author.
+pub struct GroupOpenIssues<'a> {
+    pub group: NameOrId<'a>,
+    pub label: Option<&'a str>,
+    pub author_id: Option<u64>,
+}
+
+impl<'a> Endpoint for GroupOpenIssues<'a> {
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!("groups/{}/issues", self.group).into()
+    }
+
+    fn parameters(&self) -> QueryParams<'_> {
+        let mut params = QueryParams::default();
+        params
+            .push("state", "opened")
+            .push_opt("labels", self.label)
+            .push_opt("author_id", self.author_id);
+        params
+    }
+}
+
+impl<'a> Pageable for GroupOpenIssues<'a> {}
+
+/// Closes an issue.
+pub struct CloseIssue {
+    pub project: u64,
+    pub issue: u64,
+}
+
+impl Endpoint for CloseIssue {
+    fn method(&self) -> Method {
+        Method::PUT
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!("projects/{}/issues/{}", self.project, self.issue).into()
+    }
+
+    fn body(&self) -> Result<Option<(&'static str, Vec<u8>)>, BodyError> {
+        let mut params = FormParams::default();
+        params.push("state_event", "close");
+        params.into_body()
+    }
+}
+
+/// Blocks a user, which requires an administrator.
+pub struct BlockUser {
+    pub user: u64,
+}
+
+impl Endpoint for BlockUser {
+    fn method(&self) -> Method {
+        Method::POST
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!("users/{}/block", self.user).into()
+    }
+}
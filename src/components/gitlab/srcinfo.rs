@@ -0,0 +1,134 @@
+//! Parsing of the `.SRCINFO` files of packaging projects and the repository
+//! file endpoint used to fetch them.
+
+use anyhow::{bail, Result};
+use gitlab::api::common::{self, NameOrId};
+use gitlab::api::endpoint_prelude::*;
+use serde::Deserialize;
+
+pub const SRCINFO_PATH: &str = ".SRCINFO";
+
+/// The fields of a `.SRCINFO` gluebuddy cares about.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Srcinfo {
+    pub pkgbase: String,
+    /// Description of the pkgbase, falls back to the one of the first package
+    pub pkgdesc: String,
+}
+
+impl Srcinfo {
+    pub fn parse(content: &str) -> Result<Srcinfo> {
+        let mut pkgbase = None;
+        let mut base_pkgdesc = None;
+        let mut package_pkgdesc = None;
+        let mut in_packages = false;
+
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.split_once(" = ") {
+                Some((key, value)) => (key, value),
+                None => match line.strip_suffix(" =") {
+                    Some(key) => (key, ""),
+                    None => bail!("line {} is no key value pair: {}", number + 1, line),
+                },
+            };
+            match key {
+                "pkgbase" if pkgbase.is_some() => {
+                    bail!("line {} repeats the pkgbase", number + 1)
+                }
+                "pkgbase" => pkgbase = Some(value.to_string()),
+                _ if pkgbase.is_none() => bail!("line {} precedes the pkgbase", number + 1),
+                "pkgname" => in_packages = true,
+                "pkgdesc" if !in_packages => base_pkgdesc = Some(value.to_string()),
+                "pkgdesc" if package_pkgdesc.is_none() => package_pkgdesc = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        let pkgbase = match pkgbase {
+            Some(pkgbase) if !pkgbase.is_empty() => pkgbase,
+            _ => bail!("no pkgbase"),
+        };
+        match base_pkgdesc.or(package_pkgdesc) {
+            Some(pkgdesc) if !pkgdesc.is_empty() => Ok(Srcinfo { pkgbase, pkgdesc }),
+            _ => bail!("no pkgdesc"),
+        }
+    }
+}
+
+/// Gets a file of a repository with its base64 encoded content.
+pub struct RepositoryFile<'a> {
+    pub project: NameOrId<'a>,
+    pub file_path: &'a str,
+    pub ref_: &'a str,
+}
+
+impl<'a> Endpoint for RepositoryFile<'a> {
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!(
+            "projects/{}/repository/files/{}",
+            self.project,
+            common::path_escaped(self.file_path)
+        )
+        .into()
+    }
+
+    fn parameters(&self) -> QueryParams<'_> {
+        let mut params = QueryParams::default();
+        params.push("ref", self.ref_);
+        params
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RepositoryFileContent {
+    pub content: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    const LINUX_SRCINFO: &str = "pkgbase = linux
+\tpkgdesc = Linux
+\tpkgver = 5.18.1.arch1
+\tpkgrel = 1
+\turl = https://github.com/archlinux/linux/commits/v5.18.1-arch1
+\tarch = x86_64
+
+pkgname = linux
+\tpkgdesc = The Linux kernel and modules
+
+pkgname = linux-headers
+\tpkgdesc = Headers and scripts for building modules for the Linux kernel
+";
+
+    #[rstest]
+    #[case(LINUX_SRCINFO, Some(("linux", "Linux")))]
+    #[case(
+        "pkgbase = zstd\n\tpkgver = 1.5.2\n\npkgname = zstd\n\tpkgdesc = Zstandard - Fast real-time compression algorithm\n",
+        Some(("zstd", "Zstandard - Fast real-time compression algorithm"))
+    )]
+    #[case("# Generated by makepkg\npkgbase = tar\n\tpkgdesc = Utility used to store archives\n", Some(("tar", "Utility used to store archives")))]
+    #[case("pkgname = linux\n\tpkgdesc = Linux\n", None)]
+    #[case("pkgbase = linux\n\tpkgver = 5.18.1.arch1\n", None)]
+    #[case("pkgbase = linux\n\tpkgdesc = \n", None)]
+    #[case("pkgbase = linux\n\tpkgdesc: Linux\n", None)]
+    #[case("pkgbase = linux\npkgbase = linux-lts\n\tpkgdesc = Linux\n", None)]
+    #[case("", None)]
+    fn parse_test(#[case] content: &str, #[case] expected: Option<(&str, &str)>) {
+        let expected = expected.map(|(pkgbase, pkgdesc)| Srcinfo {
+            pkgbase: pkgbase.to_string(),
+            pkgdesc: pkgdesc.to_string(),
+        });
+        assert_eq!(Srcinfo::parse(content).ok(), expected);
+    }
+}
@@ -0,0 +1,309 @@
+//! A GitLab client that paces requests by the rate limit headers of previous
+//! responses, so that concurrent listings slow down before the instance starts
+//! to answer with 429 instead of afterwards. Requests follow the same timeout
+//! and retry policy as all other HTTP clients.
+//!
+//! Requests are sent with our own reqwest client instead of the one of the
+//! gitlab crate, as that one can not be given a custom CA bundle.
+
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use bytes::Bytes;
+use gitlab::api::{ApiError, AsyncClient, RestClient};
+use http::header::HeaderValue;
+use http::request::Builder as RequestBuilder;
+use http::{HeaderMap, Request, Response, StatusCode};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::http_client::{self, MAX_ATTEMPTS, REQUEST_TIMEOUT};
+use crate::metrics;
+use crate::util::unix_timestamp;
+
+const RATE_LIMIT_LIMIT_HEADER: &str = "ratelimit-limit";
+const RATE_LIMIT_REMAINING_HEADER: &str = "ratelimit-remaining";
+const RATE_LIMIT_RESET_HEADER: &str = "ratelimit-reset";
+const PRIVATE_TOKEN_HEADER: &str = "private-token";
+
+/// Pacing starts once less than this fraction of the limit is remaining.
+const PACING_THRESHOLD_DIVISOR: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RateLimit {
+    limit: u64,
+    remaining: u64,
+    /// Unix timestamp at which the limit resets
+    reset: u64,
+}
+
+impl RateLimit {
+    fn from_headers(headers: &HeaderMap) -> Option<RateLimit> {
+        let header = |name: &str| -> Option<u64> { headers.get(name)?.to_str().ok()?.parse().ok() };
+        Some(RateLimit {
+            limit: header(RATE_LIMIT_LIMIT_HEADER)?,
+            remaining: header(RATE_LIMIT_REMAINING_HEADER)?,
+            reset: header(RATE_LIMIT_RESET_HEADER)?,
+        })
+    }
+
+    /// Spreads the remaining requests evenly until the reset once the
+    /// remaining requests drop below the threshold.
+    fn delay(&self, now: u64) -> Option<Duration> {
+        if self.reset <= now || self.remaining * PACING_THRESHOLD_DIVISOR > self.limit {
+            return None;
+        }
+        let window = Duration::from_secs(self.reset - now);
+        Some(window / self.remaining.max(1) as u32)
+    }
+}
+
+/// Errors of sending a request to GitLab.
+#[derive(Debug)]
+pub enum GitLabClientError {
+    Http(http::Error),
+    Communication(reqwest::Error),
+    /// The client was created for planning offline
+    Offline,
+}
+
+impl fmt::Display for GitLabClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GitLabClientError::Http(err) => write!(f, "`http` error: {}", err),
+            GitLabClientError::Communication(err) => {
+                write!(f, "communication with gitlab: {}", err)
+            }
+            GitLabClientError::Offline => write!(f, "no requests are sent offline"),
+        }
+    }
+}
+
+impl Error for GitLabClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GitLabClientError::Http(err) => Some(err),
+            GitLabClientError::Communication(err) => Some(err),
+            GitLabClientError::Offline => None,
+        }
+    }
+}
+
+impl From<http::Error> for GitLabClientError {
+    fn from(err: http::Error) -> Self {
+        GitLabClientError::Http(err)
+    }
+}
+
+impl From<reqwest::Error> for GitLabClientError {
+    fn from(err: reqwest::Error) -> Self {
+        GitLabClientError::Communication(err)
+    }
+}
+
+pub struct ThrottledGitlab {
+    client: reqwest::Client,
+    rest_url: Url,
+    token: HeaderValue,
+    rate_limit: Mutex<Option<RateLimit>>,
+    offline: bool,
+}
+
+impl ThrottledGitlab {
+    pub fn new(
+        client: reqwest::Client,
+        host: &str,
+        token: &str,
+    ) -> anyhow::Result<ThrottledGitlab> {
+        let rest_url = Url::parse(&format!("https://{}/api/v4/", host))
+            .with_context(|| format!("Invalid GitLab host {}", host))?;
+        let mut token = HeaderValue::from_str(token).context("Invalid GitLab token")?;
+        token.set_sensitive(true);
+        Ok(ThrottledGitlab {
+            client,
+            rest_url,
+            token,
+            rate_limit: Mutex::new(None),
+            offline: false,
+        })
+    }
+
+    /// Creates a client that fails all requests, so that an offline run can
+    /// not reach GitLab by accident.
+    pub fn offline(host: &str) -> anyhow::Result<ThrottledGitlab> {
+        let mut client = Self::new(reqwest::Client::new(), host, "offline")?;
+        client.offline = true;
+        Ok(client)
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    async fn send(
+        &self,
+        mut request: RequestBuilder,
+        body: Vec<u8>,
+    ) -> Result<Response<Bytes>, GitLabClientError> {
+        if let Some(headers) = request.headers_mut() {
+            headers.insert(PRIVATE_TOKEN_HEADER, self.token.clone());
+        }
+        let request = request.body(body)?.try_into()?;
+        let response = self.client.execute(request).await?;
+
+        let mut builder = Response::builder()
+            .status(response.status())
+            .version(response.version());
+        if let Some(headers) = builder.headers_mut() {
+            headers.extend(response.headers().clone());
+        }
+        Ok(builder.body(response.bytes().await?)?)
+    }
+}
+
+impl RestClient for ThrottledGitlab {
+    type Error = GitLabClientError;
+
+    fn rest_endpoint(&self, endpoint: &str) -> Result<Url, ApiError<Self::Error>> {
+        debug!("GitLab api call {}", endpoint);
+        Ok(self.rest_url.join(endpoint)?)
+    }
+}
+
+impl ThrottledGitlab {
+    async fn pace(&self) {
+        let rate_limit = *self.rate_limit.lock().await;
+        if let (Some(rate_limit), Ok(now)) = (rate_limit, unix_timestamp()) {
+            if let Some(delay) = rate_limit.delay(now) {
+                debug!(
+                    "{} of {} GitLab requests remaining, waiting {:?}",
+                    rate_limit.remaining, rate_limit.limit, delay
+                );
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncClient for ThrottledGitlab {
+    async fn rest_async(
+        &self,
+        request: RequestBuilder,
+        body: Vec<u8>,
+    ) -> Result<Response<Bytes>, ApiError<Self::Error>> {
+        if self.offline {
+            return Err(ApiError::client(GitLabClientError::Offline));
+        }
+        // The builder can not be cloned, so keep its parts to rebuild it for retries
+        let (parts, ()) = request
+            .body(())
+            .map_err(|err| ApiError::client(err.into()))?
+            .into_parts();
+        let mut attempt = 1;
+        loop {
+            let mut request = Request::builder()
+                .method(parts.method.clone())
+                .uri(parts.uri.clone())
+                .version(parts.version);
+            if let Some(headers) = request.headers_mut() {
+                headers.extend(parts.headers.clone());
+            }
+
+            self.pace().await;
+            metrics::count_request();
+            let result = tokio::time::timeout(REQUEST_TIMEOUT, self.send(request, body.clone()))
+                .await
+                .map(|result| result.map_err(ApiError::client));
+            let retries_left = attempt < MAX_ATTEMPTS;
+            // Writes that may have reached GitLab are never sent again
+            let resendable = retries_left && http_client::is_idempotent(&parts.method);
+            match result {
+                Ok(Ok(response)) => {
+                    if let Some(rate_limit) = RateLimit::from_headers(response.headers()) {
+                        *self.rate_limit.lock().await = Some(rate_limit);
+                    }
+                    if !retries_left
+                        || !http_client::is_retryable_status(&parts.method, response.status())
+                    {
+                        return Ok(response);
+                    }
+                    warn!(
+                        "GitLab {} returned {}, retrying in {:?}",
+                        parts.uri,
+                        response.status(),
+                        http_client::backoff(attempt)
+                    );
+                }
+                Ok(Err(err @ ApiError::Client { .. })) if resendable => warn!(
+                    "GitLab {} failed: {}, retrying in {:?}",
+                    parts.uri,
+                    err,
+                    http_client::backoff(attempt)
+                ),
+                Ok(Err(err)) => return Err(err),
+                Err(_) if resendable => warn!(
+                    "GitLab {} timed out, retrying in {:?}",
+                    parts.uri,
+                    http_client::backoff(attempt)
+                ),
+                Err(_) => {
+                    return Err(ApiError::GitlabService {
+                        status: StatusCode::GATEWAY_TIMEOUT,
+                        data: vec![],
+                    })
+                }
+            }
+            tokio::time::sleep(http_client::backoff(attempt)).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(600, 100, 1060, None)]
+    #[case(600, 60, 1060, Some(Duration::from_secs(1)))]
+    #[case(600, 30, 1060, Some(Duration::from_secs(2)))]
+    #[case(600, 0, 1060, Some(Duration::from_secs(60)))]
+    #[case(600, 0, 1000, None)]
+    fn delay_test(
+        #[case] limit: u64,
+        #[case] remaining: u64,
+        #[case] reset: u64,
+        #[case] expected: Option<Duration>,
+    ) {
+        let rate_limit = RateLimit {
+            limit,
+            remaining,
+            reset,
+        };
+        assert_eq!(rate_limit.delay(1000), expected);
+    }
+
+    #[test]
+    fn from_headers_test() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RATE_LIMIT_LIMIT_HEADER, "600".parse().unwrap());
+        headers.insert(RATE_LIMIT_REMAINING_HEADER, "42".parse().unwrap());
+        assert_eq!(RateLimit::from_headers(&headers), None);
+
+        headers.insert(RATE_LIMIT_RESET_HEADER, "1060".parse().unwrap());
+        assert_eq!(
+            RateLimit::from_headers(&headers),
+            Some(RateLimit {
+                limit: 600,
+                remaining: 42,
+                reset: 1060,
+            })
+        );
+    }
+}
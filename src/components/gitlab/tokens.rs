@@ -0,0 +1,16 @@
+//! Access token endpoints that are not provided by the gitlab crate.
+
+use gitlab::api::endpoint_prelude::*;
+
+/// Gets the personal access token used for the request, including its scopes.
+pub struct PersonalAccessTokenSelf;
+
+impl Endpoint for PersonalAccessTokenSelf {
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        "personal_access_tokens/self".into()
+    }
+}
@@ -1,43 +1,13 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
 use gitlab::api::groups::BranchProtection;
-use gitlab::api::projects::FeatureAccessLevel;
-use serde::Deserialize;
+use gitlab::api::projects::{FeatureAccessLevel, MergeMethod};
+use serde::{Deserialize, Serialize};
 use serde_repr::*;
-use std::fmt::{self, Display, Formatter};
 use strum_macros::EnumString;
 
-#[derive(Debug, Deserialize)]
-pub struct PlanSummary {
-    name: String,
-    pub add: u64,
-    pub change: u64,
-    pub destroy: u64,
-}
-
-impl PlanSummary {
-    pub fn new(name: &str) -> Self {
-        Self {
-            name: name.to_string(),
-            add: 0,
-            change: 0,
-            destroy: 0,
-        }
-    }
-}
-
-impl Display for PlanSummary {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        if 0 == self.add && 0 == self.change && 0 == self.destroy {
-            return write!(f, "No changes. {} is up-to-date.", self.name);
-        }
-        write!(
-            f,
-            "{} has changed!\nPlan: {} to add, {} to change, {} to destroy.",
-            self.name, self.add, self.change, self.destroy
-        )
-    }
-}
-
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Group {
     pub id: u64,
     pub name: String,
@@ -46,6 +16,10 @@ pub struct Group {
     pub full_path: String,
     pub request_access_enabled: bool,
     pub default_branch_protection: GroupBranchProtection,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub avatar_url: Option<String>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Deserialize)]
@@ -99,6 +73,36 @@ impl ProjectFeatureAccessLevel {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProjectMergeMethod {
+    /// A merge commit is created for every merge.
+    Merge,
+    /// A merge commit is created, but only if fast-forward merges are possible.
+    RebaseMerge,
+    /// No merge commits are created, only fast-forward merges are possible.
+    #[serde(rename = "ff")]
+    FastForward,
+}
+
+impl ProjectMergeMethod {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Merge => "merge",
+            Self::RebaseMerge => "rebase_merge",
+            Self::FastForward => "ff",
+        }
+    }
+
+    pub fn as_gitlab_type(self) -> MergeMethod {
+        match self {
+            Self::Merge => MergeMethod::Merge,
+            Self::RebaseMerge => MergeMethod::RebaseMerge,
+            Self::FastForward => MergeMethod::FastForward,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Serialize_repr, Deserialize_repr)]
 #[repr(u8)]
 pub enum GroupBranchProtection {
@@ -138,7 +142,61 @@ pub struct GroupProjects {
     pub visibility: ProjectVisibilityLevel,
     pub request_access_enabled: bool,
     pub container_registry_enabled: bool,
+    pub issues_access_level: ProjectFeatureAccessLevel,
+    pub merge_requests_access_level: ProjectFeatureAccessLevel,
+    pub wiki_access_level: ProjectFeatureAccessLevel,
+    pub builds_access_level: ProjectFeatureAccessLevel,
     pub snippets_access_level: ProjectFeatureAccessLevel,
+    pub merge_method: ProjectMergeMethod,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PackageProject {
+    pub id: u64,
+    pub path_with_namespace: String,
+    pub description: Option<String>,
+    pub default_branch: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Issue {
+    pub iid: u64,
+    pub project_id: u64,
+    pub title: String,
+    pub state: String,
+    pub author: IssueAuthor,
+    pub references: IssueReferences,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IssueAuthor {
+    pub id: u64,
+    pub username: String,
+    pub state: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssueReferences {
+    pub full: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegistryRepository {
+    pub id: u64,
+    pub path: String,
+    pub project_id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegistryTag {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegistryTagDetails {
+    pub name: String,
+    pub created_at: String,
+    pub total_size: u64,
 }
 
 #[derive(Debug, Deserialize)]
@@ -196,3 +254,55 @@ pub struct GitLabUser {
     pub name: String,
     pub email: Option<String>,
 }
+
+#[derive(Debug, Deserialize)]
+pub struct CurrentUser {
+    pub username: String,
+    #[serde(default)]
+    pub is_admin: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PersonalAccessToken {
+    pub name: String,
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssueNote {
+    pub id: u64,
+    pub body: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MergeRequest {
+    pub iid: u64,
+    pub web_url: String,
+}
+
+/// A reference to an issue in the form `group/project#iid`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IssueReference {
+    pub project: String,
+    pub iid: u64,
+}
+
+impl FromStr for IssueReference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (project, iid) = s
+            .rsplit_once('#')
+            .with_context(|| format!("Invalid issue reference {}, expected project#iid", s))?;
+        if project.is_empty() {
+            bail!("Invalid issue reference {}, missing project", s);
+        }
+        let iid = iid
+            .parse()
+            .with_context(|| format!("Invalid issue iid in {}", s))?;
+        Ok(IssueReference {
+            project: project.to_string(),
+            iid,
+        })
+    }
+}
@@ -0,0 +1,364 @@
+//! This module defines Grafana related actions and enforcements.
+//!
+//! ## Features
+//!
+//! - remove org users that are not staff members anymore
+//! - enforce org roles: DevOps are admins, all other staff are viewers
+//! - sync Grafana team members with the Keycloak team of the same name
+
+use crate::args::Action;
+use crate::components::grafana::types::*;
+use crate::components::Component;
+use crate::error::CheckStatus;
+use crate::http_client;
+use crate::plan::{Plan, PlanEntry, PlanSummary, Resource};
+use crate::state::{State, Team, User};
+use crate::util;
+
+use std::env;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use reqwest::{Client, Method, RequestBuilder, Url};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::{debug, error, info};
+
+const SERVICE: &str = "Grafana";
+const GRAFANA_ADMIN: &str = "admin";
+
+const ROLE_ADMIN: &str = "Admin";
+const ROLE_VIEWER: &str = "Viewer";
+
+const RULE_STAFF_ONLY_ORG: &str = "only staff may be grafana org users";
+const RULE_ORG_ROLES: &str = "devops are grafana admins, other staff are viewers";
+
+pub struct Grafana {
+    client: Client,
+    url: Url,
+    token: String,
+    state: Arc<Mutex<State>>,
+    plan: Arc<Plan>,
+}
+
+impl Grafana {
+    /// Returns None if no Grafana instance is configured.
+    pub async fn new(
+        client: Client,
+        state: Arc<Mutex<State>>,
+        plan: Arc<Plan>,
+    ) -> Result<Option<Grafana>> {
+        let url = match env::var("GLUEBUDDY_GRAFANA_URL") {
+            Ok(url) => url,
+            Err(_) => {
+                info!("GLUEBUDDY_GRAFANA_URL not set, skipping Grafana");
+                return Ok(None);
+            }
+        };
+        let url = Url::parse(&url).with_context(|| format!("Invalid Grafana url {}", url))?;
+        let token = util::secret("GLUEBUDDY_GRAFANA_TOKEN")?;
+
+        Ok(Some(Grafana {
+            client,
+            url,
+            token,
+            state,
+            plan,
+        }))
+    }
+
+    fn request(&self, method: Method, path: &[&str]) -> Result<RequestBuilder> {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("Grafana url {} can not be a base", self.url))?
+            .pop_if_empty()
+            .push("api")
+            .extend(path);
+        Ok(self.client.request(method, url).bearer_auth(&self.token))
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &[&str]) -> Result<T> {
+        let response = http_client::send(self.request(Method::GET, path)?)
+            .await?
+            .check_status(SERVICE)?;
+        Ok(response.json().await?)
+    }
+
+    async fn send<T: Serialize>(
+        &self,
+        method: Method,
+        path: &[&str],
+        body: Option<&T>,
+    ) -> Result<()> {
+        let mut request = self.request(method, path)?;
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        http_client::send(request).await?.check_status(SERVICE)?;
+        Ok(())
+    }
+
+    async fn update_org_users(
+        &self,
+        action: &Action,
+        state: &State,
+        org_users: &[OrgUser],
+    ) -> Result<()> {
+        let mut summary = PlanSummary::new("Grafana org users");
+        for org_user in org_users {
+            if org_user.login.eq(GRAFANA_ADMIN) {
+                continue;
+            }
+            let user_id = org_user.user_id.to_string();
+            match state
+                .staff()
+                .into_iter()
+                .find(|user| user.username.eq(&org_user.login))
+            {
+                None => {
+                    let entry = PlanEntry::destroy(
+                        Resource::grafana_org_user(&org_user.login, &org_user.role),
+                        RULE_STAFF_ONLY_ORG,
+                    );
+                    self.plan.record(&entry)?;
+                    if self.plan.applies(action, &entry) {
+                        let result = self
+                            .send::<()>(Method::DELETE, &["org", "users", &user_id], None)
+                            .await
+                            .with_context(|| format!("Failed to remove {}", org_user.login));
+                        if self.record_apply_result(&entry, result)? {
+                            let actual = self.get_org_user(&org_user.login).await;
+                            self.plan.verify_applied(&entry, actual);
+                        }
+                    }
+                    summary.destroy += 1;
+                }
+                Some(user) => {
+                    let expected_role = expected_role(user);
+                    if org_user.role.eq(expected_role) {
+                        continue;
+                    }
+                    let entry = PlanEntry::change(
+                        Resource::grafana_org_user(&org_user.login, &org_user.role),
+                        Resource::grafana_org_user(&org_user.login, expected_role),
+                        RULE_ORG_ROLES,
+                    );
+                    self.plan.record(&entry)?;
+                    if self.plan.applies(action, &entry) {
+                        let result = self
+                            .send(
+                                Method::PATCH,
+                                &["org", "users", &user_id],
+                                Some(&RoleUpdate {
+                                    role: expected_role,
+                                }),
+                            )
+                            .await
+                            .with_context(|| {
+                                format!("Failed to update role of {}", org_user.login)
+                            });
+                        if self.record_apply_result(&entry, result)? {
+                            let actual = self.get_org_user(&org_user.login).await;
+                            self.plan.verify_applied(&entry, actual);
+                        }
+                    }
+                    summary.change += 1;
+                }
+            }
+        }
+        self.plan.finish_section(&summary)
+    }
+
+    /// Records the outcome of applying a planned change and returns whether
+    /// it was applied. A failed write is logged and recorded, so that the
+    /// remaining plan is still applied.
+    fn record_apply_result(&self, entry: &PlanEntry, result: Result<()>) -> Result<bool> {
+        match result {
+            Ok(()) => {
+                self.plan.record_applied(entry)?;
+                Ok(true)
+            }
+            Err(err) => {
+                error!("{:?}", err);
+                self.plan.record_failed(entry, &err);
+                Ok(false)
+            }
+        }
+    }
+
+    async fn get_org_user(&self, login: &str) -> Result<Option<Resource>> {
+        let org_users: Vec<OrgUser> = self
+            .get(&["org", "users"])
+            .await
+            .context("Failed to get Grafana org users")?;
+        Ok(org_users
+            .into_iter()
+            .find(|org_user| org_user.login.eq(login))
+            .map(|org_user| Resource::grafana_org_user(&org_user.login, &org_user.role)))
+    }
+
+    async fn get_team_member(
+        &self,
+        team: &Team,
+        team_id: &str,
+        login: &str,
+    ) -> Result<Option<Resource>> {
+        let members: Vec<TeamMember> = self
+            .get(&["teams", team_id, "members"])
+            .await
+            .with_context(|| format!("Failed to get members of Grafana team {}", team.name))?;
+        Ok(members
+            .into_iter()
+            .find(|member| member.login.eq(login))
+            .map(|member| Resource::grafana_team_member(&team.name, &member.login)))
+    }
+
+    async fn update_team_members(
+        &self,
+        action: &Action,
+        state: &State,
+        org_users: &[OrgUser],
+        team: &Team,
+    ) -> Result<()> {
+        let search: TeamSearch = http_client::send(
+            self.request(Method::GET, &["teams", "search"])?
+                .query(&[("name", team.name.as_str())]),
+        )
+        .await?
+        .check_status(SERVICE)?
+        .json()
+        .await
+        .with_context(|| format!("Failed to search Grafana team {}", team.name))?;
+        let grafana_team = match search.teams.into_iter().find(|t| t.name.eq(&team.name)) {
+            Some(grafana_team) => grafana_team,
+            None => {
+                debug!("No Grafana team for {}", team.name);
+                return Ok(());
+            }
+        };
+        let team_id = grafana_team.id.to_string();
+
+        let members: Vec<TeamMember> = self
+            .get(&["teams", &team_id, "members"])
+            .await
+            .with_context(|| format!("Failed to get members of Grafana team {}", team.name))?;
+
+        let label = format!("Grafana '{}' team members", team.name);
+        let mut summary = PlanSummary::new(&label);
+        let rule = format!("team {} mapping", team.name);
+        let team_members = state.team_members(team);
+
+        for user in &team_members {
+            if members.iter().any(|member| member.login.eq(&user.username)) {
+                continue;
+            }
+            // Only users that logged in at least once exist in Grafana
+            let org_user = match org_users
+                .iter()
+                .find(|org_user| org_user.login.eq(&user.username))
+            {
+                Some(org_user) => org_user,
+                None => continue,
+            };
+            let entry = PlanEntry::add(
+                Resource::grafana_team_member(&team.name, &user.username),
+                &rule,
+            );
+            self.plan.record(&entry)?;
+            if self.plan.applies(action, &entry) {
+                let result = self
+                    .send(
+                        Method::POST,
+                        &["teams", &team_id, "members"],
+                        Some(&TeamMemberAdd {
+                            user_id: org_user.user_id,
+                        }),
+                    )
+                    .await
+                    .with_context(|| format!("Failed to add {} to {}", user.username, team.name));
+                if self.record_apply_result(&entry, result)? {
+                    let actual = self.get_team_member(team, &team_id, &user.username).await;
+                    self.plan.verify_applied(&entry, actual);
+                }
+            }
+            summary.add += 1;
+        }
+
+        for member in &members {
+            if team_members
+                .iter()
+                .any(|user| user.username.eq(&member.login))
+            {
+                continue;
+            }
+            let entry = PlanEntry::destroy(
+                Resource::grafana_team_member(&team.name, &member.login),
+                &rule,
+            );
+            self.plan.record(&entry)?;
+            if self.plan.applies(action, &entry) {
+                let user_id = member.user_id.to_string();
+                let result = self
+                    .send::<()>(
+                        Method::DELETE,
+                        &["teams", &team_id, "members", &user_id],
+                        None,
+                    )
+                    .await
+                    .with_context(|| {
+                        format!("Failed to remove {} from {}", member.login, team.name)
+                    });
+                if self.record_apply_result(&entry, result)? {
+                    let actual = self.get_team_member(team, &team_id, &member.login).await;
+                    self.plan.verify_applied(&entry, actual);
+                }
+            }
+            summary.destroy += 1;
+        }
+
+        self.plan.finish_section(&summary)
+    }
+}
+
+#[async_trait(?Send)]
+impl Component for Grafana {
+    async fn run(&self, action: Action) -> Result<()> {
+        self.plan.start_component("Grafana");
+        let org_users: Vec<OrgUser> = self
+            .get(&["org", "users"])
+            .await
+            .context("Failed to get Grafana org users")?;
+
+        let state = self.state.lock().await;
+        self.update_org_users(&action, &state, &org_users).await?;
+        for team in &state.teams {
+            self.update_team_members(&action, &state, &org_users, team)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+fn expected_role(user: &User) -> &'static str {
+    match user.is_devops() {
+        true => ROLE_ADMIN,
+        false => ROLE_VIEWER,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("/Arch Linux Staff/DevOps", ROLE_ADMIN)]
+    #[case("/Arch Linux Staff/Developers", ROLE_VIEWER)]
+    fn expected_role_test(#[case] group: &str, #[case] expected: &str) {
+        let mut user = User::new("alice".to_string());
+        user.groups = [group.to_string()].into();
+        assert_eq!(expected_role(&user), expected);
+    }
+}
@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrgUser {
+    pub user_id: u64,
+    pub login: String,
+    pub role: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TeamSearch {
+    pub teams: Vec<GrafanaTeam>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GrafanaTeam {
+    pub id: u64,
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TeamMember {
+    pub user_id: u64,
+    pub login: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoleUpdate<'a> {
+    pub role: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TeamMemberAdd {
+    pub user_id: u64,
+}
@@ -1,3 +1,4 @@
 pub mod core;
+pub mod types;
 
 pub use crate::components::keycloak::core::Keycloak;
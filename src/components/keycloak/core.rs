@@ -2,51 +2,85 @@
 //!
 //! ## Features
 //!
+//! - disable or delete accounts that never completed a login
 
 use crate::args::Action;
+use crate::cache::{Cache, CacheSource};
+use crate::components::keycloak::types::*;
+use crate::components::Component;
+use crate::error::{self, CheckStatus, ConfigError, ErrorClass};
+use crate::http_client;
+use crate::plan::{Plan, PlanEntry, PlanSummary, Resource};
 
 use keycloak::types::{GroupRepresentation, UserRepresentation};
 use keycloak::{KeycloakAdmin, KeycloakAdminToken, KeycloakError};
-use reqwest::Client;
+use reqwest::{Client, Method, RequestBuilder};
 
 use futures::future::try_join_all;
 
-use anyhow::{Context, Result};
-use log::{debug, info};
-use serde_json::json;
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
 use tokio::sync::Mutex;
+use tracing::{debug, error, info, info_span, Instrument};
 
+use std::collections::HashMap;
 use std::env;
 use std::sync::Arc;
 
 use crate::state::State;
 use crate::state::User;
+use crate::util;
+
+const SERVICE: &str = "Keycloak";
+
+const REALM_MANAGEMENT_CLIENT: &str = "realm-management";
+/// Roles needed to read the users and groups of the realm.
+const REQUIRED_REALM_MANAGEMENT_ROLES: [&str; 3] = ["query-groups", "query-users", "view-users"];
+/// Role needed to disable or delete accounts.
+const MANAGE_USERS_ROLE: &str = "manage-users";
+
+/// Number of users fetched per page while looking for stale accounts.
+const USERS_PAGE_SIZE: usize = 100;
+const MILLIS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// Accounts that never completed a login are cleaned up after this many days.
+struct StaleAccounts {
+    max_age_days: u64,
+    action: StaleAccountAction,
+}
 
 pub struct Keycloak {
     admin: KeycloakAdmin,
+    client: Client,
+    url: String,
+    access_token: String,
     realm: String,
+    /// Roles of the realm-management client granted to the service account
+    realm_management_roles: Vec<String>,
+    stale_accounts: Option<StaleAccounts>,
     state: Arc<Mutex<State>>,
+    plan: Arc<Plan>,
 }
 
 impl Keycloak {
-    pub async fn new(state: Arc<Mutex<State>>) -> Result<Keycloak> {
-        let username = &env::var("GLUEBUDDY_KEYCLOAK_USERNAME")
-            .context("Missing env var GLUEBUDDY_KEYCLOAK_USERNAME")?;
-        let password = &env::var("GLUEBUDDY_KEYCLOAK_PASSWORD")
-            .context("Missing env var GLUEBUDDY_KEYCLOAK_PASSWORD")?;
-        let realm = &env::var("GLUEBUDDY_KEYCLOAK_REALM")
-            .context("Missing GLUEBUDDY_KEYCLOAK_REALM env var")?;
-        let url = &env::var("GLUEBUDDY_KEYCLOAK_URL")
-            .context("Missing GLUEBUDDY_KEYCLOAK_URL env var")?;
-
-        let client = Client::new();
+    pub async fn new(
+        client: Client,
+        state: Arc<Mutex<State>>,
+        plan: Arc<Plan>,
+    ) -> Result<Keycloak> {
+        let username = &util::required_env("GLUEBUDDY_KEYCLOAK_USERNAME")?;
+        let password = &util::secret("GLUEBUDDY_KEYCLOAK_PASSWORD")?;
+        let realm = &util::required_env("GLUEBUDDY_KEYCLOAK_REALM")?;
+        let url = &util::required_env("GLUEBUDDY_KEYCLOAK_URL")?;
 
         info!(
             "acquire API token for keycloak {} using realm {}",
             url, realm
         );
 
-        let token = Self::acquire_custom_realm(
+        let response = Self::acquire_custom_realm(
             url,
             username,
             password,
@@ -56,15 +90,67 @@ impl Keycloak {
             &client,
         )
         .await?;
-        let admin = KeycloakAdmin::new(url, token, client);
+        let access_token = response["access_token"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let realm_management_roles = match access_token.is_empty() {
+            false => token_client_roles(&access_token, REALM_MANAGEMENT_CLIENT)?,
+            true => vec![],
+        };
+        let token: KeycloakAdminToken =
+            serde_json::from_value(response).context("Invalid Keycloak token response")?;
+        let admin = KeycloakAdmin::new(url, token, client.clone());
+
+        let stale_accounts = match env::var("GLUEBUDDY_KEYCLOAK_STALE_ACCOUNT_DAYS") {
+            Ok(days) => Some(StaleAccounts {
+                max_age_days: days.parse().map_err(|_| ConfigError::Invalid {
+                    name: "GLUEBUDDY_KEYCLOAK_STALE_ACCOUNT_DAYS".to_string(),
+                    reason: "expected a number".to_string(),
+                })?,
+                action: match env::var("GLUEBUDDY_KEYCLOAK_STALE_ACCOUNT_ACTION") {
+                    Ok(action) => action.parse().map_err(|err| ConfigError::Invalid {
+                        name: "GLUEBUDDY_KEYCLOAK_STALE_ACCOUNT_ACTION".to_string(),
+                        reason: format!("{}", err),
+                    })?,
+                    Err(_) => StaleAccountAction::Disable,
+                },
+            }),
+            Err(_) => None,
+        };
 
         Ok(Keycloak {
             admin,
+            client,
+            url: url.to_string(),
+            access_token,
             realm: realm.to_string(),
+            realm_management_roles,
+            stale_accounts,
             state,
+            plan,
         })
     }
 
+    /// Verifies that the service account has all realm management roles
+    /// gluebuddy needs, so that missing roles fail before planning.
+    pub fn preflight(&self) -> Result<()> {
+        let missing: Vec<&str> = REQUIRED_REALM_MANAGEMENT_ROLES
+            .iter()
+            .copied()
+            .chain(self.stale_accounts.as_ref().map(|_| MANAGE_USERS_ROLE))
+            .filter(|role| !self.realm_management_roles.iter().any(|r| r == role))
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "Keycloak service account lacks the {} roles of the {} client, assign them in its service account roles",
+                missing.join(", "),
+                REALM_MANAGEMENT_CLIENT
+            );
+        }
+        Ok(())
+    }
+
     async fn acquire_custom_realm(
         url: &str,
         username: &str,
@@ -73,21 +159,22 @@ impl Keycloak {
         client_id: &str,
         grant_type: &str,
         client: &reqwest::Client,
-    ) -> Result<KeycloakAdminToken, KeycloakError> {
-        let response = client
-            .post(&format!(
-                "{}/auth/realms/{}/protocol/openid-connect/token",
-                url, realm
-            ))
-            .form(&json!({
-                "username": username,
-                "password": password,
-                "client_id": client_id,
-                "client_secret": password,
-                "grant_type": grant_type
-            }))
-            .send()
-            .await?;
+    ) -> Result<Value> {
+        let response = http_client::send(
+            client
+                .post(format!(
+                    "{}/auth/realms/{}/protocol/openid-connect/token",
+                    url, realm
+                ))
+                .form(&json!({
+                    "username": username,
+                    "password": password,
+                    "client_id": client_id,
+                    "client_secret": password,
+                    "grant_type": grant_type
+                })),
+        )
+        .await?;
 
         Ok(Self::error_check(response).await?.json().await?)
     }
@@ -106,9 +193,17 @@ impl Keycloak {
         Ok(response)
     }
 
-    pub async fn gather(&self) -> Result<()> {
+    async fn gather_state(&self, cache: Option<&Cache>) -> Result<()> {
+        if let Some(cache) = cache {
+            if let Some(users) = cache.load(CacheSource::Keycloak)? {
+                Self::restore_cached_users(&self.state, users).await;
+                Self::apply_account_aliases(&self.state).await;
+                return Ok(());
+            }
+        }
+
         info!("Gathering Keycloak state");
-        let root_groups = vec!["Arch Linux Staff", "External Contributors"];
+        let root_groups = ["Arch Linux Staff", "External Contributors"];
 
         let all_groups = self
             .admin
@@ -171,17 +266,210 @@ impl Keycloak {
                     .users
                     .entry(user.username.as_ref().unwrap().to_string())
                     .or_insert_with_key(|key| User::new(key.clone()));
+                state_user.email = user.email.clone();
+                state_user.keycloak_id = user.id.clone();
+                state_user.enabled = user.enabled.unwrap_or(true);
+                state_user.attributes = user
+                    .attributes
+                    .as_ref()
+                    .map(attribute_values)
+                    .unwrap_or_default();
                 state_user.groups.insert(path.to_string());
             }
         }
 
+        if let Some(cache) = cache {
+            cache.store(CacheSource::Keycloak, &state.users)?;
+        }
+        drop(state);
+
+        Self::apply_account_aliases(&self.state).await;
+
         Ok(())
     }
 
-    pub async fn run(&self, _action: Action) -> Result<()> {
+    /// Restores the users of a previous run from the cache without logging
+    /// in to Keycloak, regardless of the age of the cache.
+    pub async fn restore(state: &Mutex<State>, cache: &Cache) -> Result<()> {
+        let users = cache
+            .load(CacheSource::Keycloak)?
+            .context("No cached Keycloak state, run with --cache first")?;
+        Self::restore_cached_users(state, users).await;
+        Self::apply_account_aliases(state).await;
         Ok(())
     }
 
+    async fn apply_account_aliases(state: &Mutex<State>) {
+        let aliases = match env::var("GLUEBUDDY_ACCOUNT_ALIASES") {
+            Ok(aliases) => util::parse_account_aliases(&aliases),
+            Err(_) => return,
+        };
+        let mut state = state.lock().await;
+        for (username, historical) in aliases {
+            match state.users.get_mut(&username) {
+                None => debug!("Ignoring aliases of unknown user {}", username),
+                Some(user) => user.aliases = historical,
+            }
+        }
+    }
+
+    async fn restore_cached_users(state: &Mutex<State>, users: HashMap<String, User>) {
+        let mut state = state.lock().await;
+        state.users.extend(users);
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.client
+            .request(
+                method,
+                format!("{}/auth/admin/realms/{}/{}", self.url, self.realm, path),
+            )
+            .bearer_auth(&self.access_token)
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let response = http_client::send(self.request(Method::GET, path))
+            .await?
+            .check_status(SERVICE)?;
+        Ok(response.json().await?)
+    }
+
+    async fn get_realm_user(&self, user: &RealmUser) -> Result<Option<Resource>> {
+        let result: Result<RealmUser> = self.get(&format!("users/{}", user.id)).await;
+        match result {
+            Ok(actual) => Ok(Some(Resource::keycloak_user(
+                &actual.username,
+                actual.enabled,
+            ))),
+            Err(err) => match error::classify(&err) {
+                ErrorClass::NotFound => Ok(None),
+                _ => Err(err.context(format!("Failed to get Keycloak user {}", user.username))),
+            },
+        }
+    }
+
+    /// Disables or deletes accounts older than the configured age that never
+    /// completed a login, so stalled onboarding attempts don't accumulate.
+    /// Staff members are never touched.
+    async fn cleanup_stale_accounts(
+        &self,
+        action: &Action,
+        stale_accounts: &StaleAccounts,
+    ) -> Result<()> {
+        let now = util::unix_timestamp()? * 1000;
+        let rule = format!(
+            "accounts that never logged in expire after {} days",
+            stale_accounts.max_age_days
+        );
+        let mut summary = PlanSummary::new("Keycloak stale accounts");
+        let state = self.state.lock().await.clone();
+
+        let mut first = 0;
+        let mut candidates = vec![];
+        loop {
+            let users: Vec<RealmUser> = self
+                .get(&format!(
+                    "users?briefRepresentation=false&first={}&max={}",
+                    first, USERS_PAGE_SIZE
+                ))
+                .await
+                .context("Failed to list Keycloak users")?;
+            let count = users.len();
+            candidates.extend(users.into_iter().filter(|user| {
+                !state.users.contains_key(&user.username)
+                    && is_stale(user, now, stale_accounts.max_age_days)
+                    && (user.enabled || stale_accounts.action == StaleAccountAction::Delete)
+            }));
+            if count < USERS_PAGE_SIZE {
+                break;
+            }
+            first += count;
+        }
+
+        for user in candidates {
+            if !self.never_logged_in(&user).await? {
+                continue;
+            }
+            let entry = match stale_accounts.action {
+                StaleAccountAction::Disable => PlanEntry::change(
+                    Resource::keycloak_user(&user.username, true),
+                    Resource::keycloak_user(&user.username, false),
+                    &rule,
+                ),
+                StaleAccountAction::Delete => {
+                    PlanEntry::destroy(Resource::keycloak_user(&user.username, user.enabled), &rule)
+                }
+            };
+            self.plan.record(&entry)?;
+            match stale_accounts.action {
+                StaleAccountAction::Disable => summary.change += 1,
+                StaleAccountAction::Delete => summary.destroy += 1,
+            }
+            if self.plan.applies(action, &entry) {
+                let path = format!("users/{}", user.id);
+                let request = match stale_accounts.action {
+                    StaleAccountAction::Disable => self
+                        .request(Method::PUT, &path)
+                        .json(&json!({ "enabled": false })),
+                    StaleAccountAction::Delete => self.request(Method::DELETE, &path),
+                };
+                let result = async {
+                    http_client::send(request).await?.check_status(SERVICE)?;
+                    Ok::<_, anyhow::Error>(())
+                }
+                .instrument(info_span!("user", user = %user.username))
+                .await
+                .with_context(|| format!("Failed to clean up Keycloak account {}", user.username));
+                match result {
+                    Ok(()) => {
+                        self.plan.record_applied(&entry)?;
+                        let actual = self.get_realm_user(&user).await;
+                        self.plan.verify_applied(&entry, actual);
+                    }
+                    Err(err) => {
+                        error!("{:?}", err);
+                        self.plan.record_failed(&entry, &err);
+                    }
+                }
+            }
+        }
+
+        self.plan.finish_section(&summary)
+    }
+
+    /// Users that log in with an identity provider have no credentials, so
+    /// only users without both never logged in.
+    async fn never_logged_in(&self, user: &RealmUser) -> Result<bool> {
+        let credentials: Vec<Credential> = self
+            .get(&format!("users/{}/credentials", user.id))
+            .await
+            .with_context(|| format!("Failed to get credentials of {}", user.username))?;
+        if !credentials.is_empty() {
+            debug!(
+                "Keeping {}, it has {} credentials",
+                user.username,
+                credentials
+                    .iter()
+                    .map(|credential| credential.credential_type.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            return Ok(false);
+        }
+        let identities: Vec<FederatedIdentity> = self
+            .get(&format!("users/{}/federated-identity", user.id))
+            .await
+            .with_context(|| format!("Failed to get identity providers of {}", user.username))?;
+        if let Some(identity) = identities.first() {
+            debug!(
+                "Keeping {}, it is linked to {}",
+                user.username, identity.identity_provider
+            );
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
     async fn get_group_members(
         &self,
         group: GroupRepresentation,
@@ -199,3 +487,118 @@ impl Keycloak {
         Ok((group, users))
     }
 }
+
+#[async_trait(?Send)]
+impl Component for Keycloak {
+    async fn gather(&self, cache: Option<&Cache>) -> Result<()> {
+        self.plan.start_component("Keycloak");
+        self.plan.gather_started()?;
+        self.gather_state(cache).await?;
+        self.plan.gather_finished()
+    }
+
+    async fn run(&self, action: Action) -> Result<()> {
+        self.plan.start_component("Keycloak");
+        if let Some(stale_accounts) = &self.stale_accounts {
+            self.cleanup_stale_accounts(&action, stale_accounts).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the account is older than the maximum age and still has pending
+/// required actions, which are completed on the first login.
+fn is_stale(user: &RealmUser, now: u64, max_age_days: u64) -> bool {
+    let created = match user.created_timestamp {
+        Some(created) => created,
+        None => return false,
+    };
+    !user.required_actions.is_empty() && now.saturating_sub(created) > max_age_days * MILLIS_PER_DAY
+}
+
+/// Keycloak stores every attribute as a list of strings.
+fn attribute_values(attributes: &HashMap<String, Value>) -> HashMap<String, Vec<String>> {
+    attributes
+        .iter()
+        .map(|(name, value)| {
+            let values = match value {
+                Value::Array(values) => values
+                    .iter()
+                    .filter_map(|value| value.as_str().map(|value| value.to_string()))
+                    .collect(),
+                Value::String(value) => vec![value.to_string()],
+                _ => vec![],
+            };
+            (name.to_string(), values)
+        })
+        .collect()
+}
+
+/// Returns the roles of the client granted by the JWT access token.
+fn token_client_roles(access_token: &str, client: &str) -> Result<Vec<String>> {
+    let payload = access_token
+        .split('.')
+        .nth(1)
+        .context("Keycloak access token is no JWT")?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .context("Keycloak access token has an invalid payload")?;
+    let claims: Value =
+        serde_json::from_slice(&payload).context("Keycloak access token has an invalid payload")?;
+    Ok(claims["resource_access"][client]["roles"]
+        .as_array()
+        .map(|roles| {
+            roles
+                .iter()
+                .filter_map(|role| role.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    const NOW: u64 = 1_654_000_000_000;
+
+    #[rstest]
+    #[case(Some(NOW - 31 * MILLIS_PER_DAY), vec!["VERIFY_EMAIL"], true)]
+    #[case(Some(NOW - 29 * MILLIS_PER_DAY), vec!["VERIFY_EMAIL"], false)]
+    #[case(Some(NOW - 31 * MILLIS_PER_DAY), vec![], false)]
+    #[case(None, vec!["UPDATE_PASSWORD"], false)]
+    fn is_stale_test(
+        #[case] created_timestamp: Option<u64>,
+        #[case] required_actions: Vec<&str>,
+        #[case] expected: bool,
+    ) {
+        let user = RealmUser {
+            id: "4f0a6c5e".to_string(),
+            username: "alice".to_string(),
+            enabled: true,
+            created_timestamp,
+            required_actions: required_actions.into_iter().map(str::to_string).collect(),
+        };
+        assert_eq!(is_stale(&user, NOW, 30), expected);
+    }
+
+    #[test]
+    fn token_client_roles_test() {
+        let claims = json!({
+            "resource_access": {
+                "realm-management": {"roles": ["view-users", "query-groups"]},
+                "account": {"roles": ["manage-account"]}
+            }
+        });
+        let payload = base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD);
+        let access_token = format!("header.{}.signature", payload);
+        assert_eq!(
+            token_client_roles(&access_token, REALM_MANAGEMENT_CLIENT).unwrap(),
+            vec!["view-users".to_string(), "query-groups".to_string()]
+        );
+        assert!(token_client_roles(&access_token, "broker")
+            .unwrap()
+            .is_empty());
+        assert!(token_client_roles("opaque", REALM_MANAGEMENT_CLIENT).is_err());
+    }
+}
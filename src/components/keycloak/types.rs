@@ -0,0 +1,48 @@
+use std::str::FromStr;
+
+use anyhow::bail;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RealmUser {
+    pub id: String,
+    pub username: String,
+    #[serde(default)]
+    pub enabled: bool,
+    /// Milliseconds since the epoch
+    pub created_timestamp: Option<u64>,
+    #[serde(default)]
+    pub required_actions: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Credential {
+    #[serde(rename = "type")]
+    pub credential_type: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FederatedIdentity {
+    pub identity_provider: String,
+}
+
+/// What happens to accounts that never completed a login.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StaleAccountAction {
+    Disable,
+    Delete,
+}
+
+impl FromStr for StaleAccountAction {
+    type Err = anyhow::Error;
+
+    fn from_str(action: &str) -> Result<Self, Self::Err> {
+        match action {
+            "disable" => Ok(StaleAccountAction::Disable),
+            "delete" => Ok(StaleAccountAction::Delete),
+            _ => bail!("expected disable or delete"),
+        }
+    }
+}
@@ -0,0 +1,295 @@
+//! This module defines Matrix related actions and enforcements.
+//!
+//! ## Features
+//!
+//! - invite all team members to the private rooms and spaces of their team
+//! - remove everyone else from the private rooms and spaces of a team
+
+use crate::args::Action;
+use crate::components::matrix::types::*;
+use crate::components::Component;
+use crate::error::{self, CheckStatus, ErrorClass};
+use crate::http_client;
+use crate::plan::{Plan, PlanEntry, PlanSummary, Resource};
+use crate::state::{State, Team, User};
+use crate::util;
+
+use std::env;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use reqwest::{Client, Method, RequestBuilder, Url};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::{debug, error, info, warn};
+
+const SERVICE: &str = "Matrix";
+const RULE_TEAM_ROOMS_ONLY_TEAM_MEMBERS: &str = "team rooms are reserved for team members";
+const KICK_REASON: &str = "Not a member of the team anymore";
+
+pub struct Matrix {
+    client: Client,
+    url: Url,
+    token: String,
+    server_name: String,
+    team_rooms: Vec<TeamRoom>,
+    state: Arc<Mutex<State>>,
+    plan: Arc<Plan>,
+}
+
+impl Matrix {
+    /// Returns None if no Matrix homeserver is configured.
+    pub async fn new(
+        client: Client,
+        state: Arc<Mutex<State>>,
+        plan: Arc<Plan>,
+    ) -> Result<Option<Matrix>> {
+        let url = match env::var("GLUEBUDDY_MATRIX_URL") {
+            Ok(url) => url,
+            Err(_) => {
+                info!("GLUEBUDDY_MATRIX_URL not set, skipping Matrix");
+                return Ok(None);
+            }
+        };
+        let url = Url::parse(&url).with_context(|| format!("Invalid Matrix url {}", url))?;
+        let token = util::secret("GLUEBUDDY_MATRIX_TOKEN")?;
+        let server_name = util::required_env("GLUEBUDDY_MATRIX_SERVER_NAME")?;
+        let team_rooms =
+            parse_team_rooms(&env::var("GLUEBUDDY_MATRIX_TEAM_ROOMS").unwrap_or_default());
+
+        Ok(Some(Matrix {
+            client,
+            url,
+            token,
+            server_name,
+            team_rooms,
+            state,
+            plan,
+        }))
+    }
+
+    fn request(&self, method: Method, path: &[&str]) -> Result<RequestBuilder> {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("Matrix url {} can not be a base", self.url))?
+            .pop_if_empty()
+            .extend(path);
+        Ok(self.client.request(method, url).bearer_auth(&self.token))
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &[&str]) -> Result<T> {
+        let response = http_client::send(self.request(Method::GET, path)?)
+            .await?
+            .check_status(SERVICE)?;
+        Ok(response.json().await?)
+    }
+
+    async fn post<T: Serialize>(&self, path: &[&str], body: &T) -> Result<()> {
+        http_client::send(self.request(Method::POST, path)?.json(body))
+            .await?
+            .check_status(SERVICE)?;
+        Ok(())
+    }
+
+    fn user_id(&self, user: &User) -> String {
+        format!("@{}:{}", user.username, self.server_name)
+    }
+
+    /// Records the outcome of applying a planned change and returns whether
+    /// it was applied. A failed write is logged and recorded, so that the
+    /// remaining plan is still applied.
+    fn record_apply_result(&self, entry: &PlanEntry, result: Result<()>) -> Result<bool> {
+        match result {
+            Ok(()) => {
+                self.plan.record_applied(entry)?;
+                Ok(true)
+            }
+            Err(err) => {
+                error!("{:?}", err);
+                self.plan.record_failed(entry, &err);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Returns the member of the room, which is None unless the user is
+    /// invited or joined. The room member list only has joined users, so the
+    /// membership event of the user is queried instead.
+    async fn get_room_member(&self, room_id: &str, user_id: &str) -> Result<Option<Resource>> {
+        let result: Result<RoomMembership> = self
+            .get(&[
+                "_matrix",
+                "client",
+                "v3",
+                "rooms",
+                room_id,
+                "state",
+                "m.room.member",
+                user_id,
+            ])
+            .await;
+        match result {
+            Ok(member) if ["invite", "join"].contains(&member.membership.as_str()) => {
+                Ok(Some(Resource::matrix_room_member(room_id, user_id)))
+            }
+            Ok(_) => Ok(None),
+            Err(err) => match error::classify(&err) {
+                ErrorClass::NotFound => Ok(None),
+                _ => Err(err.context(format!(
+                    "Failed to get membership of {} in {}",
+                    user_id, room_id
+                ))),
+            },
+        }
+    }
+
+    async fn update_team_room_members(
+        &self,
+        action: &Action,
+        state: &State,
+        team: &Team,
+        room_id: &str,
+        bot: &WhoAmI,
+    ) -> Result<()> {
+        debug!("Reconciling Matrix room {} of team {}", room_id, team.name);
+        let room_members: RoomMembers = self
+            .get(&["_synapse", "admin", "v1", "rooms", room_id, "members"])
+            .await
+            .with_context(|| format!("Failed to get members of Matrix room {}", room_id))?;
+
+        let label = format!("Matrix room '{}' members", room_id);
+        let mut summary = PlanSummary::new(&label);
+
+        let team_members: Vec<String> = state
+            .team_members(team)
+            .into_iter()
+            .map(|user| self.user_id(user))
+            .collect();
+
+        for user_id in &team_members {
+            if room_members.members.contains(user_id) {
+                continue;
+            }
+            let entry = PlanEntry::add(
+                Resource::matrix_room_member(room_id, user_id),
+                &format!("team {} mapping", team.name),
+            );
+            self.plan.record(&entry)?;
+            if self.plan.applies(action, &entry) {
+                let result = self
+                    .post(
+                        &["_matrix", "client", "v3", "rooms", room_id, "invite"],
+                        &MembershipRequest {
+                            user_id,
+                            reason: None,
+                        },
+                    )
+                    .await
+                    .with_context(|| format!("Failed to invite {} to {}", user_id, room_id));
+                if self.record_apply_result(&entry, result)? {
+                    let actual = self.get_room_member(room_id, user_id).await;
+                    self.plan.verify_applied(&entry, actual);
+                }
+            }
+            summary.add += 1;
+        }
+
+        for user_id in &room_members.members {
+            if user_id.eq(&bot.user_id) || team_members.contains(user_id) {
+                continue;
+            }
+            let entry = PlanEntry::destroy(
+                Resource::matrix_room_member(room_id, user_id),
+                RULE_TEAM_ROOMS_ONLY_TEAM_MEMBERS,
+            );
+            self.plan.record(&entry)?;
+            if self.plan.applies(action, &entry) {
+                let result = self
+                    .post(
+                        &["_matrix", "client", "v3", "rooms", room_id, "kick"],
+                        &MembershipRequest {
+                            user_id,
+                            reason: Some(KICK_REASON),
+                        },
+                    )
+                    .await
+                    .with_context(|| format!("Failed to kick {} from {}", user_id, room_id));
+                if self.record_apply_result(&entry, result)? {
+                    let actual = self.get_room_member(room_id, user_id).await;
+                    self.plan.verify_applied(&entry, actual);
+                }
+            }
+            summary.destroy += 1;
+        }
+
+        self.plan.finish_section(&summary)
+    }
+}
+
+#[async_trait(?Send)]
+impl Component for Matrix {
+    async fn run(&self, action: Action) -> Result<()> {
+        self.plan.start_component("Matrix");
+        let bot: WhoAmI = self
+            .get(&["_matrix", "client", "v3", "account", "whoami"])
+            .await
+            .context("Failed to authenticate against Matrix")?;
+
+        let state = self.state.lock().await;
+        for team_room in &self.team_rooms {
+            let team = match state.teams.iter().find(|team| team.name == team_room.team) {
+                Some(team) => team,
+                None => {
+                    warn!(
+                        "Unknown team {} for Matrix room {}",
+                        team_room.team, team_room.room_id
+                    );
+                    continue;
+                }
+            };
+            self.update_team_room_members(&action, &state, team, &team_room.room_id, &bot)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses `team=room_id` pairs separated with commas.
+fn parse_team_rooms(team_rooms: &str) -> Vec<TeamRoom> {
+    team_rooms
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(team, room_id)| (team.trim(), room_id.trim()))
+        .filter(|(team, room_id)| !team.is_empty() && !room_id.is_empty())
+        .map(|(team, room_id)| TeamRoom {
+            team: team.to_string(),
+            room_id: room_id.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("", vec![])]
+    #[case("DevOps=!abc:archlinux.org", vec![("DevOps", "!abc:archlinux.org")])]
+    #[case(
+        "Staff = !a:archlinux.org,broken, DevOps=!b:archlinux.org",
+        vec![("Staff", "!a:archlinux.org"), ("DevOps", "!b:archlinux.org")]
+    )]
+    fn parse_team_rooms_test(#[case] team_rooms: &str, #[case] expected: Vec<(&str, &str)>) {
+        let expected: Vec<TeamRoom> = expected
+            .into_iter()
+            .map(|(team, room_id)| TeamRoom {
+                team: team.to_string(),
+                room_id: room_id.to_string(),
+            })
+            .collect();
+        assert_eq!(parse_team_rooms(team_rooms), expected);
+    }
+}
@@ -0,0 +1,30 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize)]
+pub struct WhoAmI {
+    pub user_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoomMembers {
+    pub members: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoomMembership {
+    pub membership: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MembershipRequest<'a> {
+    pub user_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'a str>,
+}
+
+/// A private Matrix room or space reserved for the members of a team.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TeamRoom {
+    pub team: String,
+    pub room_id: String,
+}
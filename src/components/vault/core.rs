@@ -0,0 +1,307 @@
+//! This module defines Vault related actions and enforcements.
+//!
+//! ## Features
+//!
+//! - ensure the DevOps identity group only contains entities of DevOps members
+//! - ensure the DevOps identity group has the configured policies
+//! - remove OIDC entity aliases of everyone that is not a DevOps member anymore
+
+use crate::args::Action;
+use crate::components::vault::types::*;
+use crate::components::Component;
+use crate::error::{self, CheckStatus, ErrorClass};
+use crate::http_client;
+use crate::plan::{Plan, PlanEntry, PlanSummary, Resource};
+use crate::state::State;
+use crate::util;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use reqwest::{Client, Method, RequestBuilder, Url};
+use serde::de::DeserializeOwned;
+use tokio::sync::Mutex;
+use tracing::info;
+
+const SERVICE: &str = "Vault";
+const DEFAULT_DEVOPS_GROUP: &str = "devops";
+const RULE_DEVOPS_GROUP_MAPPING: &str = "team DevOps mapping";
+const RULE_DEVOPS_POLICIES: &str = "devops vault policies";
+const RULE_ONLY_DEVOPS_ALIASES: &str = "only devops may log in to vault";
+
+pub struct Vault {
+    client: Client,
+    url: Url,
+    token: String,
+    devops_group: String,
+    devops_policies: Vec<String>,
+    oidc_mount_accessor: String,
+    state: Arc<Mutex<State>>,
+    plan: Arc<Plan>,
+}
+
+impl Vault {
+    /// Returns None if no Vault is configured.
+    pub async fn new(
+        client: Client,
+        state: Arc<Mutex<State>>,
+        plan: Arc<Plan>,
+    ) -> Result<Option<Vault>> {
+        let url = match env::var("GLUEBUDDY_VAULT_URL") {
+            Ok(url) => url,
+            Err(_) => {
+                info!("GLUEBUDDY_VAULT_URL not set, skipping Vault");
+                return Ok(None);
+            }
+        };
+        let url = Url::parse(&url).with_context(|| format!("Invalid Vault url {}", url))?;
+        let token = util::secret("GLUEBUDDY_VAULT_TOKEN")?;
+        let oidc_mount_accessor = util::required_env("GLUEBUDDY_VAULT_OIDC_MOUNT_ACCESSOR")?;
+        let devops_group = env::var("GLUEBUDDY_VAULT_DEVOPS_GROUP")
+            .unwrap_or_else(|_| DEFAULT_DEVOPS_GROUP.to_string());
+        let mut devops_policies: Vec<String> = env::var("GLUEBUDDY_VAULT_DEVOPS_POLICIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(|policy| policy.trim().to_string())
+            .filter(|policy| !policy.is_empty())
+            .collect();
+        devops_policies.sort();
+
+        Ok(Some(Vault {
+            client,
+            url,
+            token,
+            devops_group,
+            devops_policies,
+            oidc_mount_accessor,
+            state,
+            plan,
+        }))
+    }
+
+    fn request(&self, method: Method, path: &[&str]) -> Result<RequestBuilder> {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("Vault url {} can not be a base", self.url))?
+            .pop_if_empty()
+            .push("v1")
+            .extend(path);
+        Ok(self
+            .client
+            .request(method, url)
+            .header("X-Vault-Token", &self.token))
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &[&str], query: &[(&str, &str)]) -> Result<T> {
+        let response: Response<T> =
+            http_client::send(self.request(Method::GET, path)?.query(query))
+                .await?
+                .check_status(SERVICE)?
+                .json()
+                .await?;
+        Ok(response.data)
+    }
+
+    async fn get_entity_alias(&self, alias: &EntityAlias) -> Result<Option<Resource>> {
+        let result: Result<EntityAlias> = self
+            .get(&["identity", "entity-alias", "id", &alias.id], &[])
+            .await;
+        match result {
+            Ok(actual) => Ok(Some(Resource::vault_entity_alias(&actual.name, &actual.id))),
+            Err(err) => match error::classify(&err) {
+                ErrorClass::NotFound => Ok(None),
+                _ => Err(err.context(format!("Failed to get Vault alias of {}", alias.name))),
+            },
+        }
+    }
+
+    async fn update_devops_group(
+        &self,
+        action: &Action,
+        devops: &[String],
+        entity_ids: &BTreeMap<&str, &str>,
+    ) -> Result<()> {
+        let group: IdentityGroup = self
+            .get(&["identity", "group", "name", &self.devops_group], &[])
+            .await
+            .with_context(|| format!("Failed to get Vault group {}", self.devops_group))?;
+
+        let label = format!("Vault '{}' group", group.name);
+        let mut summary = PlanSummary::new(&label);
+        let mut entries = vec![];
+        // Entity of every entry, None for the policies
+        let mut entities: Vec<Option<(&str, &str)>> = vec![];
+
+        let mut expected_members: Vec<String> = devops
+            .iter()
+            .filter_map(|username| entity_ids.get(username.as_str()))
+            .map(|entity_id| entity_id.to_string())
+            .collect();
+        expected_members.sort();
+
+        for (username, entity_id) in entity_ids {
+            let expected = devops.iter().any(|devops| devops.eq(username));
+            let member = group.member_entity_ids.iter().any(|id| id.eq(entity_id));
+            let resource = Resource::vault_group_member(&group.name, username, entity_id);
+            match (expected, member) {
+                (true, false) => {
+                    entries.push(PlanEntry::add(resource, RULE_DEVOPS_GROUP_MAPPING));
+                    entities.push(Some((username, entity_id)));
+                    summary.add += 1;
+                }
+                (false, true) => {
+                    entries.push(PlanEntry::destroy(resource, RULE_DEVOPS_GROUP_MAPPING));
+                    entities.push(Some((username, entity_id)));
+                    summary.destroy += 1;
+                }
+                _ => {}
+            }
+        }
+        // Members without an OIDC alias are not backed by any Keycloak user
+        for entity_id in &group.member_entity_ids {
+            if !entity_ids.values().any(|id| id.eq(entity_id)) {
+                entries.push(PlanEntry::destroy(
+                    Resource::vault_group_member(&group.name, "", entity_id),
+                    RULE_DEVOPS_GROUP_MAPPING,
+                ));
+                entities.push(Some(("", entity_id)));
+                summary.destroy += 1;
+            }
+        }
+
+        let mut policies = group.policies.clone();
+        policies.sort();
+        if !policies.eq(&self.devops_policies) {
+            entries.push(PlanEntry::change(
+                Resource::vault_group_policies(&group.name, &policies),
+                Resource::vault_group_policies(&group.name, &self.devops_policies),
+                RULE_DEVOPS_POLICIES,
+            ));
+            entities.push(None);
+            summary.change += 1;
+        }
+
+        for entry in &entries {
+            self.plan.record(entry)?;
+        }
+        if self.plan.applies_all(action, &entries) && !entries.is_empty() {
+            http_client::send(
+                self.request(Method::POST, &["identity", "group", "id", &group.id])?
+                    .json(&IdentityGroupUpdate {
+                        policies: &self.devops_policies,
+                        member_entity_ids: &expected_members,
+                    }),
+            )
+            .await?
+            .check_status(SERVICE)
+            .with_context(|| format!("Failed to update Vault group {}", group.name))?;
+            for entry in &entries {
+                self.plan.record_applied(entry)?;
+            }
+            let actual: Result<IdentityGroup> = self
+                .get(&["identity", "group", "id", &group.id], &[])
+                .await
+                .with_context(|| format!("Failed to get Vault group {}", group.name));
+            for (entry, entity) in entries.iter().zip(&entities) {
+                let actual = match &actual {
+                    Ok(actual) => Ok(group_resource(actual, *entity)),
+                    Err(err) => Err(anyhow!("{:#}", err)),
+                };
+                self.plan.verify_applied(entry, actual);
+            }
+        }
+
+        self.plan.finish_section(&summary)
+    }
+
+    async fn remove_departed_aliases(
+        &self,
+        action: &Action,
+        devops: &[String],
+        aliases: &[&EntityAlias],
+    ) -> Result<()> {
+        let mut summary = PlanSummary::new("Vault OIDC entity aliases");
+        for alias in aliases {
+            if devops.iter().any(|username| username.eq(&alias.name)) {
+                continue;
+            }
+            let entry = PlanEntry::destroy(
+                Resource::vault_entity_alias(&alias.name, &alias.id),
+                RULE_ONLY_DEVOPS_ALIASES,
+            );
+            self.plan.record(&entry)?;
+            if self.plan.applies(action, &entry) {
+                http_client::send(self.request(
+                    Method::DELETE,
+                    &["identity", "entity-alias", "id", &alias.id],
+                )?)
+                .await?
+                .check_status(SERVICE)
+                .with_context(|| format!("Failed to delete Vault alias of {}", alias.name))?;
+                self.plan.record_applied(&entry)?;
+                let actual = self.get_entity_alias(alias).await;
+                self.plan.verify_applied(&entry, actual);
+            }
+            summary.destroy += 1;
+        }
+        self.plan.finish_section(&summary)
+    }
+}
+
+/// Returns the resource of a planned group entry in the group, the policies
+/// if no entity is given.
+fn group_resource(group: &IdentityGroup, entity: Option<(&str, &str)>) -> Option<Resource> {
+    match entity {
+        Some((username, entity_id)) => group
+            .member_entity_ids
+            .iter()
+            .any(|id| id.eq(entity_id))
+            .then(|| Resource::vault_group_member(&group.name, username, entity_id)),
+        None => {
+            let mut policies = group.policies.clone();
+            policies.sort();
+            Some(Resource::vault_group_policies(&group.name, &policies))
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Component for Vault {
+    async fn run(&self, action: Action) -> Result<()> {
+        self.plan.start_component("Vault");
+
+        let entities: EntityList = self
+            .get(&["identity", "entity", "id"], &[("list", "true")])
+            .await
+            .context("Failed to list Vault entities")?;
+        let devops: Vec<String> = self
+            .state
+            .lock()
+            .await
+            .devops()
+            .into_iter()
+            .map(|user| user.username.clone())
+            .collect();
+
+        // Entity ids by the name of their OIDC alias
+        let mut entity_ids: BTreeMap<&str, &str> = BTreeMap::new();
+        let mut aliases: Vec<&EntityAlias> = vec![];
+        for (entity_id, entity) in &entities.key_info {
+            for alias in &entity.aliases {
+                if alias.mount_accessor.eq(&self.oidc_mount_accessor) {
+                    entity_ids.insert(&alias.name, entity_id);
+                    aliases.push(alias);
+                }
+            }
+        }
+
+        self.update_devops_group(&action, &devops, &entity_ids)
+            .await?;
+        self.remove_departed_aliases(&action, &devops, &aliases)
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize)]
+pub struct Response<T> {
+    pub data: T,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IdentityGroup {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub policies: Vec<String>,
+    #[serde(default)]
+    pub member_entity_ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IdentityGroupUpdate<'a> {
+    pub policies: &'a [String],
+    pub member_entity_ids: &'a [String],
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EntityList {
+    pub key_info: HashMap<String, EntityInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EntityInfo {
+    pub name: String,
+    #[serde(default)]
+    pub aliases: Vec<EntityAlias>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EntityAlias {
+    pub id: String,
+    pub name: String,
+    pub mount_accessor: String,
+}
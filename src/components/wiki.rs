@@ -0,0 +1,4 @@
+pub mod core;
+pub mod types;
+
+pub use crate::components::wiki::core::Wiki;
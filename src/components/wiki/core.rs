@@ -0,0 +1,320 @@
+//! This module defines ArchWiki related actions and enforcements.
+//!
+//! ## Features
+//!
+//! - ensure privileged wiki groups only contain members of the mapped Keycloak group
+//! - add members of the mapped Keycloak group to the privileged wiki group
+
+use crate::args::Action;
+use crate::components::wiki::types::*;
+use crate::components::Component;
+use crate::error::CheckStatus;
+use crate::http_client;
+use crate::plan::{Plan, PlanEntry, PlanSummary, Resource};
+use crate::state::{State, User};
+use crate::util;
+
+use std::env;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use reqwest::{Client, Url};
+use serde_json::Value;
+use tokio::sync::Mutex;
+use tracing::{debug, info};
+
+const SERVICE: &str = "Wiki";
+const WIKI_USERNAME_ATTRIBUTE: &str = "wiki_username";
+const RULE_WIKI_GROUPS_VIA_KEYCLOAK: &str =
+    "privileged wiki groups are granted via keycloak groups";
+
+pub struct Wiki {
+    client: Client,
+    url: Url,
+    token: String,
+    groups: Vec<WikiGroup>,
+    bot_users: Vec<String>,
+    state: Arc<Mutex<State>>,
+    plan: Arc<Plan>,
+}
+
+impl Wiki {
+    /// Returns None if no wiki is configured.
+    pub async fn new(
+        client: Client,
+        state: Arc<Mutex<State>>,
+        plan: Arc<Plan>,
+    ) -> Result<Option<Wiki>> {
+        let url = match env::var("GLUEBUDDY_WIKI_API_URL") {
+            Ok(url) => url,
+            Err(_) => {
+                info!("GLUEBUDDY_WIKI_API_URL not set, skipping wiki");
+                return Ok(None);
+            }
+        };
+        let url = Url::parse(&url).with_context(|| format!("Invalid wiki api url {}", url))?;
+        let token = util::secret("GLUEBUDDY_WIKI_TOKEN")?;
+        let groups = parse_wiki_groups(&env::var("GLUEBUDDY_WIKI_GROUPS").unwrap_or_default());
+        let bot_users = env::var("GLUEBUDDY_WIKI_BOT_USERS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|user| user.trim().to_string())
+            .filter(|user| !user.is_empty())
+            .collect();
+
+        Ok(Some(Wiki {
+            client,
+            url,
+            token,
+            groups,
+            bot_users,
+            state,
+            plan,
+        }))
+    }
+
+    async fn get_group_members(&self, group: &str) -> Result<Vec<String>> {
+        let mut members = vec![];
+        let mut from: Option<String> = None;
+        loop {
+            let mut query = vec![
+                ("action", "query"),
+                ("list", "allusers"),
+                ("augroup", group),
+                ("aulimit", "max"),
+                ("format", "json"),
+            ];
+            if let Some(from) = &from {
+                query.push(("aufrom", from.as_str()));
+            }
+            let response: AllUsersResponse = http_client::send(
+                self.client
+                    .get(self.url.clone())
+                    .bearer_auth(&self.token)
+                    .query(&query),
+            )
+            .await?
+            .check_status(SERVICE)?
+            .json()
+            .await?;
+            members.extend(response.query.allusers.into_iter().map(|user| user.name));
+            match response.continuation {
+                Some(continuation) => from = Some(continuation.aufrom),
+                None => return Ok(members),
+            }
+        }
+    }
+
+    async fn userrights_token(&self) -> Result<String> {
+        let response: TokensResponse = http_client::send(
+            self.client
+                .get(self.url.clone())
+                .bearer_auth(&self.token)
+                .query(&[
+                    ("action", "query"),
+                    ("meta", "tokens"),
+                    ("type", "userrights"),
+                    ("format", "json"),
+                ]),
+        )
+        .await?
+        .check_status(SERVICE)?
+        .json()
+        .await
+        .context("Failed to get wiki userrights token")?;
+        Ok(response.query.tokens.userrightstoken)
+    }
+
+    async fn change_user_rights(&self, username: &str, change: &str, group: &str) -> Result<()> {
+        let token = self.userrights_token().await?;
+        let response: Value = http_client::send(
+            self.client
+                .post(self.url.clone())
+                .bearer_auth(&self.token)
+                .form(&[
+                    ("action", "userrights"),
+                    ("user", username),
+                    (change, group),
+                    ("reason", "Synchronized with Keycloak by gluebuddy"),
+                    ("token", token.as_str()),
+                    ("format", "json"),
+                ]),
+        )
+        .await?
+        .check_status(SERVICE)?
+        .json()
+        .await?;
+        if let Some(error) = response.get("error") {
+            bail!("Failed to change wiki rights of {}: {}", username, error);
+        }
+        Ok(())
+    }
+
+    async fn get_group_member(&self, group: &str, username: &str) -> Result<Option<Resource>> {
+        let members = self
+            .get_group_members(group)
+            .await
+            .with_context(|| format!("Failed to get members of wiki group {}", group))?;
+        Ok(members
+            .iter()
+            .any(|member| member.eq(username))
+            .then(|| Resource::wiki_group_member(group, username)))
+    }
+
+    async fn update_group_members(
+        &self,
+        action: &Action,
+        state: &State,
+        group: &WikiGroup,
+    ) -> Result<()> {
+        debug!(
+            "Reconciling wiki group {} with {}",
+            group.wiki_group, group.keycloak_group
+        );
+        let members = self
+            .get_group_members(&group.wiki_group)
+            .await
+            .with_context(|| format!("Failed to get members of wiki group {}", group.wiki_group))?;
+
+        let label = format!("Wiki '{}' group members", group.wiki_group);
+        let mut summary = PlanSummary::new(&label);
+
+        let expected: Vec<String> = state
+            .users
+            .values()
+            .filter(|user| {
+                user.enabled
+                    && user
+                        .groups
+                        .iter()
+                        .any(|keycloak_group| keycloak_group.starts_with(&group.keycloak_group))
+            })
+            .map(wiki_username)
+            .collect();
+
+        for username in &expected {
+            if members.contains(username) {
+                continue;
+            }
+            let entry = PlanEntry::add(
+                Resource::wiki_group_member(&group.wiki_group, username),
+                RULE_WIKI_GROUPS_VIA_KEYCLOAK,
+            );
+            self.plan.record(&entry)?;
+            if self.plan.applies(action, &entry) {
+                self.change_user_rights(username, "add", &group.wiki_group)
+                    .await?;
+                self.plan.record_applied(&entry)?;
+                let actual = self.get_group_member(&group.wiki_group, username).await;
+                self.plan.verify_applied(&entry, actual);
+            }
+            summary.add += 1;
+        }
+
+        for username in &members {
+            if expected.contains(username) || self.bot_users.contains(username) {
+                continue;
+            }
+            let entry = PlanEntry::destroy(
+                Resource::wiki_group_member(&group.wiki_group, username),
+                RULE_WIKI_GROUPS_VIA_KEYCLOAK,
+            );
+            self.plan.record(&entry)?;
+            if self.plan.applies(action, &entry) {
+                self.change_user_rights(username, "remove", &group.wiki_group)
+                    .await?;
+                self.plan.record_applied(&entry)?;
+                let actual = self.get_group_member(&group.wiki_group, username).await;
+                self.plan.verify_applied(&entry, actual);
+            }
+            summary.destroy += 1;
+        }
+
+        self.plan.finish_section(&summary)
+    }
+}
+
+#[async_trait(?Send)]
+impl Component for Wiki {
+    async fn run(&self, action: Action) -> Result<()> {
+        self.plan.start_component("Wiki");
+        let state = self.state.lock().await;
+        for group in &self.groups {
+            self.update_group_members(&action, &state, group).await?;
+        }
+        Ok(())
+    }
+}
+
+/// The wiki username from the attribute, otherwise the username with the
+/// first letter capitalized like MediaWiki normalizes it.
+fn wiki_username(user: &User) -> String {
+    if let Some(username) = user.attribute(WIKI_USERNAME_ATTRIBUTE) {
+        return username.to_string();
+    }
+    let mut chars = user.username.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Parses `wiki_group=keycloak_group` pairs separated with commas.
+fn parse_wiki_groups(groups: &str) -> Vec<WikiGroup> {
+    groups
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(wiki_group, keycloak_group)| (wiki_group.trim(), keycloak_group.trim()))
+        .filter(|(wiki_group, keycloak_group)| !wiki_group.is_empty() && !keycloak_group.is_empty())
+        .map(|(wiki_group, keycloak_group)| WikiGroup {
+            wiki_group: wiki_group.to_string(),
+            keycloak_group: keycloak_group.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("alice", None, "Alice")]
+    #[case("alice", Some("Alice Wonder"), "Alice Wonder")]
+    #[case("Bob", None, "Bob")]
+    fn wiki_username_test(
+        #[case] username: &str,
+        #[case] attribute: Option<&str>,
+        #[case] expected: &str,
+    ) {
+        let mut user = User::new(username.to_string());
+        if let Some(attribute) = attribute {
+            user.attributes.insert(
+                WIKI_USERNAME_ATTRIBUTE.to_string(),
+                vec![attribute.to_string()],
+            );
+        }
+        assert_eq!(wiki_username(&user), expected);
+    }
+
+    #[rstest]
+    #[case("", vec![])]
+    #[case(
+        "sysop=/Arch Linux Staff/Wiki Admins, maintainer = /Arch Linux Staff/Wiki Maintainers,broken",
+        vec![
+            ("sysop", "/Arch Linux Staff/Wiki Admins"),
+            ("maintainer", "/Arch Linux Staff/Wiki Maintainers"),
+        ]
+    )]
+    fn parse_wiki_groups_test(#[case] groups: &str, #[case] expected: Vec<(&str, &str)>) {
+        let expected: Vec<WikiGroup> = expected
+            .into_iter()
+            .map(|(wiki_group, keycloak_group)| WikiGroup {
+                wiki_group: wiki_group.to_string(),
+                keycloak_group: keycloak_group.to_string(),
+            })
+            .collect();
+        assert_eq!(parse_wiki_groups(groups), expected);
+    }
+}
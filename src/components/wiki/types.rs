@@ -0,0 +1,45 @@
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct AllUsersResponse {
+    pub query: AllUsersQuery,
+    #[serde(rename = "continue")]
+    pub continuation: Option<AllUsersContinue>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AllUsersQuery {
+    pub allusers: Vec<WikiUser>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AllUsersContinue {
+    pub aufrom: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WikiUser {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokensResponse {
+    pub query: TokensQuery,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokensQuery {
+    pub tokens: Tokens,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Tokens {
+    pub userrightstoken: String,
+}
+
+/// A privileged wiki group reserved for the members of a Keycloak group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WikiGroup {
+    pub wiki_group: String,
+    pub keycloak_group: String,
+}
@@ -0,0 +1,229 @@
+//! This module defines the errors the runner classifies to choose the exit
+//! code. Components keep using anyhow for context, but raise these errors at
+//! the root of the chain where the cause is known.
+
+use crate::components::gitlab::throttle::GitLabClientError;
+
+use gitlab::api::ApiError;
+use reqwest::{Response, StatusCode};
+use thiserror::Error;
+
+/// Errors of the configuration, detected before talking to any service.
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Missing env var {0}")]
+    Missing(String),
+    #[error("Missing secret {0}, set {0} or {0}_FILE or pass it as systemd credential")]
+    MissingSecret(String),
+    #[error("Invalid {name}: {reason}")]
+    Invalid { name: String, reason: String },
+}
+
+/// Errors of a service a component talks to.
+#[derive(Debug, Error)]
+pub enum ServiceError {
+    #[error("{service} rejected the credentials with {status} for {url}")]
+    Auth {
+        service: &'static str,
+        status: StatusCode,
+        url: String,
+    },
+    #[error("{service} rate limit exceeded for {url}")]
+    RateLimit { service: &'static str, url: String },
+    #[error("{service} has no {url}")]
+    NotFound { service: &'static str, url: String },
+    #[error("{service} is unavailable with {status} for {url}")]
+    Unavailable {
+        service: &'static str,
+        status: StatusCode,
+        url: String,
+    },
+    #[error("{service} failed with {status} for {url}")]
+    Failed {
+        service: &'static str,
+        status: StatusCode,
+        url: String,
+    },
+}
+
+/// Exit code of runs in which some changes failed to apply or verify.
+pub const PARTIAL_APPLY_EXIT_CODE: i32 = 7;
+/// Exit code of runs that planned changes, with `--fail-on-drift`.
+pub const DRIFT_EXIT_CODE: i32 = 8;
+/// Exit code of runs with any failure, with `--detailed-exitcode`.
+pub const DETAILED_ERROR_EXIT_CODE: i32 = 1;
+/// Exit code of runs that planned changes, with `--detailed-exitcode`.
+pub const DETAILED_DRIFT_EXIT_CODE: i32 = 2;
+
+/// The class of a failure, each with its own exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    Other,
+    Config,
+    Auth,
+    RateLimit,
+    NotFound,
+    /// The network or the service failed, likely to pass on its own
+    Unavailable,
+}
+
+impl ErrorClass {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorClass::Other => 1,
+            ErrorClass::Config => 2,
+            ErrorClass::Auth => 3,
+            ErrorClass::RateLimit => 4,
+            ErrorClass::NotFound => 5,
+            ErrorClass::Unavailable => 6,
+        }
+    }
+
+    fn from_status(status: StatusCode) -> ErrorClass {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ErrorClass::Auth,
+            StatusCode::TOO_MANY_REQUESTS => ErrorClass::RateLimit,
+            StatusCode::NOT_FOUND => ErrorClass::NotFound,
+            status if status.is_server_error() => ErrorClass::Unavailable,
+            _ => ErrorClass::Other,
+        }
+    }
+}
+
+impl ServiceError {
+    pub fn from_status(service: &'static str, status: StatusCode, url: String) -> ServiceError {
+        match ErrorClass::from_status(status) {
+            ErrorClass::Auth => ServiceError::Auth {
+                service,
+                status,
+                url,
+            },
+            ErrorClass::RateLimit => ServiceError::RateLimit { service, url },
+            ErrorClass::NotFound => ServiceError::NotFound { service, url },
+            ErrorClass::Unavailable => ServiceError::Unavailable {
+                service,
+                status,
+                url,
+            },
+            _ => ServiceError::Failed {
+                service,
+                status,
+                url,
+            },
+        }
+    }
+
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            ServiceError::Auth { .. } => ErrorClass::Auth,
+            ServiceError::RateLimit { .. } => ErrorClass::RateLimit,
+            ServiceError::NotFound { .. } => ErrorClass::NotFound,
+            ServiceError::Unavailable { .. } => ErrorClass::Unavailable,
+            ServiceError::Failed { .. } => ErrorClass::Other,
+        }
+    }
+}
+
+/// Turns unsuccessful responses into a [`ServiceError`] of the service.
+pub trait CheckStatus: Sized {
+    fn check_status(self, service: &'static str) -> Result<Self, ServiceError>;
+}
+
+impl CheckStatus for Response {
+    fn check_status(self, service: &'static str) -> Result<Self, ServiceError> {
+        let status = self.status();
+        if status.is_client_error() || status.is_server_error() {
+            return Err(ServiceError::from_status(
+                service,
+                status,
+                self.url().to_string(),
+            ));
+        }
+        Ok(self)
+    }
+}
+
+/// Classifies an error by the first cause in its chain that is known.
+pub fn classify(error: &anyhow::Error) -> ErrorClass {
+    for cause in error.chain() {
+        if cause.is::<ConfigError>() {
+            return ErrorClass::Config;
+        }
+        if let Some(err) = cause.downcast_ref::<ServiceError>() {
+            return err.class();
+        }
+        if let Some(err) = cause.downcast_ref::<ApiError<GitLabClientError>>() {
+            return classify_gitlab(err);
+        }
+        if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            if err.is_timeout() || err.is_connect() {
+                return ErrorClass::Unavailable;
+            }
+        }
+    }
+    ErrorClass::Other
+}
+
+/// GitLab errors only carry the status if the body was no JSON, otherwise the
+/// message starts with it, e.g. `404 Group Not Found`.
+fn classify_gitlab(error: &ApiError<GitLabClientError>) -> ErrorClass {
+    match error {
+        ApiError::GitlabService { status, .. } => ErrorClass::from_status(*status),
+        ApiError::Gitlab { msg } => msg
+            .split_whitespace()
+            .next()
+            .and_then(|code| code.parse::<u16>().ok())
+            .and_then(|code| StatusCode::from_u16(code).ok())
+            .map_or(ErrorClass::Other, ErrorClass::from_status),
+        ApiError::Client {
+            source: GitLabClientError::Communication(_),
+        } => ErrorClass::Unavailable,
+        _ => ErrorClass::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(StatusCode::UNAUTHORIZED, ErrorClass::Auth)]
+    #[case(StatusCode::FORBIDDEN, ErrorClass::Auth)]
+    #[case(StatusCode::TOO_MANY_REQUESTS, ErrorClass::RateLimit)]
+    #[case(StatusCode::NOT_FOUND, ErrorClass::NotFound)]
+    #[case(StatusCode::SERVICE_UNAVAILABLE, ErrorClass::Unavailable)]
+    #[case(StatusCode::CONFLICT, ErrorClass::Other)]
+    fn classify_service_error_test(#[case] status: StatusCode, #[case] expected: ErrorClass) {
+        let error = Err::<(), _>(ServiceError::from_status(
+            "Grafana",
+            status,
+            "https://grafana.archlinux.org/api/teams".to_string(),
+        ))
+        .context("Failed to list Grafana teams")
+        .unwrap_err();
+        assert_eq!(classify(&error), expected);
+    }
+
+    #[rstest]
+    #[case("404 Group Not Found", ErrorClass::NotFound)]
+    #[case("403 Forbidden", ErrorClass::Auth)]
+    #[case("502 Bad Gateway", ErrorClass::Unavailable)]
+    #[case("something went wrong", ErrorClass::Other)]
+    fn classify_gitlab_test(#[case] msg: &str, #[case] expected: ErrorClass) {
+        let error = ApiError::<GitLabClientError>::Gitlab {
+            msg: msg.to_string(),
+        };
+        assert_eq!(classify(&anyhow::Error::new(error)), expected);
+    }
+
+    #[test]
+    fn classify_config_error_test() {
+        let error = Err::<(), _>(ConfigError::Missing("GLUEBUDDY_GITLAB_TOKEN".to_string()))
+            .context("Failed to set up GitLab")
+            .unwrap_err();
+        assert_eq!(classify(&error), ErrorClass::Config);
+        assert_eq!(classify(&error).exit_code(), 2);
+    }
+}
@@ -0,0 +1,108 @@
+//! This module writes a machine readable stream of plan and apply events,
+//! one JSON object per line, for consumption by other tooling.
+
+use crate::plan::PlanEntry;
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind<'a> {
+    GatherStarted,
+    GatherFinished,
+    ResourcePlanned {
+        entry: &'a PlanEntry,
+    },
+    ResourceApplied {
+        resource_type: &'a str,
+        address: &'a str,
+    },
+    ResourceFailed {
+        resource_type: &'a str,
+        address: &'a str,
+        message: String,
+    },
+    ResourceVerificationFailed {
+        resource_type: &'a str,
+        address: &'a str,
+        message: String,
+    },
+    SectionFinished {
+        section: &'a str,
+        add: u64,
+        change: u64,
+        destroy: u64,
+    },
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct Event<'a> {
+    timestamp: String,
+    component: &'a str,
+    #[serde(flatten)]
+    kind: EventKind<'a>,
+}
+
+pub struct EventLog {
+    file: Mutex<File>,
+}
+
+impl EventLog {
+    pub fn create(path: &Path) -> Result<EventLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .with_context(|| format!("Failed to open event log {}", path.display()))?;
+        Ok(EventLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn emit(&self, component: &str, kind: EventKind) -> Result<()> {
+        let event = Event {
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            component,
+            kind,
+        };
+        let mut line = serde_json::to_string(&event)?;
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())
+            .context("Failed to write event log")?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_event_test() {
+        let event = Event {
+            timestamp: "2022-01-01T00:00:00Z".to_string(),
+            component: "GitLab",
+            kind: EventKind::ResourceApplied {
+                resource_type: "gitlab_member_access",
+                address: "archlinux:alice",
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"timestamp":"2022-01-01T00:00:00Z","component":"GitLab","event":"resource_applied","resource_type":"gitlab_member_access","address":"archlinux:alice"}"#
+        );
+    }
+}
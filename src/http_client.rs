@@ -0,0 +1,144 @@
+//! This module builds the HTTP client shared by all components that talk to
+//! services directly, so that connections are pooled and every request is
+//! sent with the same settings. It also defines the timeout and retry policy
+//! that applies to all clients, including the GitLab one.
+
+use std::env;
+use std::time::Duration;
+
+use crate::metrics;
+
+use anyhow::{Context, Result};
+use reqwest::{Client, ClientBuilder, Method, Proxy, RequestBuilder, Response, StatusCode};
+use tracing::{debug, warn};
+
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Attempts per request including the first one.
+pub const MAX_ATTEMPTS: u32 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Returns a builder with the shared settings, for clients that need
+/// additional TLS settings. Proxies are taken from `HTTPS_PROXY`, `HTTP_PROXY`
+/// and `NO_PROXY` unless `GLUEBUDDY_PROXY` sets one for all requests.
+pub fn builder() -> Result<ClientBuilder> {
+    let builder = Client::builder()
+        .user_agent(USER_AGENT)
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .tcp_keepalive(TCP_KEEPALIVE)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT);
+    Ok(match env::var("GLUEBUDDY_PROXY") {
+        Ok(proxy) => builder.proxy(Proxy::all(&proxy).context("Invalid GLUEBUDDY_PROXY")?),
+        Err(_) => builder,
+    })
+}
+
+/// Builds the shared client. HTTP/2 is negotiated via ALPN where supported.
+pub fn build() -> Result<Client> {
+    builder()?.build().context("Failed to build HTTP client")
+}
+
+/// Whether the request can be sent again without applying it twice.
+pub fn is_idempotent(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD)
+}
+
+/// Whether a response with the status is worth retrying. Writes are only
+/// retried on statuses that reject them before any processing, so that a
+/// write whose response got lost is not applied twice.
+pub fn is_retryable_status(method: &Method, status: StatusCode) -> bool {
+    match is_idempotent(method) {
+        true => matches!(
+            status,
+            StatusCode::TOO_MANY_REQUESTS
+                | StatusCode::BAD_GATEWAY
+                | StatusCode::SERVICE_UNAVAILABLE
+                | StatusCode::GATEWAY_TIMEOUT
+        ),
+        false => matches!(
+            status,
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+        ),
+    }
+}
+
+/// Exponential backoff before the given retry, starting with 1.
+pub fn backoff(retry: u32) -> Duration {
+    INITIAL_BACKOFF * 2u32.pow(retry.saturating_sub(1))
+}
+
+/// Sends the request, retrying reads on timeouts, connection errors and
+/// retryable statuses, and writes only on statuses that reject them before
+/// any processing. Requests with a streaming body can not be retried.
+pub async fn send(request: RequestBuilder) -> Result<Response> {
+    let method = match request.try_clone().and_then(|request| request.build().ok()) {
+        Some(request) => {
+            debug!("{} {}", request.method(), request.url());
+            request.method().clone()
+        }
+        None => return Ok(request.send().await?),
+    };
+    let mut attempt = 1;
+    loop {
+        metrics::count_request();
+        let current = match request.try_clone() {
+            Some(current) if attempt < MAX_ATTEMPTS => current,
+            _ => return Ok(request.send().await?),
+        };
+        match current.send().await {
+            Ok(response) if !is_retryable_status(&method, response.status()) => {
+                return Ok(response)
+            }
+            Ok(response) => warn!(
+                "{} returned {}, retrying in {:?}",
+                response.url(),
+                response.status(),
+                backoff(attempt)
+            ),
+            Err(err) if is_idempotent(&method) && (err.is_timeout() || err.is_connect()) => {
+                warn!("{}, retrying in {:?}", err, backoff(attempt))
+            }
+            Err(err) => return Err(err.into()),
+        }
+        tokio::time::sleep(backoff(attempt)).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(1, Duration::from_secs(1))]
+    #[case(2, Duration::from_secs(2))]
+    #[case(3, Duration::from_secs(4))]
+    fn backoff_test(#[case] retry: u32, #[case] expected: Duration) {
+        assert_eq!(backoff(retry), expected);
+    }
+
+    #[rstest]
+    #[case(Method::GET, StatusCode::OK, false)]
+    #[case(Method::GET, StatusCode::NOT_FOUND, false)]
+    #[case(Method::GET, StatusCode::INTERNAL_SERVER_ERROR, false)]
+    #[case(Method::GET, StatusCode::TOO_MANY_REQUESTS, true)]
+    #[case(Method::GET, StatusCode::SERVICE_UNAVAILABLE, true)]
+    #[case(Method::GET, StatusCode::GATEWAY_TIMEOUT, true)]
+    #[case(Method::POST, StatusCode::TOO_MANY_REQUESTS, true)]
+    #[case(Method::PUT, StatusCode::SERVICE_UNAVAILABLE, true)]
+    #[case(Method::POST, StatusCode::BAD_GATEWAY, false)]
+    #[case(Method::DELETE, StatusCode::GATEWAY_TIMEOUT, false)]
+    fn is_retryable_status_test(
+        #[case] method: Method,
+        #[case] status: StatusCode,
+        #[case] expected: bool,
+    ) {
+        assert_eq!(is_retryable_status(&method, status), expected);
+    }
+}
@@ -0,0 +1,122 @@
+//! This module renders the enforcement checks of a run as a JUnit XML report,
+//! so CI can display drift as failing test cases.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestCase {
+    /// The component that ran the check
+    pub classname: String,
+    /// The section name, describing the checked resource
+    pub name: String,
+    /// One message per drifted resource
+    pub failures: Vec<String>,
+    pub error: Option<String>,
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn render(cases: &[TestCase]) -> String {
+    let failures = cases
+        .iter()
+        .filter(|case| !case.failures.is_empty())
+        .count();
+    let errors = cases.iter().filter(|case| case.error.is_some()).count();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuite name=\"gluebuddy\" tests=\"{}\" failures=\"{}\" errors=\"{}\">\n",
+        cases.len(),
+        failures,
+        errors
+    ));
+    for case in cases {
+        xml.push_str(&format!(
+            "  <testcase classname=\"{}\" name=\"{}\"",
+            escape(&case.classname),
+            escape(&case.name)
+        ));
+        if case.failures.is_empty() && case.error.is_none() {
+            xml.push_str("/>\n");
+            continue;
+        }
+        xml.push_str(">\n");
+        if !case.failures.is_empty() {
+            xml.push_str(&format!(
+                "    <failure message=\"{} resources drifted\">{}</failure>\n",
+                case.failures.len(),
+                escape(&case.failures.join("\n"))
+            ));
+        }
+        if let Some(error) = &case.error {
+            xml.push_str(&format!("    <error message=\"{}\"/>\n", escape(error)));
+        }
+        xml.push_str("  </testcase>\n");
+    }
+    xml.push_str("</testsuite>\n");
+    xml
+}
+
+pub fn write(path: &Path, cases: &[TestCase]) -> Result<()> {
+    fs::write(path, render(cases))
+        .with_context(|| format!("Failed to write JUnit report {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_test() {
+        let cases = vec![
+            TestCase {
+                classname: "GitLab".to_string(),
+                name: "GitLab 'archlinux' group members".to_string(),
+                failures: vec![],
+                error: None,
+            },
+            TestCase {
+                classname: "GitLab".to_string(),
+                name: "GitLab 'archlinux/teams/staff' group members".to_string(),
+                failures: vec!["destroy archlinux/teams/staff:bob (team Staff mapping)".to_string()],
+                error: None,
+            },
+            TestCase {
+                classname: "GitLab".to_string(),
+                name: "error".to_string(),
+                failures: vec![],
+                error: Some("Failed to get <members>".to_string()),
+            },
+        ];
+
+        assert_eq!(
+            render(&cases),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+            <testsuite name=\"gluebuddy\" tests=\"3\" failures=\"1\" errors=\"1\">\n  \
+            <testcase classname=\"GitLab\" name=\"GitLab &apos;archlinux&apos; group members\"/>\n  \
+            <testcase classname=\"GitLab\" name=\"GitLab &apos;archlinux/teams/staff&apos; group members\">\n    \
+            <failure message=\"1 resources drifted\">destroy archlinux/teams/staff:bob (team Staff mapping)</failure>\n  \
+            </testcase>\n  \
+            <testcase classname=\"GitLab\" name=\"error\">\n    \
+            <error message=\"Failed to get &lt;members&gt;\"/>\n  \
+            </testcase>\n\
+            </testsuite>\n"
+        );
+    }
+}
@@ -0,0 +1,179 @@
+//! This module sets up logging to the console and optionally to a rotating
+//! log file that receives full debug output independent of the verbosity.
+
+use crate::util;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use clap::ArgEnum;
+use tracing::Level;
+use tracing_subscriber::filter::{EnvFilter, Targets};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{Layer, Registry};
+
+const LOG_FILE_ROTATIONS: u32 = 5;
+
+/// Format of the console and log file output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per event, carrying the fields of its spans
+    Json,
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    size: u64,
+    file: File,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64) -> Result<RotatingFile> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log file {}", path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            max_size,
+            size,
+            file,
+        })
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    /// Shifts `file.N` to `file.N+1`, dropping the oldest, and reopens the file.
+    fn rotate(&mut self) -> Result<()> {
+        for index in (1..LOG_FILE_ROTATIONS).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))?;
+        *self = RotatingFile::open(&self.path, self.max_size)?;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Shared handle of the log file, every event is written in one piece.
+#[derive(Clone)]
+struct LogFile(Arc<Mutex<RotatingFile>>);
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut file = self
+            .0
+            .lock()
+            .map_err(|_| io::Error::other("log file lock poisoned"))?;
+        file.write_line(buf)
+            .map_err(|err| io::Error::other(err.to_string()))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.0.lock() {
+            Ok(mut file) => file.file.flush(),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+/// Masks emails in everything written to the inner writer.
+struct Redacting<W> {
+    inner: W,
+    redact: bool,
+}
+
+impl<W: Write> Write for Redacting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.redact {
+            return self.inner.write(buf);
+        }
+        let redacted = util::redact_emails(&String::from_utf8_lossy(buf));
+        self.inner.write_all(redacted.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn layer<W>(format: LogFormat, ansi: bool, writer: W) -> Box<dyn Layer<Registry> + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().with_current_span(false).boxed(),
+    }
+}
+
+pub fn init(
+    verbose: u8,
+    format: LogFormat,
+    log_file: Option<&Path>,
+    log_file_max_size: u64,
+    redact: bool,
+) -> Result<()> {
+    let logging = match verbose {
+        0 => "info",
+        1 => "gluebuddy=debug",
+        _ => "debug",
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(logging));
+    let console = move || Redacting {
+        inner: io::stderr(),
+        redact,
+    };
+    let mut layers = vec![layer(format, atty::is(atty::Stream::Stderr), console)
+        .with_filter(filter)
+        .boxed()];
+
+    if let Some(path) = log_file {
+        let file = LogFile(Arc::new(Mutex::new(RotatingFile::open(
+            path,
+            log_file_max_size,
+        )?)));
+        let writer = move || Redacting {
+            inner: file.clone(),
+            redact,
+        };
+        // Everything of gluebuddy itself, but only info and above from dependencies
+        let targets = Targets::new()
+            .with_default(Level::INFO)
+            .with_target("gluebuddy", Level::TRACE);
+        layers.push(layer(format, false, writer).with_filter(targets).boxed());
+    }
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .try_init()
+        .context("Failed to set logger")
+}
@@ -1,6 +1,42 @@
 use args::*;
 mod args;
 
+mod cache;
+use cache::Cache;
+
+mod error;
+use error::ConfigError;
+
+mod events;
+
+mod http_client;
+
+mod junit;
+
+mod logging;
+
+mod metrics;
+
+mod notify;
+use notify::MatrixNotifier;
+
+#[allow(dead_code)]
+mod plan;
+use plan::{OutputFormat, Plan};
+
+mod plan_diff;
+use plan_diff::PlanDiff;
+
+mod review;
+
+mod snapshot;
+use snapshot::Snapshot;
+
+mod stats;
+use stats::Stats;
+
+mod validation;
+
 #[allow(dead_code)]
 mod state;
 use state::State;
@@ -8,69 +44,537 @@ use state::State;
 #[allow(dead_code)]
 mod util;
 
+mod vault_secrets;
+
 #[allow(dead_code)]
 mod components;
+use components::files::Files;
 use components::gitlab::GitLabGlue;
+use components::grafana::Grafana;
 use components::keycloak::Keycloak;
+use components::matrix::Matrix;
+use components::vault::Vault;
+use components::wiki::Wiki;
+use components::Component;
 
+use std::env;
+use std::fs;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use clap::Parser;
-use env_logger::Env;
-use log::error;
 use tokio::sync::Mutex;
+use tracing::{error, info, info_span, Instrument};
 
-async fn run(args: Args) -> Result<()> {
+async fn run(args: Args, plan: Arc<Plan>, started: Instant) -> Result<()> {
     /* Early exit for completions */
     if let Command::Completions(completions) = args.command {
         args::gen_completions(&completions)?;
         return Ok(());
     }
 
+    /* Early exit for comparing saved plans */
+    if let Command::Plan {
+        command: Some(PlanCommand::Diff { old, new }),
+        ..
+    } = &args.command
+    {
+        PlanDiff::new(plan_diff::load(old)?, plan_diff::load(new)?).print();
+        return Ok(());
+    }
+
+    let cache = match args.cache {
+        true => Some(Cache::new(
+            Duration::from_secs(args.keycloak_cache_ttl),
+            Duration::from_secs(args.gitlab_cache_ttl),
+            Duration::from_secs(args.gitlab_groups_cache_ttl),
+        )?),
+        false => None,
+    };
+
     let state = Arc::new(Mutex::new(State::default()));
 
-    let keycloak_glue = Keycloak::new(state.clone()).await?;
-    let gitlab_glue = GitLabGlue::new(state.clone()).await?;
+    /* Planning offline never builds an HTTP client, so no request can be sent */
+    if let Command::Plan {
+        command: None,
+        offline: true,
+        ..
+    } = &args.command
+    {
+        return plan_offline(&args, state, plan).await;
+    }
+
+    let client = http_client::build()?;
+    if env::var("GLUEBUDDY_SECRET_BACKEND").as_deref() == Ok("vault") {
+        vault_secrets::load(client.clone()).await?;
+    }
 
-    keycloak_glue.gather().await?;
-    gitlab_glue.gather().await?;
+    /* Early exit for verifying .SRCINFO files, which only needs a GitLab token */
+    if let Command::Gitlab {
+        command: GitlabCommand::VerifySrcinfo { projects },
+        ..
+    } = &args.command
+    {
+        let gitlab_glue = GitLabGlue::new(state.clone(), plan.clone()).await?;
+        return gitlab_glue.verify_srcinfo(projects).await;
+    }
+
+    let keycloak_glue = Keycloak::new(client.clone(), state.clone(), plan.clone()).await?;
+    let gitlab_glue = GitLabGlue::new(state.clone(), plan.clone())
+        .await?
+        .with_enforcements(&args.enforcement);
+    let gitlab_glue = match &args.command {
+        Command::Gitlab { scope, .. } => gitlab_glue.with_scope(scope)?,
+        _ => gitlab_glue,
+    };
+    let grafana_glue = Grafana::new(client.clone(), state.clone(), plan.clone()).await?;
+    let matrix_glue = Matrix::new(client.clone(), state.clone(), plan.clone()).await?;
+    let vault_glue = Vault::new(client.clone(), state.clone(), plan.clone()).await?;
+    let wiki_glue = Wiki::new(client.clone(), state.clone(), plan.clone()).await?;
+    let files_glue = args
+        .output_dir
+        .clone()
+        .map(|directory| Files::new(directory, state.clone(), plan.clone()));
+
+    keycloak_glue.preflight()?;
+    gitlab_glue.preflight().await?;
+
+    /* Early exit for single resource diffs, which skip the whole gathering */
+    if let Command::Diff {
+        resource_type,
+        address,
+    } = &args.command
+    {
+        return gitlab_glue.diff(resource_type, address).await;
+    }
+
+    /* All components in the order they gather and run, None if not configured */
+    let configured: [(ComponentKind, Option<&dyn Component>); 7] = [
+        (
+            ComponentKind::Keycloak,
+            Some(&keycloak_glue as &dyn Component),
+        ),
+        (ComponentKind::Gitlab, Some(&gitlab_glue as &dyn Component)),
+        (
+            ComponentKind::Grafana,
+            grafana_glue.as_ref().map(|glue| glue as &dyn Component),
+        ),
+        (
+            ComponentKind::Matrix,
+            matrix_glue.as_ref().map(|glue| glue as &dyn Component),
+        ),
+        (
+            ComponentKind::Vault,
+            vault_glue.as_ref().map(|glue| glue as &dyn Component),
+        ),
+        (
+            ComponentKind::Wiki,
+            wiki_glue.as_ref().map(|glue| glue as &dyn Component),
+        ),
+        (
+            ComponentKind::Files,
+            files_glue.as_ref().map(|glue| glue as &dyn Component),
+        ),
+    ];
+    for (kind, component) in &configured {
+        if let Some(component) = component {
+            component
+                .gather(cache.as_ref())
+                .instrument(info_span!("component", component = ?kind))
+                .await?;
+        }
+    }
+
+    if let Some(path) = &args.dump_state {
+        let state = state.lock().await;
+        fs::write(path, serde_json::to_string_pretty(&state.export())?)
+            .with_context(|| format!("Failed to write state to {}", path.display()))?;
+        info!("Dumped consolidated state to {}", path.display());
+    }
+
+    let is_plan = matches!(
+        args.command,
+        Command::Plan { command: None, .. }
+            | Command::Keycloak {
+                action: Action::Plan
+            }
+            | Command::Gitlab {
+                command: GitlabCommand::Action(Action::Plan),
+                ..
+            }
+            | Command::Grafana {
+                action: Action::Plan
+            }
+            | Command::Matrix {
+                action: Action::Plan
+            }
+            | Command::Vault {
+                action: Action::Plan
+            }
+            | Command::Wiki {
+                action: Action::Plan
+            }
+            | Command::Files {
+                action: Action::Plan
+            }
+    );
+    if is_plan {
+        let findings = validation::validate(&*state.lock().await);
+        match args.output {
+            OutputFormat::Json => validation::log_findings(&findings),
+            _ => validation::print_findings(&findings),
+        }
+    }
 
     match args.command {
-        Command::Completions(_) => {}
+        Command::Completions(_)
+        | Command::Plan {
+            command: Some(_), ..
+        }
+        | Command::Diff { .. }
+        | Command::Gitlab {
+            command: GitlabCommand::VerifySrcinfo { .. },
+            ..
+        } => {}
         Command::Keycloak { action } => {
             keycloak_glue.run(action).await?;
         }
-        Command::Gitlab { action } => gitlab_glue.run(action).await?,
-        Command::Plan => {
-            keycloak_glue.run(Action::Plan).await?;
-            gitlab_glue.run(Action::Plan).await?;
+        Command::Gitlab {
+            command: GitlabCommand::Action(action),
+            ..
+        } => gitlab_glue.run(action).await?,
+        Command::Grafana { action } => {
+            grafana_glue
+                .as_ref()
+                .context("Grafana is not configured, set GLUEBUDDY_GRAFANA_URL")?
+                .run(action)
+                .await?
+        }
+        Command::Matrix { action } => {
+            matrix_glue
+                .as_ref()
+                .context("Matrix is not configured, set GLUEBUDDY_MATRIX_URL")?
+                .run(action)
+                .await?
+        }
+        Command::Vault { action } => {
+            vault_glue
+                .as_ref()
+                .context("Vault is not configured, set GLUEBUDDY_VAULT_URL")?
+                .run(action)
+                .await?
+        }
+        Command::Wiki { action } => {
+            wiki_glue
+                .as_ref()
+                .context("Wiki is not configured, set GLUEBUDDY_WIKI_API_URL")?
+                .run(action)
+                .await?
+        }
+        Command::Files { action } => {
+            files_glue
+                .as_ref()
+                .context("Generating files requires --output-dir")?
+                .run(action)
+                .await?
         }
-        Command::Apply => {
-            keycloak_glue.run(Action::Apply).await?;
-            gitlab_glue.run(Action::Apply).await?;
+        Command::Plan {
+            command: None,
+            ref selection,
+            ..
+        }
+        | Command::Apply { ref selection, .. } => {
+            let interactive = matches!(
+                args.command,
+                Command::Plan {
+                    interactive: true,
+                    ..
+                }
+            );
+            let saved = match &args.command {
+                Command::Apply {
+                    plan: Some(path), ..
+                } => Some(plan_diff::load(path)?),
+                _ => None,
+            };
+            let mut action = match (&args.command, &saved) {
+                (Command::Apply { .. }, None) => Action::Apply,
+                _ => Action::Plan,
+            };
+            /* All configured and selected components in the order they run */
+            let mut components: Vec<(ComponentKind, &dyn Component)> = vec![];
+            for (kind, component) in configured {
+                match component {
+                    Some(component) if selection.includes(kind) => {
+                        components.push((kind, component))
+                    }
+                    None if selection.only.contains(&kind) => bail!(ConfigError::Invalid {
+                        name: "--only".to_string(),
+                        reason: format!("{:?} is not configured", kind),
+                    }),
+                    _ => {}
+                }
+            }
+            loop {
+                for (kind, component) in &components {
+                    component
+                        .run(action)
+                        .instrument(info_span!("component", component = ?kind))
+                        .await?;
+                }
+
+                /* Reviewed or saved plans apply the approved changes in a second pass */
+                if matches!(action, Action::Apply) {
+                    break;
+                }
+                let approved = match &saved {
+                    Some(saved) => {
+                        let diff = PlanDiff::new(saved.clone(), plan.take_recorded());
+                        if !diff.is_empty() {
+                            diff.print();
+                            bail!("The live state diverged since the plan was saved, plan again");
+                        }
+                        saved.clone()
+                    }
+                    None if interactive => review::review(&plan.take_recorded(), args.diff_style)?,
+                    None => break,
+                };
+                if approved.is_empty() {
+                    break;
+                }
+                plan.approve(&approved);
+                action = Action::Apply;
+            }
+
+            if let Command::Plan {
+                out: Some(path), ..
+            } = &args.command
+            {
+                let json = plan.json_plan(&plan.take_recorded(), started.elapsed())?;
+                fs::write(path, json)
+                    .with_context(|| format!("Failed to write plan to {}", path.display()))?;
+                info!("Saved the plan to {}", path.display());
+            }
+        }
+        Command::Stats => {
+            gitlab_glue.gather_team_group_members().await?;
+            let snapshot = Snapshot::new(
+                &*state.lock().await,
+                &gitlab_glue.member_access_levels().await,
+            )?;
+            let previous = match &args.snapshot {
+                Some(path) => Snapshot::load(path)?,
+                None => None,
+            };
+            Stats::new(&snapshot).print(previous.as_ref().map(Stats::new).as_ref());
         }
     }
+
+    if let Some(path) = &args.snapshot {
+        let state = state.lock().await;
+        let snapshot = Snapshot::new(&state, &gitlab_glue.member_access_levels().await)?;
+        if let Some(previous) = Snapshot::load(path)?.filter(|_| args.output != OutputFormat::Json)
+        {
+            snapshot.churn_since(&previous).print(previous.created);
+        }
+        snapshot.save(path)?;
+    }
+
+    if let Some(cache) = &cache {
+        gitlab_glue.store_group_tree(cache).await?;
+    }
+
+    if let (Some(project), Some(files_glue)) = (&args.merge_request, &files_glue) {
+        let files = files_glue.take_changed_files().await;
+        gitlab_glue.propose_generated_files(project, &files).await?;
+    }
+
+    if let Some(issue) = &args.report_issue {
+        if let Some(report) = plan.markdown_report(started.elapsed()) {
+            gitlab_glue.post_report(issue, &report).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Plans from the cached Keycloak state and the snapshot of a previous run.
+async fn plan_offline(args: &Args, state: Arc<Mutex<State>>, plan: Arc<Plan>) -> Result<()> {
+    let snapshot = match &args.snapshot {
+        Some(path) => Snapshot::load(path)?,
+        None => None,
+    }
+    .context("Planning offline requires the --snapshot of a previous run")?;
+    // Cached state is used regardless of its age
+    let cache = Cache::new(Duration::MAX, Duration::MAX, Duration::MAX)?;
+    let gitlab_glue = GitLabGlue::offline(state.clone(), plan.clone(), &snapshot).await?;
+
+    plan.start_component("Keycloak");
+    plan.gather_started()?;
+    Keycloak::restore(&state, &cache).await?;
+    plan.gather_finished()?;
+
+    gitlab_glue.gather(Some(&cache)).await?;
+
+    let findings = validation::validate(&*state.lock().await);
+    match args.output {
+        OutputFormat::Json => validation::log_findings(&findings),
+        _ => validation::print_findings(&findings),
+    }
+
+    gitlab_glue.run(Action::Plan).await?;
+    if let Some(directory) = &args.output_dir {
+        Files::new(directory.clone(), state.clone(), plan.clone())
+            .run(Action::Plan)
+            .await?;
+    }
     Ok(())
 }
 
+/// Posts the run summary and failures to the notification room, if one is
+/// configured and anything was planned or failed.
+async fn notify(plan: &Plan, title: &str, redact: bool) -> Result<()> {
+    let notifier = match MatrixNotifier::from_env(http_client::build()?)? {
+        Some(notifier) => notifier,
+        None => return Ok(()),
+    };
+    let message = match notify::render_message(title, &plan.totals(), &plan.failures()) {
+        Some(message) => message,
+        None => return Ok(()),
+    };
+    match redact {
+        true => notifier.notify(&util::redact_emails(&message)).await,
+        false => notifier.notify(&message).await,
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
-    let logging = match args.verbose {
-        0 => "info",
-        1 => "gluebuddy=debug",
-        _ => "debug",
+    if let Err(err) = logging::init(
+        args.verbose,
+        args.log_format,
+        args.log_file.as_deref(),
+        args.log_file_max_size * 1024 * 1024,
+        args.redact,
+    ) {
+        eprintln!("Failed to initialize logging: {:?}", err);
+        std::process::exit(1)
+    }
+
+    let plan = Plan::new(args.output, args.changes_only, args.group_by_type)
+        .with_diff_style(args.diff_style);
+    let plan = match args.redact {
+        true => plan.with_redaction(),
+        false => plan,
+    };
+    let plan = match &args.events {
+        None => plan,
+        Some(path) => match plan.with_event_log(path) {
+            Ok(plan) => plan,
+            Err(err) => {
+                eprintln!("Failed to initialize event log: {:?}", err);
+                std::process::exit(1)
+            }
+        },
+    };
+    let plan = match args.report_issue {
+        None => plan,
+        Some(_) => plan.with_report(),
+    };
+    let plan = match args.junit {
+        None => plan,
+        Some(_) => plan.with_test_cases(),
     };
+    let plan = match args.command {
+        Command::Plan {
+            interactive: true, ..
+        }
+        | Command::Plan { out: Some(_), .. }
+        | Command::Apply { plan: Some(_), .. } => plan.with_recorded_entries(),
+        _ => plan,
+    };
+    let junit_path = args.junit.clone();
+    let metrics_path = args.metrics.clone();
+    let fail_on_drift = args.fail_on_drift;
+    let detailed_exitcode = args.detailed_exitcode;
+    let redact = args.redact;
+    let title = format!(
+        "gluebuddy {}",
+        env::args().skip(1).collect::<Vec<_>>().join(" ")
+    );
+    let plan = Arc::new(plan);
+    let started = Instant::now();
+
+    let result = run(args, plan.clone(), started).await;
+    if let Err(err) = &result {
+        plan.record_error(err);
+    }
+    if let Err(err) = plan.print_grouped_entries() {
+        error!("Failed to print grouped plan: {:?}", err);
+    }
+    plan.print_run_summary(started.elapsed());
+
+    if let Some(path) = &metrics_path {
+        let written = serde_json::to_string_pretty(&plan.totals())
+            .map_err(anyhow::Error::from)
+            .and_then(|metrics| {
+                fs::write(path, metrics)
+                    .with_context(|| format!("Failed to write metrics to {}", path.display()))
+            });
+        if let Err(err) = written {
+            error!("{:?}", err);
+        }
+    }
+
+    if let (Some(path), Some(test_cases)) = (&junit_path, plan.test_cases()) {
+        if let Err(err) = junit::write(path, &test_cases) {
+            error!("{:?}", err);
+        }
+    }
 
-    env_logger::init_from_env(Env::default().default_filter_or(logging));
+    if let Err(err) = notify(&plan, &title, redact).await {
+        error!("{:?}", err);
+    }
 
-    if let Err(err) = run(args).await {
+    if let Err(err) = result {
         error!("Error: {:?}", err);
         for cause in err.chain() {
             error!("Caused by: {:?}", cause)
         }
-        std::process::exit(1)
+        std::process::exit(match detailed_exitcode {
+            true => error::DETAILED_ERROR_EXIT_CODE,
+            false => error::classify(&err).exit_code(),
+        })
+    }
+
+    let partial_apply_exit_code = match detailed_exitcode {
+        true => error::DETAILED_ERROR_EXIT_CODE,
+        false => error::PARTIAL_APPLY_EXIT_CODE,
+    };
+    let totals = plan.totals();
+    if totals.values().any(|totals| totals.errors > 0) {
+        error!("Some planned changes failed to apply");
+        std::process::exit(partial_apply_exit_code)
+    }
+    if totals
+        .values()
+        .any(|totals| totals.verification_failures > 0)
+    {
+        error!("Some applied changes failed verification");
+        std::process::exit(partial_apply_exit_code)
+    }
+    if (fail_on_drift || detailed_exitcode)
+        && totals
+            .values()
+            .any(|totals| totals.add + totals.change + totals.destroy > 0)
+    {
+        info!("Changes were planned");
+        std::process::exit(match detailed_exitcode {
+            true => error::DETAILED_DRIFT_EXIT_CODE,
+            false => error::DRIFT_EXIT_CODE,
+        })
     }
 }
@@ -0,0 +1,25 @@
+//! Process wide counters of API requests and cache hits. The plan attributes
+//! them to the component that was running while they were counted.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+
+/// Counts a request sent to a service, including retries.
+pub fn count_request() {
+    REQUESTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts state that was taken from the cache instead of a service.
+pub fn count_cache_hit() {
+    CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the requests and cache hits counted so far.
+pub fn counts() -> (u64, u64) {
+    (
+        REQUESTS.load(Ordering::Relaxed),
+        CACHE_HITS.load(Ordering::Relaxed),
+    )
+}
@@ -0,0 +1,164 @@
+//! This module posts the run summary and all failures of a run to a Matrix
+//! room, so that changes applied by scheduled runs do not go unnoticed.
+
+use crate::error::CheckStatus;
+use crate::http_client;
+use crate::plan::ComponentTotals;
+use crate::util;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use reqwest::{Client, Url};
+use serde_json::json;
+use tracing::{debug, info};
+
+const SERVICE: &str = "Matrix";
+
+pub struct MatrixNotifier {
+    client: Client,
+    url: Url,
+    room: String,
+    token: String,
+}
+
+impl MatrixNotifier {
+    /// Returns None if no notification room is configured.
+    pub fn from_env(client: Client) -> Result<Option<MatrixNotifier>> {
+        let url = match env::var("GLUEBUDDY_NOTIFY_MATRIX_URL") {
+            Ok(url) => url,
+            Err(_) => return Ok(None),
+        };
+        let url = Url::parse(&url).with_context(|| format!("Invalid Matrix url {}", url))?;
+        let room = util::required_env("GLUEBUDDY_NOTIFY_MATRIX_ROOM")?;
+        let token = util::secret("GLUEBUDDY_NOTIFY_MATRIX_TOKEN")?;
+        Ok(Some(MatrixNotifier {
+            client,
+            url,
+            room,
+            token,
+        }))
+    }
+
+    /// Sends the message as notice, which bots do not respond to.
+    pub async fn notify(&self, message: &str) -> Result<()> {
+        let txn_id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("System time is before the unix epoch")?
+            .as_nanos()
+            .to_string();
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("Matrix url {} can not be a base", self.url))?
+            .pop_if_empty()
+            .extend(&[
+                "_matrix",
+                "client",
+                "v3",
+                "rooms",
+                &self.room,
+                "send",
+                "m.room.message",
+                &txn_id,
+            ]);
+        debug!("Notifying Matrix room {}", self.room);
+        let request = self
+            .client
+            .put(url)
+            .bearer_auth(&self.token)
+            .json(&json!({ "msgtype": "m.notice", "body": message }));
+        http_client::send(request)
+            .await?
+            .check_status(SERVICE)
+            .with_context(|| format!("Failed to notify Matrix room {}", self.room))?;
+        info!("Notified Matrix room {}", self.room);
+        Ok(())
+    }
+}
+
+/// Renders the notification of a run, which is None if nothing was planned
+/// and nothing failed.
+pub fn render_message(
+    title: &str,
+    totals: &BTreeMap<String, ComponentTotals>,
+    failures: &[String],
+) -> Option<String> {
+    let eventful: Vec<(&String, &ComponentTotals)> = totals
+        .iter()
+        .filter(|(_, totals)| {
+            totals.add
+                + totals.change
+                + totals.destroy
+                + totals.errors
+                + totals.verification_failures
+                > 0
+        })
+        .collect();
+    if eventful.is_empty() && failures.is_empty() {
+        return None;
+    }
+    let mut message = format!("{}\n", title);
+    for (component, totals) in eventful {
+        message.push_str(&format!(
+            "{}: {} to add, {} to change, {} to destroy, {} errors, {} verification failures\n",
+            component,
+            totals.add,
+            totals.change,
+            totals.destroy,
+            totals.errors,
+            totals.verification_failures
+        ));
+    }
+    if !failures.is_empty() {
+        message.push_str("Failures:\n");
+        for failure in failures {
+            message.push_str(&format!("- {}\n", failure));
+        }
+    }
+    Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn totals(add: u64, destroy: u64, errors: u64) -> ComponentTotals {
+        ComponentTotals {
+            add,
+            destroy,
+            errors,
+            ..ComponentTotals::default()
+        }
+    }
+
+    #[rstest]
+    #[case(vec![("GitLab", totals(0, 0, 0))], vec![], None)]
+    #[case(
+        vec![("GitLab", totals(0, 1, 0)), ("Keycloak", totals(0, 0, 0))],
+        vec![],
+        Some("gluebuddy apply\nGitLab: 0 to add, 0 to change, 1 to destroy, 0 errors, 0 verification failures\n")
+    )]
+    #[case(
+        vec![("GitLab", totals(2, 0, 1))],
+        vec!["GitLab: add gitlab_member_access archlinux:alice: 403 Forbidden"],
+        Some("gluebuddy apply\nGitLab: 2 to add, 0 to change, 0 to destroy, 1 errors, 0 verification failures\nFailures:\n- GitLab: add gitlab_member_access archlinux:alice: 403 Forbidden\n")
+    )]
+    fn render_message_test(
+        #[case] totals: Vec<(&str, ComponentTotals)>,
+        #[case] failures: Vec<&str>,
+        #[case] expected: Option<&str>,
+    ) {
+        let totals = totals
+            .into_iter()
+            .map(|(component, totals)| (component.to_string(), totals))
+            .collect();
+        let failures: Vec<String> = failures.into_iter().map(str::to_string).collect();
+        assert_eq!(
+            render_message("gluebuddy apply", &totals, &failures).as_deref(),
+            expected
+        );
+    }
+}
@@ -0,0 +1,1339 @@
+//! This module defines structured plan entries.
+//!
+//! Components describe the current and expected state of a resource as a
+//! [`Resource`] and build a [`PlanEntry`] from them, which can then be
+//! rendered for humans or serialized for machine consumption.
+
+use crate::args::Action;
+use crate::components::gitlab::policy::ProjectSettings;
+use crate::events::{EventKind, EventLog};
+use crate::junit::TestCase;
+use crate::metrics;
+use crate::util;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::ArgEnum;
+use gitlab::api::common::AccessLevel;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum OutputFormat {
+    /// Colored unified diffs for terminals
+    Text,
+    /// GitLab flavored markdown with collapsible sections
+    Markdown,
+    /// A single JSON document with all planned changes and totals, printed at the end
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum DiffStyle {
+    /// Unified diffs with +/- prefixed lines
+    Unified,
+    /// Two columns with the resource before and after the change
+    SideBySide,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PlanSummary {
+    name: String,
+    pub add: u64,
+    pub change: u64,
+    pub destroy: u64,
+}
+
+impl PlanSummary {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            add: 0,
+            change: 0,
+            destroy: 0,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn has_changes(&self) -> bool {
+        0 != self.add || 0 != self.change || 0 != self.destroy
+    }
+}
+
+impl Display for PlanSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if !self.has_changes() {
+            return write!(f, "No changes. {} is up-to-date.", self.name);
+        }
+        write!(
+            f,
+            "{} has changed!\nPlan: {} to add, {} to change, {} to destroy.",
+            self.name, self.add, self.change, self.destroy
+        )
+    }
+}
+
+/// Totals of all sections a component planned.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct ComponentTotals {
+    pub sections: u64,
+    pub changed_sections: u64,
+    pub add: u64,
+    pub change: u64,
+    pub destroy: u64,
+    pub errors: u64,
+    /// Applied changes the re-queried resource did not reflect
+    pub verification_failures: u64,
+    pub requests: u64,
+    pub cache_hits: u64,
+}
+
+impl ComponentTotals {
+    fn add_summary(&mut self, summary: &PlanSummary) {
+        self.sections += 1;
+        if summary.has_changes() {
+            self.changed_sections += 1;
+        }
+        self.add += summary.add;
+        self.change += summary.change;
+        self.destroy += summary.destroy;
+    }
+
+    fn add_totals(&mut self, totals: &ComponentTotals) {
+        self.sections += totals.sections;
+        self.changed_sections += totals.changed_sections;
+        self.add += totals.add;
+        self.change += totals.change;
+        self.destroy += totals.destroy;
+        self.errors += totals.errors;
+        self.verification_failures += totals.verification_failures;
+        self.requests += totals.requests;
+        self.cache_hits += totals.cache_hits;
+    }
+}
+
+impl Display for ComponentTotals {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} to add, {} to change, {} to destroy in {} of {} resources, {} errors, {} verification failures, {} requests, {} cache hits",
+            self.add,
+            self.change,
+            self.destroy,
+            self.changed_sections,
+            self.sections,
+            self.errors,
+            self.verification_failures,
+            self.requests,
+            self.cache_hits
+        )
+    }
+}
+
+#[derive(Default)]
+struct PlanState {
+    component: String,
+    section: Vec<PlanEntry>,
+    grouped: Vec<PlanEntry>,
+    /// Markdown of all sections, collected if a report is requested
+    report: Option<String>,
+    /// One test case per section, collected if a JUnit report is requested
+    test_cases: Option<Vec<TestCase>>,
+    totals: BTreeMap<String, ComponentTotals>,
+    /// Requests and cache hits counted until the current component started
+    counted: (u64, u64),
+    /// All recorded entries, collected for a review or a plan file
+    recorded: Option<Vec<PlanEntry>>,
+    /// Resource types and addresses approved to apply, all if unset
+    approved: Option<BTreeSet<(String, String)>>,
+    /// All recorded entries, collected for the JSON output
+    changes: Vec<PlanEntry>,
+    /// Errors and failed changes by component, for notifications
+    failures: Vec<String>,
+}
+
+impl PlanState {
+    fn component_totals(&mut self) -> &mut ComponentTotals {
+        self.totals.entry(self.component.clone()).or_default()
+    }
+
+    /// Attributes requests and cache hits counted since the last call to the
+    /// current component.
+    fn attribute_metrics(&mut self) {
+        let (requests, cache_hits) = metrics::counts();
+        let (counted_requests, counted_cache_hits) = self.counted;
+        self.counted = (requests, cache_hits);
+        if requests == counted_requests && cache_hits == counted_cache_hits {
+            return;
+        }
+        let totals = self.component_totals();
+        totals.requests += requests - counted_requests;
+        totals.cache_hits += cache_hits - counted_cache_hits;
+    }
+}
+
+/// Records planned changes of the components and renders them per section.
+pub struct Plan {
+    format: OutputFormat,
+    diff_style: DiffStyle,
+    /// Mask emails in the rendered output, but not in the event log
+    redact: bool,
+    changes_only: bool,
+    group_by_type: bool,
+    /// Wrap each printed entry into a collapsible GitLab CI job log section
+    ci_sections: bool,
+    state: Mutex<PlanState>,
+    events: Option<EventLog>,
+}
+
+impl Plan {
+    pub fn new(format: OutputFormat, changes_only: bool, group_by_type: bool) -> Plan {
+        let state = PlanState {
+            component: "Gather".to_string(),
+            ..PlanState::default()
+        };
+        Plan {
+            format,
+            diff_style: DiffStyle::Unified,
+            redact: false,
+            changes_only,
+            group_by_type,
+            ci_sections: util::is_gitlab_ci(),
+            state: Mutex::new(state),
+            events: None,
+        }
+    }
+
+    /// Renders text output entries in the given diff style.
+    pub fn with_diff_style(mut self, diff_style: DiffStyle) -> Plan {
+        self.diff_style = diff_style;
+        self
+    }
+
+    /// Masks emails in all rendered output.
+    pub fn with_redaction(mut self) -> Plan {
+        self.redact = true;
+        self
+    }
+
+    /// Additionally collects all sections as markdown for [`Plan::markdown_report`].
+    pub fn with_report(self) -> Plan {
+        self.state.lock().unwrap().report = Some(String::new());
+        self
+    }
+
+    /// Additionally collects all sections as test cases for [`Plan::test_cases`].
+    pub fn with_test_cases(self) -> Plan {
+        self.state.lock().unwrap().test_cases = Some(vec![]);
+        self
+    }
+
+    /// Additionally collects all recorded entries for [`Plan::take_recorded`].
+    pub fn with_recorded_entries(self) -> Plan {
+        self.state.lock().unwrap().recorded = Some(vec![]);
+        self
+    }
+
+    /// Additionally writes all events as JSON lines to the given file.
+    pub fn with_event_log(mut self, path: &Path) -> Result<Plan> {
+        self.events = Some(EventLog::create(path)?);
+        Ok(self)
+    }
+
+    fn emit(&self, kind: EventKind) -> Result<()> {
+        if let Some(events) = &self.events {
+            let component = self.state.lock().unwrap().component.clone();
+            events.emit(&component, kind)?;
+        }
+        Ok(())
+    }
+
+    /// Attributes all following sections and errors to the component.
+    pub fn start_component(&self, component: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.attribute_metrics();
+        state.component = component.to_string();
+    }
+
+    pub fn gather_started(&self) -> Result<()> {
+        self.emit(EventKind::GatherStarted)
+    }
+
+    pub fn gather_finished(&self) -> Result<()> {
+        self.emit(EventKind::GatherFinished)
+    }
+
+    /// Records a planned change of the current section.
+    pub fn record(&self, entry: &PlanEntry) -> Result<()> {
+        self.emit(EventKind::ResourcePlanned { entry })?;
+        if let Some(recorded) = self.state.lock().unwrap().recorded.as_mut() {
+            recorded.push(entry.clone());
+        }
+        let entry = &match self.redact {
+            true => entry.redacted(),
+            false => entry.clone(),
+        };
+        {
+            let mut state = self.state.lock().unwrap();
+            if self.format == OutputFormat::Markdown
+                || state.report.is_some()
+                || state.test_cases.is_some()
+            {
+                state.section.push(entry.clone());
+            }
+            if self.format == OutputFormat::Json {
+                state.changes.push(entry.clone());
+            }
+            if self.group_by_type {
+                state.grouped.push(entry.clone());
+                return Ok(());
+            }
+        }
+        if self.format == OutputFormat::Text {
+            self.print_entry(entry)?;
+        }
+        Ok(())
+    }
+
+    fn print_entry(&self, entry: &PlanEntry) -> Result<()> {
+        if !self.ci_sections {
+            return entry.print(self.diff_style);
+        }
+        let name = format!("{} {}", entry.resource_type, entry.address);
+        let header = format!(
+            "{} {} {}",
+            entry.kind.as_str(),
+            entry.resource_type,
+            entry.address
+        );
+        println!(
+            "{}",
+            util::format_ci_section_start(&name, &header, util::unix_timestamp()?)
+        );
+        entry.print(self.diff_style)?;
+        println!(
+            "{}",
+            util::format_ci_section_end(&name, util::unix_timestamp()?)
+        );
+        Ok(())
+    }
+
+    /// Returns the entries recorded since they were last taken.
+    pub fn take_recorded(&self) -> Vec<PlanEntry> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .recorded
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Restricts applying to the given entries and resets the totals, so the
+    /// run summary only covers the following apply.
+    pub fn approve(&self, entries: &[PlanEntry]) {
+        let mut state = self.state.lock().unwrap();
+        state.approved = Some(
+            entries
+                .iter()
+                .map(|entry| (entry.resource_type.clone(), entry.address.clone()))
+                .collect(),
+        );
+        state.recorded = None;
+        state.totals.clear();
+        state.changes.clear();
+    }
+
+    /// Returns whether the action applies the planned change, which requires
+    /// it to be approved if a review took place or a plan file is applied.
+    pub fn applies(&self, action: &Action, entry: &PlanEntry) -> bool {
+        self.applies_all(action, std::slice::from_ref(entry))
+    }
+
+    /// Returns whether the action applies planned changes that can only be
+    /// applied together, which requires all of them to be approved.
+    pub fn applies_all(&self, action: &Action, entries: &[PlanEntry]) -> bool {
+        if let Action::Plan = action {
+            return false;
+        }
+        let state = self.state.lock().unwrap();
+        let approved = match &state.approved {
+            Some(approved) => approved,
+            None => return true,
+        };
+        match entries
+            .iter()
+            .find(|entry| !approved.contains(&(entry.resource_type.clone(), entry.address.clone())))
+        {
+            Some(entry) => {
+                info!("Skipping {}: not approved", entry.describe());
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Records that a planned change was successfully applied.
+    pub fn record_applied(&self, entry: &PlanEntry) -> Result<()> {
+        self.emit(EventKind::ResourceApplied {
+            resource_type: &entry.resource_type,
+            address: &entry.address,
+        })
+    }
+
+    /// Finishes the current section with its summary.
+    pub fn finish_section(&self, summary: &PlanSummary) -> Result<()> {
+        let hidden = self.changes_only && !summary.has_changes();
+        let entries: Vec<PlanEntry> = {
+            let mut state = self.state.lock().unwrap();
+            state.component_totals().add_summary(summary);
+            let entries: Vec<PlanEntry> = state.section.drain(..).collect();
+            if let Some(report) = state.report.as_mut().filter(|_| !hidden) {
+                report.push_str(&render_markdown_section(summary, &entries));
+            }
+            let classname = state.component.clone();
+            if let Some(test_cases) = state.test_cases.as_mut() {
+                test_cases.push(TestCase {
+                    classname,
+                    name: summary.name().to_string(),
+                    failures: entries.iter().map(PlanEntry::describe).collect(),
+                    error: None,
+                });
+            }
+            entries
+        };
+        self.emit(EventKind::SectionFinished {
+            section: summary.name(),
+            add: summary.add,
+            change: summary.change,
+            destroy: summary.destroy,
+        })?;
+        if self.group_by_type || hidden {
+            return Ok(());
+        }
+        match self.format {
+            OutputFormat::Text => {
+                println!("{}", summary);
+                println!("{}", util::format_separator());
+            }
+            OutputFormat::Markdown => print!("{}", render_markdown_section(summary, &entries)),
+            OutputFormat::Json => {}
+        }
+        Ok(())
+    }
+
+    /// Records an error of the current component.
+    pub fn record_error(&self, error: &anyhow::Error) {
+        let message = format!("{:#}", error);
+        self.count_error("error", &message);
+        if let Err(err) = self.emit(EventKind::Error { message }) {
+            warn!("{:?}", err);
+        }
+    }
+
+    /// Records that applying a planned change failed. The error is counted
+    /// for the current component, the remaining changes are still applied.
+    pub fn record_failed(&self, entry: &PlanEntry, error: &anyhow::Error) {
+        let message = format!("{:#}", error);
+        self.count_error(&entry.describe(), &message);
+        if let Err(err) = self.emit(EventKind::ResourceFailed {
+            resource_type: &entry.resource_type,
+            address: &entry.address,
+            message,
+        }) {
+            warn!("{:?}", err);
+        }
+    }
+
+    /// Compares an applied change with the re-queried resource, which is
+    /// `None` if it no longer exists. A mismatch means the API accepted the
+    /// change without applying it and is counted as verification failure.
+    pub fn record_verified(&self, entry: &PlanEntry, actual: Option<&Resource>) {
+        let mismatches = entry.mismatches(actual);
+        if mismatches.is_empty() {
+            return;
+        }
+        let message = format!("not applied: {}", mismatches.join(", "));
+        warn!("Verification of {} failed: {}", entry.describe(), message);
+        {
+            let mut state = self.state.lock().unwrap();
+            state.component_totals().verification_failures += 1;
+            let classname = state.component.clone();
+            if let Some(test_cases) = state.test_cases.as_mut() {
+                test_cases.push(TestCase {
+                    classname,
+                    name: format!("verify {}", entry.describe()),
+                    failures: vec![message.clone()],
+                    error: None,
+                });
+            }
+        }
+        if let Err(err) = self.emit(EventKind::ResourceVerificationFailed {
+            resource_type: &entry.resource_type,
+            address: &entry.address,
+            message,
+        }) {
+            warn!("{:?}", err);
+        }
+    }
+
+    /// Verifies an applied change against the re-queried resource. Some
+    /// endpoints silently ignore fields, so a successful response alone does
+    /// not mean the change was applied. Failing to re-query is only logged.
+    pub fn verify_applied(&self, entry: &PlanEntry, actual: Result<Option<Resource>>) {
+        match actual {
+            Ok(actual) => self.record_verified(entry, actual.as_ref()),
+            Err(err) => warn!("Failed to verify {}: {:?}", entry.describe(), err),
+        }
+    }
+
+    fn count_error(&self, name: &str, message: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.component_totals().errors += 1;
+        let failure = format!("{}: {}: {}", state.component, name, message);
+        state.failures.push(failure);
+        let classname = state.component.clone();
+        if let Some(test_cases) = state.test_cases.as_mut() {
+            test_cases.push(TestCase {
+                classname,
+                name: name.to_string(),
+                failures: vec![],
+                error: Some(message.to_string()),
+            });
+        }
+    }
+
+    /// Returns the errors and failed changes of the run so far.
+    pub fn failures(&self) -> Vec<String> {
+        self.state.lock().unwrap().failures.clone()
+    }
+
+    /// Returns the collected test cases, if requested.
+    pub fn test_cases(&self) -> Option<Vec<TestCase>> {
+        self.state.lock().unwrap().test_cases.clone()
+    }
+
+    pub fn totals(&self) -> BTreeMap<String, ComponentTotals> {
+        let mut state = self.state.lock().unwrap();
+        state.attribute_metrics();
+        state.totals.clone()
+    }
+
+    /// Prints all entries recorded while grouping by resource type, with
+    /// counts per type. Does nothing unless grouping is enabled.
+    pub fn print_grouped_entries(&self) -> Result<()> {
+        let entries: Vec<PlanEntry> = self.state.lock().unwrap().grouped.drain(..).collect();
+        for (summary, entries) in group_entries_by_type(entries) {
+            match self.format {
+                OutputFormat::Text => {
+                    for entry in &entries {
+                        self.print_entry(entry)?;
+                    }
+                    println!("{}", summary);
+                    println!("{}", util::format_separator());
+                }
+                OutputFormat::Markdown => print!("{}", render_markdown_section(&summary, &entries)),
+                OutputFormat::Json => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders the entries with the totals so far as JSON plan, the format
+    /// of `--output json` that `plan diff` and `apply --plan` read.
+    pub fn json_plan(&self, changes: &[PlanEntry], duration: Duration) -> Result<String> {
+        let totals = self.totals();
+        render_json_plan(changes, &totals, &overall_totals(&totals), duration)
+    }
+
+    /// Returns the collected markdown sections followed by the run summary,
+    /// if a report was requested.
+    pub fn markdown_report(&self, duration: Duration) -> Option<String> {
+        let mut report = self.state.lock().unwrap().report.clone()?;
+        let totals = self.totals();
+        report.push_str(&render_markdown_run_summary(
+            &totals,
+            &overall_totals(&totals),
+            duration,
+        ));
+        Some(report)
+    }
+
+    /// Prints the aggregated totals of all components, if any ran. The JSON
+    /// output is always printed, with all recorded entries.
+    pub fn print_run_summary(&self, duration: Duration) {
+        let totals = self.totals();
+        let overall = overall_totals(&totals);
+        log_run_summary(&totals, &overall, duration);
+        if self.format == OutputFormat::Json {
+            let changes = self.state.lock().unwrap().changes.clone();
+            match render_json_plan(&changes, &totals, &overall, duration) {
+                Ok(json) => println!("{}", json),
+                Err(err) => warn!("Failed to render JSON plan: {:?}", err),
+            }
+            return;
+        }
+        if totals.is_empty() {
+            return;
+        }
+
+        match self.format {
+            OutputFormat::Text => {
+                println!("Run summary:");
+                for (component, component_totals) in &totals {
+                    println!("\t{}: {}", component, component_totals);
+                }
+                println!("Total: {} ({}s)", overall, duration.as_secs());
+                println!("{}", util::format_separator());
+            }
+            OutputFormat::Markdown => {
+                print!(
+                    "{}",
+                    render_markdown_run_summary(&totals, &overall, duration)
+                )
+            }
+            OutputFormat::Json => {}
+        }
+    }
+}
+
+/// Version of the JSON plan document, increased on incompatible changes.
+const JSON_PLAN_FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct JsonPlan<'a> {
+    format_version: u32,
+    changes: &'a [PlanEntry],
+    components: &'a BTreeMap<String, ComponentTotals>,
+    total: &'a ComponentTotals,
+    duration_secs: u64,
+}
+
+fn render_json_plan(
+    changes: &[PlanEntry],
+    totals: &BTreeMap<String, ComponentTotals>,
+    overall: &ComponentTotals,
+    duration: Duration,
+) -> Result<String> {
+    Ok(serde_json::to_string_pretty(&JsonPlan {
+        format_version: JSON_PLAN_FORMAT_VERSION,
+        changes,
+        components: totals,
+        total: overall,
+        duration_secs: duration.as_secs(),
+    })?)
+}
+
+/// Groups entries by their resource type, keeping the traversal order within
+/// each type, and counts them in a summary named after the type.
+fn group_entries_by_type(entries: Vec<PlanEntry>) -> Vec<(PlanSummary, Vec<PlanEntry>)> {
+    let mut groups: BTreeMap<String, (PlanSummary, Vec<PlanEntry>)> = BTreeMap::new();
+    for entry in entries {
+        let (summary, entries) = groups
+            .entry(entry.resource_type.clone())
+            .or_insert_with(|| (PlanSummary::new(&entry.resource_type), vec![]));
+        match entry.kind {
+            ChangeKind::Add => summary.add += 1,
+            ChangeKind::Change => summary.change += 1,
+            ChangeKind::Destroy => summary.destroy += 1,
+        }
+        entries.push(entry);
+    }
+    groups.into_values().collect()
+}
+
+fn overall_totals(totals: &BTreeMap<String, ComponentTotals>) -> ComponentTotals {
+    let mut overall = ComponentTotals::default();
+    for component_totals in totals.values() {
+        overall.add_totals(component_totals);
+    }
+    overall
+}
+
+/// Logs the roll-up of the run, so that the log file and log aggregation
+/// have it independent of the output format.
+fn log_run_summary(
+    totals: &BTreeMap<String, ComponentTotals>,
+    overall: &ComponentTotals,
+    duration: Duration,
+) {
+    for (component, component_totals) in totals {
+        info!(
+            component = %component,
+            add = component_totals.add,
+            change = component_totals.change,
+            destroy = component_totals.destroy,
+            errors = component_totals.errors,
+            "Run summary {}: {}",
+            component,
+            component_totals
+        );
+    }
+    info!(
+        add = overall.add,
+        change = overall.change,
+        destroy = overall.destroy,
+        errors = overall.errors,
+        "Run total: {} ({}s)",
+        overall,
+        duration.as_secs()
+    );
+}
+
+fn render_markdown_run_summary(
+    totals: &BTreeMap<String, ComponentTotals>,
+    overall: &ComponentTotals,
+    duration: Duration,
+) -> String {
+    let mut markdown = "\n### Run summary\n\n\
+        | Component | Add | Change | Destroy | Resources | Errors | Verification failures | Requests | Cache hits |\n\
+        |---|---|---|---|---|---|---|---|---|\n"
+        .to_string();
+    let total_label = "**Total**".to_string();
+    for (component, component_totals) in totals
+        .iter()
+        .chain(std::iter::once((&total_label, overall)))
+    {
+        markdown.push_str(&format!(
+            "| {} | {} | {} | {} | {}/{} | {} | {} | {} | {} |\n",
+            component,
+            component_totals.add,
+            component_totals.change,
+            component_totals.destroy,
+            component_totals.changed_sections,
+            component_totals.sections,
+            component_totals.errors,
+            component_totals.verification_failures,
+            component_totals.requests,
+            component_totals.cache_hits
+        ));
+    }
+    markdown.push_str(&format!("\nFinished in {}s.\n", duration.as_secs()));
+    markdown
+}
+
+fn render_markdown_section(summary: &PlanSummary, entries: &[PlanEntry]) -> String {
+    if entries.is_empty() {
+        return format!("- :white_check_mark: {}\n", summary);
+    }
+
+    let mut markdown = format!(
+        "<details>\n<summary>:warning: <b>{}</b>: {} to add, {} to change, {} to destroy</summary>\n\n",
+        summary.name(),
+        summary.add,
+        summary.change,
+        summary.destroy
+    );
+    for entry in entries {
+        let (before, after) = entry.render_text();
+        markdown.push_str(&format!(
+            "`{}` {} _({})_\n\n```diff\n{}```\n\n",
+            entry.address,
+            entry.kind.as_str(),
+            entry.rule,
+            util::format_diff(&before, &after)
+        ));
+    }
+    markdown.push_str("</details>\n");
+    markdown
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Add,
+    Change,
+    Destroy,
+}
+
+impl ChangeKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Add => "add",
+            Self::Change => "change",
+            Self::Destroy => "destroy",
+        }
+    }
+}
+
+/// The state of a single resource as a list of named fields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resource {
+    pub resource_type: String,
+    pub address: String,
+    pub fields: Vec<(String, String)>,
+    /// Fields identifying the resource, always rendered even if unchanged
+    pub keys: Vec<String>,
+}
+
+impl Resource {
+    pub fn new(resource_type: &str, address: &str) -> Resource {
+        Resource {
+            resource_type: resource_type.to_string(),
+            address: address.to_string(),
+            fields: vec![],
+            keys: vec![],
+        }
+    }
+
+    pub fn field(mut self, name: &str, value: impl ToString) -> Resource {
+        self.fields.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn key_field(mut self, name: &str, value: impl ToString) -> Resource {
+        self.keys.push(name.to_string());
+        self.field(name, value)
+    }
+
+    fn value(&self, name: &str) -> Option<&String> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field.eq(name))
+            .map(|(_, value)| value)
+    }
+
+    pub fn gitlab_member_access(
+        namespace: &str,
+        username: &str,
+        access_level: AccessLevel,
+    ) -> Resource {
+        Resource::new(
+            "gitlab_member_access",
+            &format!("{}:{}", namespace, username),
+        )
+        .key_field("namespace", namespace)
+        .key_field("username", username)
+        .field("access_level", access_level.as_str())
+    }
+
+    pub fn gitlab_user(username: &str, admin: bool) -> Resource {
+        Resource::new("gitlab_user", username)
+            .key_field("username", username)
+            .field("admin", admin)
+    }
+
+    pub fn gitlab_group(path: &str, visibility: &str, request_access_enabled: bool) -> Resource {
+        Resource::new("gitlab_group", path)
+            .key_field("path", path)
+            .field("visibility", visibility)
+            .field("request_access_enabled", request_access_enabled)
+    }
+
+    pub fn gitlab_group_settings(path: &str, description: &str, avatar: &str) -> Resource {
+        Resource::new("gitlab_group_settings", path)
+            .key_field("path", path)
+            .field("description", description)
+            .field("avatar", avatar)
+    }
+
+    pub fn gitlab_user_state(username: &str, state: &str) -> Resource {
+        Resource::new("gitlab_user_state", username)
+            .key_field("username", username)
+            .field("state", state)
+    }
+
+    pub fn gitlab_issue_state(reference: &str, title: &str, state: &str) -> Resource {
+        Resource::new("gitlab_issue_state", reference)
+            .key_field("reference", reference)
+            .field("title", title)
+            .field("state", state)
+    }
+
+    pub fn gitlab_registry_tag(repository: &str, tag: &str, created_at: &str) -> Resource {
+        Resource::new("gitlab_registry_tag", &format!("{}:{}", repository, tag))
+            .key_field("repository", repository)
+            .key_field("tag", tag)
+            .field("created_at", created_at)
+    }
+
+    pub fn file_line(path: &str, line: &str) -> Resource {
+        Resource::new("file_line", path)
+            .key_field("path", path)
+            .key_field("line", line)
+    }
+
+    pub fn keycloak_user(username: &str, enabled: bool) -> Resource {
+        Resource::new("keycloak_user", username)
+            .key_field("username", username)
+            .field("enabled", enabled)
+    }
+
+    pub fn grafana_org_user(login: &str, role: &str) -> Resource {
+        Resource::new("grafana_org_user", login)
+            .key_field("login", login)
+            .field("role", role)
+    }
+
+    pub fn grafana_team_member(team: &str, login: &str) -> Resource {
+        Resource::new("grafana_team_member", &format!("{}:{}", team, login))
+            .key_field("team", team)
+            .key_field("login", login)
+    }
+
+    pub fn vault_group_member(group: &str, username: &str, entity_id: &str) -> Resource {
+        Resource::new("vault_group_member", &format!("{}:{}", group, entity_id))
+            .key_field("group", group)
+            .key_field("username", username)
+            .key_field("entity_id", entity_id)
+    }
+
+    pub fn vault_group_policies(group: &str, policies: &[String]) -> Resource {
+        Resource::new("vault_group_policies", group)
+            .key_field("group", group)
+            .field("policies", policies.join(","))
+    }
+
+    pub fn vault_entity_alias(name: &str, id: &str) -> Resource {
+        Resource::new("vault_entity_alias", name)
+            .key_field("name", name)
+            .key_field("id", id)
+    }
+
+    pub fn wiki_group_member(group: &str, username: &str) -> Resource {
+        Resource::new("wiki_group_member", &format!("{}:{}", group, username))
+            .key_field("group", group)
+            .key_field("username", username)
+    }
+
+    pub fn matrix_room_member(room_id: &str, user_id: &str) -> Resource {
+        Resource::new("matrix_room_member", &format!("{}:{}", room_id, user_id))
+            .key_field("room_id", room_id)
+            .key_field("user_id", user_id)
+    }
+
+    pub fn gitlab_project_settings(namespace: &str, settings: &ProjectSettings) -> Resource {
+        Resource::new("gitlab_project_setting", namespace)
+            .key_field("namespace", namespace)
+            .field("request_access_enabled", settings.request_access_enabled)
+            .field("issues_access_level", settings.issues_access_level.as_str())
+            .field(
+                "merge_requests_access_level",
+                settings.merge_requests_access_level.as_str(),
+            )
+            .field("wiki_access_level", settings.wiki_access_level.as_str())
+            .field("builds_access_level", settings.builds_access_level.as_str())
+            .field(
+                "snippets_access_level",
+                settings.snippets_access_level.as_str(),
+            )
+            .field("merge_method", settings.merge_method.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub key: bool,
+}
+
+impl FieldChange {
+    pub fn is_changed(&self) -> bool {
+        self.before != self.after
+    }
+}
+
+/// A single planned change of a resource and the rule that requires it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlanEntry {
+    pub resource_type: String,
+    pub address: String,
+    pub kind: ChangeKind,
+    pub rule: String,
+    pub fields: Vec<FieldChange>,
+}
+
+impl PlanEntry {
+    pub fn add(after: Resource, rule: &str) -> PlanEntry {
+        Self::new(None, Some(after), ChangeKind::Add, rule)
+    }
+
+    pub fn change(before: Resource, after: Resource, rule: &str) -> PlanEntry {
+        Self::new(Some(before), Some(after), ChangeKind::Change, rule)
+    }
+
+    pub fn destroy(before: Resource, rule: &str) -> PlanEntry {
+        Self::new(Some(before), None, ChangeKind::Destroy, rule)
+    }
+
+    fn new(
+        before: Option<Resource>,
+        after: Option<Resource>,
+        kind: ChangeKind,
+        rule: &str,
+    ) -> PlanEntry {
+        let template = after
+            .as_ref()
+            .or(before.as_ref())
+            .expect("plan entry requires a resource");
+
+        let mut names: Vec<&String> = vec![];
+        for resource in after.iter().chain(before.iter()) {
+            for (name, _) in &resource.fields {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+
+        let fields = names
+            .into_iter()
+            .map(|name| FieldChange {
+                field: name.to_string(),
+                before: before
+                    .as_ref()
+                    .and_then(|resource| resource.value(name).cloned()),
+                after: after
+                    .as_ref()
+                    .and_then(|resource| resource.value(name).cloned()),
+                key: template.keys.contains(name),
+            })
+            .collect();
+
+        PlanEntry {
+            resource_type: template.resource_type.clone(),
+            address: template.address.clone(),
+            kind,
+            rule: rule.to_string(),
+            fields,
+        }
+    }
+
+    /// Fields worth rendering, which for changes are only the changed and
+    /// identifying fields.
+    fn rendered_fields(&self) -> impl Iterator<Item = &FieldChange> {
+        self.fields.iter().filter(move |change| {
+            self.kind != ChangeKind::Change || change.key || change.is_changed()
+        })
+    }
+
+    /// Returns a copy with all emails in the address and values masked.
+    pub fn redacted(&self) -> PlanEntry {
+        let redact = |value: &Option<String>| value.as_deref().map(util::redact_emails);
+        PlanEntry {
+            address: util::redact_emails(&self.address),
+            fields: self
+                .fields
+                .iter()
+                .map(|change| FieldChange {
+                    before: redact(&change.before),
+                    after: redact(&change.after),
+                    ..change.clone()
+                })
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Describes the fields of the resource that do not have the value this
+    /// change expects, or that the resource still or not yet exists.
+    pub fn mismatches(&self, actual: Option<&Resource>) -> Vec<String> {
+        let actual = match (self.kind, actual) {
+            (ChangeKind::Destroy, None) => return vec![],
+            (ChangeKind::Destroy, Some(_)) => return vec!["resource still exists".to_string()],
+            (_, None) => return vec!["resource does not exist".to_string()],
+            (_, Some(actual)) => actual,
+        };
+        self.fields
+            .iter()
+            .filter(|change| !change.key)
+            .filter_map(|change| {
+                let expected = change.after.as_deref()?;
+                let found = actual.value(&change.field).map(String::as_str);
+                match found == Some(expected) {
+                    true => None,
+                    false => Some(format!(
+                        "{} is {} instead of {}",
+                        change.field,
+                        found.unwrap_or("unset"),
+                        expected
+                    )),
+                }
+            })
+            .collect()
+    }
+
+    /// Describes the change in a single line.
+    pub fn describe(&self) -> String {
+        format!(
+            "{} {} {} ({})",
+            self.kind.as_str(),
+            self.resource_type,
+            self.address,
+            self.rule
+        )
+    }
+
+    /// Renders the resource before and after the change as text blocks.
+    pub fn render_text(&self) -> (String, String) {
+        let before: Vec<(&str, &str)> = self
+            .rendered_fields()
+            .filter_map(|change| {
+                change
+                    .before
+                    .as_deref()
+                    .map(|value| (change.field.as_str(), value))
+            })
+            .collect();
+        let after: Vec<(&str, &str)> = self
+            .rendered_fields()
+            .filter_map(|change| {
+                change
+                    .after
+                    .as_deref()
+                    .map(|value| (change.field.as_str(), value))
+            })
+            .collect();
+        (
+            format_block(&self.resource_type, &before),
+            format_block(&self.resource_type, &after),
+        )
+    }
+
+    pub fn print(&self, diff_style: DiffStyle) -> Result<()> {
+        let (before, after) = self.render_text();
+        match diff_style {
+            DiffStyle::Unified => util::print_diff(&before, &after, &self.rule),
+            DiffStyle::SideBySide => util::print_side_by_side(&before, &after, &self.rule),
+        }
+    }
+}
+
+fn format_block(resource_type: &str, fields: &[(&str, &str)]) -> String {
+    if fields.is_empty() {
+        return String::new();
+    }
+    let width = fields.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    let mut text = format!("{} {{\n", resource_type);
+    for (name, value) in fields {
+        text.push_str(&format!("\t{:width$} = {}\n", name, value, width = width));
+    }
+    text.push('}');
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::gitlab::types::{ProjectFeatureAccessLevel, ProjectMergeMethod};
+
+    #[test]
+    fn render_markdown_section_test() {
+        let mut summary = PlanSummary::new("GitLab 'archlinux' group members");
+        summary.destroy += 1;
+        let entry = PlanEntry::destroy(
+            Resource::gitlab_member_access("archlinux", "bob", AccessLevel::Minimal),
+            "only staff may be group members",
+        );
+
+        assert_eq!(
+            render_markdown_section(&summary, &[entry]),
+            "<details>\n\
+            <summary>:warning: <b>GitLab 'archlinux' group members</b>: \
+            0 to add, 0 to change, 1 to destroy</summary>\n\n\
+            `archlinux:bob` destroy _(only staff may be group members)_\n\n\
+            ```diff\n\
+            -gitlab_member_access {\n\
+            -\tnamespace    = archlinux\n\
+            -\tusername     = bob\n\
+            -\taccess_level = minimal\n\
+            -}\n\
+            ```\n\n\
+            </details>\n"
+        );
+    }
+
+    #[test]
+    fn group_entries_by_type_test() {
+        let entries = vec![
+            PlanEntry::add(
+                Resource::gitlab_member_access("archlinux", "alice", AccessLevel::Minimal),
+                "team Staff mapping",
+            ),
+            PlanEntry::change(
+                Resource::gitlab_user("bob", true),
+                Resource::gitlab_user("bob", false),
+                "only devops may be admins",
+            ),
+            PlanEntry::destroy(
+                Resource::gitlab_member_access("archlinux", "carol", AccessLevel::Minimal),
+                "only staff may be group members",
+            ),
+        ];
+
+        let groups = group_entries_by_type(entries);
+        let counts: Vec<(&str, u64, u64, u64, usize)> = groups
+            .iter()
+            .map(|(summary, entries)| {
+                (
+                    summary.name(),
+                    summary.add,
+                    summary.change,
+                    summary.destroy,
+                    entries.len(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                ("gitlab_member_access", 1, 0, 1, 2),
+                ("gitlab_user", 0, 1, 0, 1),
+            ]
+        );
+        assert_eq!(groups[0].1[1].address, "archlinux:carol");
+    }
+
+    #[test]
+    fn change_entry_test() {
+        let entry = PlanEntry::change(
+            Resource::gitlab_member_access("archlinux", "alice", AccessLevel::Developer),
+            Resource::gitlab_member_access("archlinux", "alice", AccessLevel::Minimal),
+            "team Staff mapping",
+        );
+
+        assert_eq!(entry.kind, ChangeKind::Change);
+        assert_eq!(entry.address, "archlinux:alice");
+        let changed: Vec<&FieldChange> = entry.fields.iter().filter(|f| f.is_changed()).collect();
+        assert_eq!(
+            changed,
+            vec![&FieldChange {
+                field: "access_level".to_string(),
+                before: Some("developer".to_string()),
+                after: Some("minimal".to_string()),
+                key: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn render_json_plan_test() {
+        let entry = PlanEntry::destroy(
+            Resource::gitlab_member_access("archlinux", "bob", AccessLevel::Minimal),
+            "only staff may be group members",
+        );
+        let mut totals = BTreeMap::new();
+        totals.insert(
+            "GitLab".to_string(),
+            ComponentTotals {
+                sections: 2,
+                changed_sections: 1,
+                destroy: 1,
+                ..ComponentTotals::default()
+            },
+        );
+        let json = render_json_plan(
+            std::slice::from_ref(&entry),
+            &totals,
+            &overall_totals(&totals),
+            Duration::from_secs(3),
+        )
+        .unwrap();
+
+        let plan: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(plan["format_version"], 1);
+        assert_eq!(plan["changes"][0]["kind"], "destroy");
+        assert_eq!(
+            serde_json::from_value::<PlanEntry>(plan["changes"][0].clone()).unwrap(),
+            entry
+        );
+        assert_eq!(plan["components"]["GitLab"]["destroy"], 1);
+        assert_eq!(plan["total"]["sections"], 2);
+        assert_eq!(plan["duration_secs"], 3);
+    }
+
+    #[test]
+    fn mismatches_test() {
+        let member =
+            |access_level| Resource::gitlab_member_access("archlinux", "alice", access_level);
+        let change = PlanEntry::change(
+            member(AccessLevel::Developer),
+            member(AccessLevel::Minimal),
+            "team Staff mapping",
+        );
+        assert!(change
+            .mismatches(Some(&member(AccessLevel::Minimal)))
+            .is_empty());
+        assert_eq!(
+            change.mismatches(Some(&member(AccessLevel::Developer))),
+            vec!["access_level is developer instead of minimal"]
+        );
+        assert_eq!(change.mismatches(None), vec!["resource does not exist"]);
+
+        let destroy = PlanEntry::destroy(member(AccessLevel::Minimal), "only staff");
+        assert!(destroy.mismatches(None).is_empty());
+        assert_eq!(
+            destroy.mismatches(Some(&member(AccessLevel::Minimal))),
+            vec!["resource still exists"]
+        );
+    }
+
+    #[test]
+    fn render_text_changed_fields_test() {
+        let settings = ProjectSettings {
+            request_access_enabled: true,
+            issues_access_level: ProjectFeatureAccessLevel::Enabled,
+            merge_requests_access_level: ProjectFeatureAccessLevel::Enabled,
+            wiki_access_level: ProjectFeatureAccessLevel::Enabled,
+            builds_access_level: ProjectFeatureAccessLevel::Enabled,
+            snippets_access_level: ProjectFeatureAccessLevel::Disabled,
+            merge_method: ProjectMergeMethod::Merge,
+        };
+        let entry = PlanEntry::change(
+            Resource::gitlab_project_settings("archlinux/gluebuddy", &settings),
+            Resource::gitlab_project_settings(
+                "archlinux/gluebuddy",
+                &ProjectSettings {
+                    request_access_enabled: false,
+                    ..settings
+                },
+            ),
+            "project settings policy",
+        );
+
+        let (before, after) = entry.render_text();
+        assert_eq!(
+            before,
+            "gitlab_project_setting {\n\
+            \tnamespace              = archlinux/gluebuddy\n\
+            \trequest_access_enabled = true\n\
+            }"
+        );
+        assert_eq!(
+            after,
+            "gitlab_project_setting {\n\
+            \tnamespace              = archlinux/gluebuddy\n\
+            \trequest_access_enabled = false\n\
+            }"
+        );
+    }
+
+    #[test]
+    fn render_text_test() {
+        let entry = PlanEntry::add(
+            Resource::gitlab_member_access("archlinux", "alice", AccessLevel::Minimal),
+            "team Staff mapping",
+        );
+
+        let (before, after) = entry.render_text();
+        assert_eq!(before, "");
+        assert_eq!(
+            after,
+            "gitlab_member_access {\n\
+            \tnamespace    = archlinux\n\
+            \tusername     = alice\n\
+            \taccess_level = minimal\n\
+            }"
+        );
+    }
+}
@@ -0,0 +1,189 @@
+//! This module compares the planned changes of two event logs written with
+//! `--events` or JSON plans written with `--output json`, to review whether a
+//! code or configuration change altered what gluebuddy plans to do.
+
+use crate::plan::PlanEntry;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+const RESOURCE_PLANNED_EVENT: &str = "resource_planned";
+
+#[derive(Debug, Deserialize)]
+struct JsonPlan {
+    changes: Vec<PlanEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PlannedEvent {
+    event: String,
+    #[serde(default)]
+    entry: Option<PlanEntry>,
+}
+
+/// Differences between the planned changes of two runs.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PlanDiff {
+    /// Changes only planned by the new run
+    pub added: Vec<PlanEntry>,
+    /// Changes only planned by the old run
+    pub removed: Vec<PlanEntry>,
+    /// Changes of the same resource that differ, old and new
+    pub changed: Vec<(PlanEntry, PlanEntry)>,
+}
+
+impl PlanDiff {
+    pub fn new(old: Vec<PlanEntry>, new: Vec<PlanEntry>) -> PlanDiff {
+        let key = |entry: &PlanEntry| (entry.resource_type.clone(), entry.address.clone());
+        let mut old: BTreeMap<(String, String), PlanEntry> =
+            old.into_iter().map(|entry| (key(&entry), entry)).collect();
+        let mut diff = PlanDiff::default();
+        let new: BTreeMap<(String, String), PlanEntry> =
+            new.into_iter().map(|entry| (key(&entry), entry)).collect();
+        for (key, entry) in new {
+            match old.remove(&key) {
+                None => diff.added.push(entry),
+                Some(previous) if previous != entry => diff.changed.push((previous, entry)),
+                Some(_) => {}
+            }
+        }
+        diff.removed = old.into_values().collect();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    pub fn print(&self) {
+        if self.is_empty() {
+            println!("Both runs planned the same changes");
+            return;
+        }
+        for entry in &self.removed {
+            println!("- {}", entry.describe());
+        }
+        for entry in &self.added {
+            println!("+ {}", entry.describe());
+        }
+        for (old, new) in &self.changed {
+            println!("~ {}", old.describe());
+            println!("  {}", new.describe());
+        }
+        println!(
+            "{} no longer planned, {} newly planned, {} planned differently",
+            self.removed.len(),
+            self.added.len(),
+            self.changed.len()
+        );
+    }
+}
+
+/// Loads the planned changes of an event log or a JSON plan.
+pub fn load(path: &Path) -> Result<Vec<PlanEntry>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read plan {}", path.display()))?;
+    parse(&content).with_context(|| format!("Invalid event log or JSON plan {}", path.display()))
+}
+
+fn parse(content: &str) -> Result<Vec<PlanEntry>> {
+    if let Ok(plan) = serde_json::from_str::<JsonPlan>(content) {
+        return Ok(plan.changes);
+    }
+    let mut entries = vec![];
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let event: PlannedEvent = serde_json::from_str(line)?;
+        if event.event == RESOURCE_PLANNED_EVENT {
+            entries.extend(event.entry);
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::Resource;
+    use gitlab::api::common::AccessLevel;
+
+    fn member(group: &str, username: &str, access_level: AccessLevel) -> Resource {
+        Resource::gitlab_member_access(group, username, access_level)
+    }
+
+    #[test]
+    fn diff_test() {
+        let unchanged = PlanEntry::destroy(
+            member("archlinux", "bob", AccessLevel::Minimal),
+            "only staff may be group members",
+        );
+        let old = vec![
+            unchanged.clone(),
+            PlanEntry::add(
+                member("archlinux/teams/staff", "alice", AccessLevel::Reporter),
+                "team Staff mapping",
+            ),
+            PlanEntry::destroy(
+                member("archlinux", "carol", AccessLevel::Minimal),
+                "only staff may be group members",
+            ),
+        ];
+        let new = vec![
+            unchanged,
+            PlanEntry::add(
+                member("archlinux/teams/staff", "alice", AccessLevel::Developer),
+                "team Staff mapping",
+            ),
+            PlanEntry::add(
+                member("archlinux/teams/staff", "dave", AccessLevel::Reporter),
+                "team Staff mapping",
+            ),
+        ];
+
+        let diff = PlanDiff::new(old.clone(), new.clone());
+        assert_eq!(diff.added, vec![new[2].clone()]);
+        assert_eq!(diff.removed, vec![old[2].clone()]);
+        assert_eq!(diff.changed, vec![(old[1].clone(), new[1].clone())]);
+        assert!(PlanDiff::new(old.clone(), old).is_empty());
+    }
+
+    #[test]
+    fn parse_test() {
+        let entry = PlanEntry::destroy(
+            member("archlinux", "bob", AccessLevel::Minimal),
+            "only staff may be group members",
+        );
+        let content = format!(
+            "{}\n{}\n{}\n",
+            r#"{"timestamp":"2022-01-01T00:00:00Z","component":"GitLab","event":"gather_started"}"#,
+            serde_json::json!({
+                "timestamp": "2022-01-01T00:00:01Z",
+                "component": "GitLab",
+                "event": "resource_planned",
+                "entry": entry,
+            }),
+            r#"{"timestamp":"2022-01-01T00:00:02Z","component":"GitLab","event":"resource_applied","resource_type":"gitlab_member_access","address":"archlinux:bob"}"#,
+        );
+        assert_eq!(parse(&content).unwrap(), vec![entry]);
+    }
+
+    #[test]
+    fn parse_json_plan_test() {
+        let entry = PlanEntry::destroy(
+            member("archlinux", "bob", AccessLevel::Minimal),
+            "only staff may be group members",
+        );
+        let content = serde_json::to_string_pretty(&serde_json::json!({
+            "format_version": 1,
+            "changes": [entry],
+            "components": {},
+            "total": {},
+            "duration_secs": 3,
+        }))
+        .unwrap();
+        assert_eq!(parse(&content).unwrap(), vec![entry]);
+    }
+}
@@ -0,0 +1,190 @@
+//! This module lets the operator review the planned changes of a run entry by
+//! entry in the terminal, to apply only the approved subset afterwards.
+
+use crate::error::ConfigError;
+use crate::plan::{DiffStyle, PlanEntry};
+
+use std::io::{self, BufRead, Write};
+
+use anyhow::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Answer {
+    Approve,
+    Deny,
+    /// Approve this and all remaining entries of the resource type
+    ApproveType,
+    /// Deny this and all remaining entries of the resource type
+    DenyType,
+    /// Show the diff of the entry again
+    Show,
+    /// Stop the review without applying anything
+    Quit,
+}
+
+impl Answer {
+    fn parse(line: &str) -> Option<Answer> {
+        match line.trim().to_lowercase().as_str() {
+            "y" | "yes" => Some(Answer::Approve),
+            "n" | "no" => Some(Answer::Deny),
+            "a" | "all" => Some(Answer::ApproveType),
+            "s" | "skip" => Some(Answer::DenyType),
+            "d" | "diff" => Some(Answer::Show),
+            "q" | "quit" => Some(Answer::Quit),
+            _ => None,
+        }
+    }
+}
+
+/// Reviews the entries on the terminal and returns the approved ones, which
+/// are none if the operator quits or does not confirm them.
+pub fn review(entries: &[PlanEntry], diff_style: DiffStyle) -> Result<Vec<PlanEntry>> {
+    if !atty::is(atty::Stream::Stdin) {
+        return Err(ConfigError::Invalid {
+            name: "--interactive".to_string(),
+            reason: "reviewing requires a terminal on stdin".to_string(),
+        }
+        .into());
+    }
+    if entries.is_empty() {
+        println!("No changes to review");
+        return Ok(vec![]);
+    }
+    let stdin = io::stdin();
+    review_with(entries, diff_style, &mut stdin.lock())
+}
+
+fn review_with(
+    entries: &[PlanEntry],
+    diff_style: DiffStyle,
+    input: &mut impl BufRead,
+) -> Result<Vec<PlanEntry>> {
+    let mut entries = entries.to_vec();
+    entries.sort_by(|a, b| a.resource_type.cmp(&b.resource_type));
+
+    let mut approved = vec![];
+    let mut decided_type: Option<(&str, bool)> = None;
+    for (index, entry) in entries.iter().enumerate() {
+        if let Some((resource_type, approve)) = decided_type {
+            if resource_type == entry.resource_type {
+                if approve {
+                    approved.push(entry.clone());
+                }
+                continue;
+            }
+        }
+        if index == 0 || entries[index - 1].resource_type != entry.resource_type {
+            let count = entries
+                .iter()
+                .filter(|other| other.resource_type == entry.resource_type)
+                .count();
+            println!("== {}: {} planned changes", entry.resource_type, count);
+        }
+
+        entry.print(diff_style)?;
+        let answer = loop {
+            let answer = prompt(
+                &format!(
+                    "[{}/{}] Approve {}? [y]es, [n]o, [a]ll or [s]kip {}, [d]iff, [q]uit: ",
+                    index + 1,
+                    entries.len(),
+                    entry.describe(),
+                    entry.resource_type
+                ),
+                input,
+            )?;
+            match answer.as_deref().map(Answer::parse) {
+                None => break Answer::Quit,
+                Some(Some(Answer::Show)) => entry.print(diff_style)?,
+                Some(Some(answer)) => break answer,
+                Some(None) => println!("Unknown answer"),
+            }
+        };
+        match answer {
+            Answer::Approve => approved.push(entry.clone()),
+            Answer::Deny | Answer::Show => {}
+            Answer::ApproveType => {
+                approved.push(entry.clone());
+                decided_type = Some((&entry.resource_type, true));
+            }
+            Answer::DenyType => decided_type = Some((&entry.resource_type, false)),
+            Answer::Quit => {
+                println!("Review aborted, nothing will be applied");
+                return Ok(vec![]);
+            }
+        }
+    }
+
+    if approved.is_empty() {
+        return Ok(approved);
+    }
+    let confirmation = prompt(
+        &format!(
+            "Apply {} of {} planned changes? [y]es, [n]o: ",
+            approved.len(),
+            entries.len()
+        ),
+        input,
+    )?;
+    match confirmation.as_deref().map(Answer::parse) {
+        Some(Some(Answer::Approve)) => Ok(approved),
+        _ => {
+            println!("Nothing will be applied");
+            Ok(vec![])
+        }
+    }
+}
+
+/// Prints the question and reads the answer, which is `None` at the end of
+/// the input.
+fn prompt(question: &str, input: &mut impl BufRead) -> Result<Option<String>> {
+    print!("{}", question);
+    io::stdout().flush()?;
+    let mut line = String::new();
+    match input.read_line(&mut line)? {
+        0 => Ok(None),
+        _ => Ok(Some(line)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::Resource;
+    use gitlab::api::common::AccessLevel;
+    use rstest::rstest;
+    use std::io::Cursor;
+
+    fn entries() -> Vec<PlanEntry> {
+        vec![
+            PlanEntry::add(
+                Resource::gitlab_member_access("archlinux", "alice", AccessLevel::Minimal),
+                "staff",
+            ),
+            PlanEntry::add(Resource::keycloak_user("bob", true), "stale"),
+            PlanEntry::add(
+                Resource::gitlab_member_access("archlinux", "carol", AccessLevel::Minimal),
+                "staff",
+            ),
+            PlanEntry::add(Resource::keycloak_user("dave", true), "stale"),
+        ]
+    }
+
+    #[rstest]
+    #[case("y\nn\ny\nn\ny\n", vec!["archlinux:alice", "bob"])]
+    #[case("a\nn\ny\ny\n", vec!["archlinux:alice", "archlinux:carol", "dave"])]
+    #[case("s\ny\nd\nn\ny\n", vec!["bob"])]
+    #[case("maybe\ny\ns\nn\nn\ny\n", vec!["archlinux:alice"])]
+    #[case("y\nq\n", vec![])]
+    #[case("y\ny\ny\ny\nn\n", vec![])]
+    #[case("y\n", vec![])]
+    fn review_with_test(#[case] input: &str, #[case] expected: Vec<&str>) {
+        let approved =
+            review_with(&entries(), DiffStyle::Unified, &mut Cursor::new(input)).unwrap();
+        let addresses: Vec<&str> = approved
+            .iter()
+            .map(|entry| entry.address.as_str())
+            .collect();
+        assert_eq!(addresses, expected);
+    }
+}
@@ -0,0 +1,231 @@
+//! This module persists a compact membership snapshot after each run and
+//! reports the churn compared to the snapshot of the previous run.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::state::State;
+use crate::util;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub created: u64,
+    /// Keycloak group path to usernames
+    pub teams: BTreeMap<String, BTreeSet<String>>,
+    /// GitLab group path to usernames and their access level
+    pub gitlab_access_levels: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TeamChurn {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct AccessLevelChange {
+    pub group: String,
+    pub username: String,
+    pub before: String,
+    pub after: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Churn {
+    pub teams: BTreeMap<String, TeamChurn>,
+    pub access_levels: Vec<AccessLevelChange>,
+}
+
+impl Snapshot {
+    pub fn new(
+        state: &State,
+        gitlab_access_levels: &BTreeMap<String, BTreeMap<String, u64>>,
+    ) -> Result<Snapshot> {
+        let mut teams: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for user in state.users.values() {
+            for group in &user.groups {
+                teams
+                    .entry(group.clone())
+                    .or_default()
+                    .insert(user.username.clone());
+            }
+        }
+
+        let gitlab_access_levels = gitlab_access_levels
+            .iter()
+            .map(|(group, members)| {
+                let members = members
+                    .iter()
+                    .map(|(username, access_level)| {
+                        (
+                            username.clone(),
+                            util::access_level_from_u64(*access_level)
+                                .as_str()
+                                .to_string(),
+                        )
+                    })
+                    .collect();
+                (group.clone(), members)
+            })
+            .collect();
+
+        Ok(Snapshot {
+            created: util::unix_timestamp()?,
+            teams,
+            gitlab_access_levels,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Option<Snapshot>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read snapshot {}", path.display()))?;
+        let snapshot = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse snapshot {}", path.display()))?;
+        Ok(Some(snapshot))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string(self)?)
+            .with_context(|| format!("Failed to write snapshot {}", path.display()))
+    }
+
+    pub fn churn_since(&self, previous: &Snapshot) -> Churn {
+        let mut churn = Churn::default();
+
+        let empty = BTreeSet::new();
+        let groups: BTreeSet<&String> = self.teams.keys().chain(previous.teams.keys()).collect();
+        for group in groups {
+            let before = previous.teams.get(group).unwrap_or(&empty);
+            let after = self.teams.get(group).unwrap_or(&empty);
+            let team_churn = TeamChurn {
+                added: after.difference(before).cloned().collect(),
+                removed: before.difference(after).cloned().collect(),
+            };
+            if !team_churn.added.is_empty() || !team_churn.removed.is_empty() {
+                churn.teams.insert(group.clone(), team_churn);
+            }
+        }
+
+        for (group, members) in &self.gitlab_access_levels {
+            let previous_members = match previous.gitlab_access_levels.get(group) {
+                None => continue,
+                Some(members) => members,
+            };
+            for (username, after) in members {
+                if let Some(before) = previous_members.get(username) {
+                    if before != after {
+                        churn.access_levels.push(AccessLevelChange {
+                            group: group.clone(),
+                            username: username.clone(),
+                            before: before.clone(),
+                            after: after.clone(),
+                        });
+                    }
+                }
+            }
+        }
+
+        churn
+    }
+}
+
+impl Churn {
+    pub fn print(&self, since: u64) {
+        println!("Membership churn since {} (unix time):", since);
+        if self.teams.is_empty() && self.access_levels.is_empty() {
+            println!("No membership changes.");
+        }
+        for (group, team_churn) in &self.teams {
+            println!(
+                "{}: {} added, {} removed",
+                group,
+                team_churn.added.len(),
+                team_churn.removed.len()
+            );
+            for username in &team_churn.added {
+                println!("\t+ {}", username);
+            }
+            for username in &team_churn.removed {
+                println!("\t- {}", username);
+            }
+        }
+        for change in &self.access_levels {
+            println!(
+                "{}: {} access_level {} -> {}",
+                change.group, change.username, change.before, change.after
+            );
+        }
+        println!("{}", util::format_separator());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(teams: &[(&str, &[&str])], levels: &[(&str, &str, &str)]) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        for (group, users) in teams {
+            snapshot.teams.insert(
+                group.to_string(),
+                users.iter().map(|user| user.to_string()).collect(),
+            );
+        }
+        for (group, username, level) in levels {
+            snapshot
+                .gitlab_access_levels
+                .entry(group.to_string())
+                .or_default()
+                .insert(username.to_string(), level.to_string());
+        }
+        snapshot
+    }
+
+    #[test]
+    fn churn_since_test() {
+        let previous = snapshot(
+            &[("/Arch Linux Staff/DevOps", &["alice", "bob"])],
+            &[("archlinux/teams/staff", "alice", "reporter")],
+        );
+        let current = snapshot(
+            &[
+                ("/Arch Linux Staff/DevOps", &["alice", "carol"]),
+                ("/Arch Linux Staff/Security", &["dave"]),
+            ],
+            &[("archlinux/teams/staff", "alice", "developer")],
+        );
+
+        let churn = current.churn_since(&previous);
+
+        assert_eq!(
+            churn.teams.get("/Arch Linux Staff/DevOps"),
+            Some(&TeamChurn {
+                added: vec!["carol".to_string()],
+                removed: vec!["bob".to_string()],
+            })
+        );
+        assert_eq!(
+            churn.teams.get("/Arch Linux Staff/Security"),
+            Some(&TeamChurn {
+                added: vec!["dave".to_string()],
+                removed: vec![],
+            })
+        );
+        assert_eq!(
+            churn.access_levels,
+            vec![AccessLevelChange {
+                group: "archlinux/teams/staff".to_string(),
+                username: "alice".to_string(),
+                before: "reporter".to_string(),
+                after: "developer".to_string(),
+            }]
+        );
+    }
+}
@@ -1,9 +1,33 @@
-use std::collections::{HashMap, HashSet};
+use anyhow::{anyhow, Result};
+use gitlab::api::common::AccessLevel;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
-#[derive(Eq, PartialEq, Debug)]
+/// GitLab group the default team groups live below.
+pub const DEFAULT_GITLAB_ROOT_GROUP: &str = "archlinux";
+
+/// Keycloak group whose direct subgroups are teams.
+const STAFF_GROUP: &str = "/Arch Linux Staff/";
+
+/// Access level members get in the GitLab groups of discovered teams.
+const DISCOVERED_TEAM_ACCESS_LEVEL: AccessLevel = AccessLevel::Reporter;
+
+const PACKAGER_GROUPS: [&str; 2] = [
+    "/Arch Linux Staff/Developers",
+    "/Arch Linux Staff/Package Maintainers",
+];
+
+#[derive(Eq, PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub username: String,
+    pub email: Option<String>,
+    pub keycloak_id: Option<String>,
+    pub enabled: bool,
+    pub attributes: HashMap<String, Vec<String>>,
+    /// Historical usernames or emails this account was known as
+    pub aliases: Vec<String>,
     pub gitlab_id: Option<u64>,
+    pub gitlab_username: Option<String>,
     pub groups: HashSet<String>,
 }
 
@@ -11,15 +35,40 @@ impl User {
     pub fn new(username: String) -> User {
         User {
             username,
+            email: None,
+            keycloak_id: None,
+            enabled: true,
+            attributes: HashMap::new(),
+            aliases: vec![],
             gitlab_id: None,
+            gitlab_username: None,
             groups: HashSet::new(),
         }
     }
 
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .get(name)
+            .and_then(|values| values.first())
+            .map(|value| value.as_str())
+    }
+
+    pub fn attribute_values(&self, name: &str) -> &[String] {
+        self.attributes
+            .get(name)
+            .map(|values| values.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Whether the name is the current username or one of the aliases.
+    pub fn is_known_as(&self, name: &str) -> bool {
+        self.username.eq(name) || self.aliases.iter().any(|alias| alias.eq(name))
+    }
+
     pub fn is_staff(&self) -> bool {
         self.groups
             .iter()
-            .any(|group| group.starts_with("/Arch Linux Staff/"))
+            .any(|group| group.starts_with(STAFF_GROUP))
     }
 
     pub fn is_external_contributor(&self) -> bool {
@@ -28,66 +77,480 @@ impl User {
             .any(|group| group.starts_with("/External Contributors"))
     }
 
+    pub fn is_packager(&self) -> bool {
+        self.groups.iter().any(|group| {
+            PACKAGER_GROUPS
+                .iter()
+                .any(|packager| group.starts_with(packager))
+        })
+    }
+
     pub fn is_devops(&self) -> bool {
         self.groups
             .iter()
-            .any(|group| group.starts_with("/Arch Linux Staff/DevOps"))
+            .any(|group| is_in_group(group, "/Arch Linux Staff/DevOps"))
+    }
+}
+
+/// Stable serializable representation of a user for consumption by other tools.
+#[derive(Debug, Serialize)]
+pub struct UserExport<'a> {
+    pub username: &'a str,
+    pub email: Option<&'a str>,
+    pub keycloak_id: Option<&'a str>,
+    pub enabled: bool,
+    pub gitlab_id: Option<u64>,
+    pub gitlab_username: Option<&'a str>,
+    pub groups: BTreeSet<&'a str>,
+    pub attributes: BTreeMap<&'a str, &'a [String]>,
+}
+
+/// Stable serializable representation of the consolidated state.
+#[derive(Debug, Serialize)]
+pub struct StateExport<'a> {
+    pub users: Vec<UserExport<'a>>,
+}
+
+/// A GitLab group managed for a team and the access level its members get.
+#[derive(Debug, Clone)]
+pub struct TeamGitLabGroup {
+    pub path: String,
+    pub access_level: AccessLevel,
+}
+
+/// A team is a Keycloak group whose members are mirrored into GitLab groups.
+#[derive(Debug, Clone)]
+pub struct Team {
+    pub name: String,
+    /// Keycloak group path, members of sub groups are part of the team as well
+    pub keycloak_group: String,
+    pub gitlab_groups: Vec<TeamGitLabGroup>,
+}
+
+impl Team {
+    pub fn new(name: &str, keycloak_group: &str) -> Team {
+        Team {
+            name: name.to_string(),
+            keycloak_group: keycloak_group.to_string(),
+            gitlab_groups: vec![],
+        }
+    }
+
+    pub fn with_gitlab_group(mut self, path: &str, access_level: AccessLevel) -> Team {
+        self.gitlab_groups.push(TeamGitLabGroup {
+            path: path.to_string(),
+            access_level,
+        });
+        self
+    }
+
+    /// Replaces the access level of the team groups listed in the overrides.
+    pub fn with_access_level_overrides(mut self, overrides: &HashMap<String, AccessLevel>) -> Team {
+        for group in &mut self.gitlab_groups {
+            if let Some(access_level) = overrides.get(&group.path) {
+                group.access_level = *access_level;
+            }
+        }
+        self
+    }
+
+    pub fn contains(&self, user: &User) -> bool {
+        user.groups
+            .iter()
+            .any(|group| is_in_group(group, &self.keycloak_group))
+    }
+
+    /// Returns the default teams with their GitLab groups below the root group.
+    pub fn defaults(root_group: &str) -> Vec<Team> {
+        vec![
+            Team::new("Staff", "/Arch Linux Staff/")
+                .with_gitlab_group(root_group, AccessLevel::Minimal)
+                .with_gitlab_group(
+                    &format!("{}/teams/staff", root_group),
+                    AccessLevel::Reporter,
+                ),
+            Team::new("DevOps", "/Arch Linux Staff/DevOps").with_gitlab_group(
+                &format!("{}/teams/devops", root_group),
+                AccessLevel::Developer,
+            ),
+        ]
     }
 }
 
-#[derive(Default)]
+#[derive(Clone)]
 pub struct State {
     pub users: HashMap<String, User>,
+    pub teams: Vec<Team>,
+    /// Usernames by GitLab id, rebuilt with `index_gitlab_ids`
+    gitlab_ids: HashMap<u64, String>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            users: HashMap::new(),
+            teams: Team::defaults(DEFAULT_GITLAB_ROOT_GROUP),
+            gitlab_ids: HashMap::new(),
+        }
+    }
 }
 
 impl State {
+    pub fn export(&self) -> StateExport<'_> {
+        let mut users: Vec<UserExport> = self
+            .users
+            .values()
+            .map(|user| UserExport {
+                username: &user.username,
+                email: user.email.as_deref(),
+                keycloak_id: user.keycloak_id.as_deref(),
+                enabled: user.enabled,
+                gitlab_id: user.gitlab_id,
+                gitlab_username: user.gitlab_username.as_deref(),
+                groups: user.groups.iter().map(|group| group.as_str()).collect(),
+                attributes: user
+                    .attributes
+                    .iter()
+                    .map(|(name, values)| (name.as_str(), values.as_slice()))
+                    .collect(),
+            })
+            .collect();
+        users.sort_by(|a, b| a.username.cmp(b.username));
+        StateExport { users }
+    }
+
+    /// Adds a team for every direct subgroup of the staff group with members
+    /// that is no team yet, mirrored into `<root_group>/teams/<team>`.
+    /// Returns the added teams and an error for every team whose group path
+    /// collides with the group of an existing team, which is not added.
+    pub fn discover_teams(
+        &mut self,
+        root_group: &str,
+        access_levels: &HashMap<String, AccessLevel>,
+    ) -> Vec<Result<Team>> {
+        let names: BTreeSet<String> = self
+            .users
+            .values()
+            .filter(|user| user.enabled)
+            .flat_map(|user| &user.groups)
+            .filter_map(|group| group.strip_prefix(STAFF_GROUP)?.split('/').next())
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect();
+        let mut discovered = vec![];
+        for name in names {
+            let path = format!("{}{}", STAFF_GROUP, name);
+            if self.teams.iter().any(|team| team.keycloak_group == path) {
+                continue;
+            }
+            let group_path = format!("{}/teams/{}", root_group, team_group_path(&name));
+            if let Some(team) = self.teams.iter().find(|team| {
+                team.gitlab_groups
+                    .iter()
+                    .any(|group| group.path == group_path)
+            }) {
+                discovered.push(Err(anyhow!(
+                    "Keycloak team {} collides with team {} on GitLab group {}",
+                    name,
+                    team.name,
+                    group_path
+                )));
+                continue;
+            }
+            let team = Team::new(&name, &path)
+                .with_gitlab_group(&group_path, DISCOVERED_TEAM_ACCESS_LEVEL)
+                .with_access_level_overrides(access_levels);
+            self.teams.push(team.clone());
+            discovered.push(Ok(team));
+        }
+        discovered
+    }
+
+    pub fn team_members(&self, team: &Team) -> Vec<&User> {
+        self.users
+            .values()
+            .filter(|user| user.enabled && team.contains(user))
+            .collect()
+    }
+
+    /// Rebuilds the GitLab id index, must be called after changing GitLab ids.
+    pub fn index_gitlab_ids(&mut self) {
+        self.gitlab_ids = self
+            .users
+            .values()
+            .filter_map(|user| user.gitlab_id.map(|id| (id, user.username.clone())))
+            .collect();
+    }
+
+    pub fn team_member_from_gitlab_id(&self, team: &Team, gitlab_id: u64) -> Option<&User> {
+        self.user_from_gitlab_id(gitlab_id)
+            .filter(|user| user.enabled && team.contains(user))
+    }
+
     pub fn staff(&self) -> Vec<&User> {
-        self.users.values().filter(|user| user.is_staff()).collect()
+        self.users
+            .values()
+            .filter(|user| user.enabled && user.is_staff())
+            .collect()
     }
 
     pub fn staff_with_externals(&self) -> Vec<&User> {
         self.users
             .values()
-            .filter(|user| user.is_staff() || user.is_external_contributor())
+            .filter(|user| user.enabled && (user.is_staff() || user.is_external_contributor()))
+            .collect()
+    }
+
+    pub fn packagers(&self) -> Vec<&User> {
+        self.users
+            .values()
+            .filter(|user| user.enabled && user.is_packager())
             .collect()
     }
 
     pub fn devops(&self) -> Vec<&User> {
         self.users
             .values()
-            .filter(|user| user.is_devops())
+            .filter(|user| user.enabled && user.is_devops())
             .collect()
     }
 
+    pub fn user_from_keycloak_id(&self, keycloak_id: &str) -> Option<&User> {
+        self.users
+            .values()
+            .find(|user| user.keycloak_id.as_deref() == Some(keycloak_id))
+    }
+
     pub fn user_from_gitlab_id(&self, gitlab_id: u64) -> Option<&User> {
-        self.users.values().find(|user| {
-            user.gitlab_id
-                .map(|id| id.eq(&gitlab_id))
-                .unwrap_or_else(|| false)
-        })
+        self.gitlab_ids
+            .get(&gitlab_id)
+            .and_then(|username| self.users.get(username))
     }
 
     pub fn staff_from_gitlab_id(&self, gitlab_id: u64) -> Option<&User> {
-        self.staff().into_iter().find(|user| {
-            user.gitlab_id
-                .map(|id| id.eq(&gitlab_id))
-                .unwrap_or_else(|| false)
-        })
+        self.user_from_gitlab_id(gitlab_id)
+            .filter(|user| user.enabled && user.is_staff())
     }
 
     pub fn staff_with_externals_from_gitlab_id(&self, gitlab_id: u64) -> Option<&User> {
-        self.staff_with_externals().into_iter().find(|user| {
-            user.gitlab_id
-                .map(|id| id.eq(&gitlab_id))
-                .unwrap_or_else(|| false)
-        })
+        self.user_from_gitlab_id(gitlab_id)
+            .filter(|user| user.enabled && (user.is_staff() || user.is_external_contributor()))
     }
 
     pub fn devops_from_gitlab_id(&self, gitlab_id: u64) -> Option<&User> {
-        self.devops().into_iter().find(|user| {
-            user.gitlab_id
-                .map(|id| id.eq(&gitlab_id))
-                .unwrap_or_else(|| false)
-        })
+        self.user_from_gitlab_id(gitlab_id)
+            .filter(|user| user.enabled && user.is_devops())
+    }
+}
+
+/// Returns whether the Keycloak group is the parent group or one of its sub
+/// groups. Groups that merely share the prefix, e.g. `/Arch Linux Staff/DevOps
+/// Foo` for `/Arch Linux Staff/DevOps`, are not.
+fn is_in_group(group: &str, parent: &str) -> bool {
+    group == parent || group.starts_with(&format!("{}/", parent.trim_end_matches('/')))
+}
+
+/// Turns a team name into a GitLab group path, e.g. `package-maintainers`.
+fn team_group_path(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[test]
+    fn team_defaults_test() {
+        let paths: Vec<String> = Team::defaults("bot-test")
+            .into_iter()
+            .flat_map(|team| team.gitlab_groups)
+            .map(|group| group.path)
+            .collect();
+        assert_eq!(
+            paths,
+            vec!["bot-test", "bot-test/teams/staff", "bot-test/teams/devops"]
+        );
+    }
+
+    #[rstest]
+    #[case("/Arch Linux Staff/DevOps", true)]
+    #[case("/Arch Linux Staff/DevOps/Monitoring", true)]
+    #[case("/Arch Linux Staff/DevOps Foo", false)]
+    #[case("/Arch Linux Staff", false)]
+    fn team_contains_test(#[case] group: &str, #[case] expected: bool) {
+        let team = Team::new("DevOps", "/Arch Linux Staff/DevOps");
+        let mut user = User::new("alice".to_string());
+        user.groups = [group.to_string()].into();
+        assert_eq!(team.contains(&user), expected);
+        assert_eq!(user.is_devops(), expected);
+    }
+
+    #[rstest]
+    #[case("/Arch Linux Staff/DevOps", true)]
+    #[case("/Arch Linux Staffers/DevOps", false)]
+    fn staff_team_contains_test(#[case] group: &str, #[case] expected: bool) {
+        let team = &Team::defaults("archlinux")[0];
+        let mut user = User::new("alice".to_string());
+        user.groups = [group.to_string()].into();
+        assert_eq!(team.contains(&user), expected);
+    }
+
+    #[test]
+    fn from_gitlab_id_test() {
+        let mut state = State::default();
+        for (username, group, gitlab_id, enabled) in [
+            ("alice", "/Arch Linux Staff/DevOps", Some(1), true),
+            ("bob", "/External Contributors", Some(2), true),
+            ("carol", "/Arch Linux Staff/Developers", Some(3), false),
+            ("dave", "/Arch Linux Staff/Developers", None, true),
+        ] {
+            let mut user = User::new(username.to_string());
+            user.groups = [group.to_string()].into();
+            user.gitlab_id = gitlab_id;
+            user.enabled = enabled;
+            state.users.insert(user.username.clone(), user);
+        }
+        assert_eq!(state.user_from_gitlab_id(1), None);
+
+        state.index_gitlab_ids();
+        let username = |user: Option<&User>| user.map(|user| user.username.clone());
+        assert_eq!(
+            username(state.devops_from_gitlab_id(1)),
+            Some("alice".into())
+        );
+        assert_eq!(username(state.staff_from_gitlab_id(2)), None);
+        assert_eq!(
+            username(state.staff_with_externals_from_gitlab_id(2)),
+            Some("bob".into())
+        );
+        assert_eq!(username(state.user_from_gitlab_id(3)), Some("carol".into()));
+        assert_eq!(username(state.staff_from_gitlab_id(3)), None);
+        assert_eq!(username(state.user_from_gitlab_id(4)), None);
+    }
+
+    #[rstest]
+    #[case("DevOps", "devops")]
+    #[case("Package Maintainers", "package-maintainers")]
+    #[case("Bug Wranglers & Support", "bug-wranglers-support")]
+    fn team_group_path_test(#[case] name: &str, #[case] expected: &str) {
+        assert_eq!(team_group_path(name), expected);
+    }
+
+    #[test]
+    fn discover_teams_test() {
+        let mut state = State::default();
+        for (username, group, enabled) in [
+            ("alice", "/Arch Linux Staff/DevOps", true),
+            (
+                "bob",
+                "/Arch Linux Staff/Package Maintainers/Security",
+                true,
+            ),
+            ("carol", "/Arch Linux Staff/Wiki Admins", false),
+            ("dave", "/External Contributors", true),
+        ] {
+            let mut user = User::new(username.to_string());
+            user.groups = [group.to_string()].into();
+            user.enabled = enabled;
+            state.users.insert(user.username.clone(), user);
+        }
+        let overrides: HashMap<String, AccessLevel> = [(
+            "archlinux/teams/package-maintainers".to_string(),
+            AccessLevel::Developer,
+        )]
+        .into();
+
+        let discovered: Vec<Team> = state
+            .discover_teams("archlinux", &overrides)
+            .into_iter()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(discovered.len(), 1);
+        assert_eq!(discovered[0].name, "Package Maintainers");
+        assert_eq!(
+            discovered[0].keycloak_group,
+            "/Arch Linux Staff/Package Maintainers"
+        );
+        assert_eq!(
+            discovered[0].gitlab_groups[0].path,
+            "archlinux/teams/package-maintainers"
+        );
+        assert_eq!(
+            discovered[0].gitlab_groups[0].access_level,
+            AccessLevel::Developer
+        );
+        assert_eq!(state.teams.len(), 3);
+        assert!(state.discover_teams("archlinux", &overrides).is_empty());
+    }
+
+    #[test]
+    fn discover_teams_prefix_test() {
+        let mut state = State::default();
+        for (username, group) in [
+            ("alice", "/Arch Linux Staff/DevOps"),
+            ("bob", "/Arch Linux Staff/DevOps Foo"),
+            ("carol", "/Arch Linux Staff/DevOps Foo/Bar"),
+        ] {
+            let mut user = User::new(username.to_string());
+            user.groups = [group.to_string()].into();
+            user.enabled = true;
+            state.users.insert(user.username.clone(), user);
+        }
+
+        let discovered: Vec<Team> = state
+            .discover_teams("archlinux", &HashMap::new())
+            .into_iter()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(discovered.len(), 1);
+        assert_eq!(discovered[0].name, "DevOps Foo");
+        for (team, expected) in [
+            ("DevOps", vec!["alice"]),
+            ("DevOps Foo", vec!["bob", "carol"]),
+        ] {
+            let team = state.teams.iter().find(|t| t.name == team).unwrap();
+            let mut members: Vec<&str> = state
+                .team_members(team)
+                .into_iter()
+                .map(|user| user.username.as_str())
+                .collect();
+            members.sort_unstable();
+            assert_eq!(members, expected);
+        }
+    }
+
+    #[test]
+    fn discover_teams_collision_test() {
+        let mut state = State::default();
+        for (username, group) in [
+            ("alice", "/Arch Linux Staff/Package Maintainers"),
+            ("bob", "/Arch Linux Staff/Package-Maintainers"),
+            ("carol", "/Arch Linux Staff/devops"),
+        ] {
+            let mut user = User::new(username.to_string());
+            user.groups = [group.to_string()].into();
+            user.enabled = true;
+            state.users.insert(user.username.clone(), user);
+        }
+
+        let discovered = state.discover_teams("archlinux", &HashMap::new());
+        let names: Vec<Result<String, String>> = discovered
+            .into_iter()
+            .map(|team| team.map(|team| team.name).map_err(|err| err.to_string()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                Ok("Package Maintainers".to_string()),
+                Err("Keycloak team Package-Maintainers collides with team Package Maintainers on GitLab group archlinux/teams/package-maintainers".to_string()),
+                Err("Keycloak team devops collides with team DevOps on GitLab group archlinux/teams/devops".to_string()),
+            ]
+        );
+        assert_eq!(state.teams.len(), 3);
     }
 }
@@ -0,0 +1,101 @@
+//! This module computes membership statistics from a snapshot and prints
+//! them together with the deltas since a previous snapshot.
+
+use std::collections::BTreeMap;
+
+use crate::snapshot::Snapshot;
+use crate::util;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Keycloak group path to number of members
+    pub keycloak_teams: BTreeMap<String, usize>,
+    /// GitLab group path to number of members
+    pub gitlab_groups: BTreeMap<String, usize>,
+}
+
+impl Stats {
+    pub fn new(snapshot: &Snapshot) -> Stats {
+        Stats {
+            keycloak_teams: snapshot
+                .teams
+                .iter()
+                .map(|(group, members)| (group.clone(), members.len()))
+                .collect(),
+            gitlab_groups: snapshot
+                .gitlab_access_levels
+                .iter()
+                .map(|(group, members)| (group.clone(), members.len()))
+                .collect(),
+        }
+    }
+
+    pub fn print(&self, previous: Option<&Stats>) {
+        println!("Keycloak teams:");
+        print_counts(
+            &self.keycloak_teams,
+            previous.map(|previous| &previous.keycloak_teams),
+        );
+        println!("GitLab groups:");
+        print_counts(
+            &self.gitlab_groups,
+            previous.map(|previous| &previous.gitlab_groups),
+        );
+        println!("{}", util::format_separator());
+    }
+}
+
+fn format_delta(count: usize, previous: Option<usize>) -> String {
+    match previous {
+        None => String::new(),
+        Some(previous) if previous == count => " (±0)".to_string(),
+        Some(previous) if previous < count => format!(" (+{})", count - previous),
+        Some(previous) => format!(" (-{})", previous - count),
+    }
+}
+
+fn print_counts(counts: &BTreeMap<String, usize>, previous: Option<&BTreeMap<String, usize>>) {
+    for (name, count) in counts {
+        let previous_count = previous.map(|previous| previous.get(name).copied().unwrap_or(0));
+        println!(
+            "\t{}: {}{}",
+            name,
+            count,
+            format_delta(*count, previous_count)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_test() {
+        let mut snapshot = Snapshot::default();
+        snapshot.teams.insert(
+            "/Arch Linux Staff/DevOps".to_string(),
+            ["alice", "bob"]
+                .iter()
+                .map(|user| user.to_string())
+                .collect(),
+        );
+        snapshot
+            .gitlab_access_levels
+            .entry("archlinux/teams/devops".to_string())
+            .or_default()
+            .insert("alice".to_string(), "developer".to_string());
+
+        let stats = Stats::new(&snapshot);
+        assert_eq!(
+            stats.keycloak_teams.get("/Arch Linux Staff/DevOps"),
+            Some(&2)
+        );
+        assert_eq!(stats.gitlab_groups.get("archlinux/teams/devops"), Some(&1));
+
+        assert_eq!(format_delta(2, None), "");
+        assert_eq!(format_delta(2, Some(2)), " (±0)");
+        assert_eq!(format_delta(3, Some(1)), " (+2)");
+        assert_eq!(format_delta(1, Some(3)), " (-2)");
+    }
+}
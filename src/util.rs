@@ -1,12 +1,50 @@
-use crate::components::gitlab::types::ProjectFeatureAccessLevel;
-use anyhow::{Context, Result};
+use crate::error::ConfigError;
+use crate::vault_secrets;
+
+use anyhow::{bail, Context, Result};
 use difference::{Changeset, Difference};
 use gitlab::api::common::AccessLevel;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+use tracing::info;
+
+/// Decides whether to colorize stdout, honoring `CLICOLOR_FORCE` and `NO_COLOR`
+/// and only coloring terminals otherwise.
+fn color_choice(
+    no_color: Option<String>,
+    clicolor_force: Option<String>,
+    is_tty: bool,
+) -> ColorChoice {
+    if clicolor_force.is_some_and(|value| value != "0") {
+        return ColorChoice::Always;
+    }
+    if no_color.is_some_and(|value| !value.is_empty()) || !is_tty {
+        return ColorChoice::Never;
+    }
+    ColorChoice::Auto
+}
+
+fn stdout_color_choice() -> ColorChoice {
+    color_choice(
+        env::var("NO_COLOR").ok(),
+        env::var("CLICOLOR_FORCE").ok(),
+        atty::is(atty::Stream::Stdout),
+    )
+}
 
-pub fn print_diff(text1: &str, text2: &str) -> Result<()> {
+pub fn print_diff(text1: &str, text2: &str, rule: &str) -> Result<()> {
     let Changeset { diffs, .. } = Changeset::new(text1, text2, "\n");
 
-    let mut stdout = term::stdout().context("failed to get stdout")?;
+    let stdout = StandardStream::stdout(stdout_color_choice());
+    let mut stdout = stdout.lock();
+
+    stdout.set_color(ColorSpec::new().set_fg(Some(Color::Cyan)))?;
+    writeln!(stdout, "# rule: {}", rule)?;
 
     for diff in diffs {
         match diff {
@@ -18,13 +56,13 @@ pub fn print_diff(text1: &str, text2: &str) -> Result<()> {
             }
             Difference::Add(ref x) => {
                 for line in x.lines() {
-                    stdout.fg(term::color::GREEN)?;
+                    stdout.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
                     writeln!(stdout, "+{}", line)?;
                 }
             }
             Difference::Rem(ref x) => {
                 for line in x.lines() {
-                    stdout.fg(term::color::RED)?;
+                    stdout.set_color(ColorSpec::new().set_fg(Some(Color::Red)))?;
                     writeln!(stdout, "-{}", line)?;
                 }
             }
@@ -37,6 +75,194 @@ pub fn print_diff(text1: &str, text2: &str) -> Result<()> {
     Ok(())
 }
 
+/// Formats two texts as side-by-side columns in the style of `diff -y`,
+/// marking changed lines with `|` and lines only on one side with `<` or `>`.
+pub fn format_side_by_side(text1: &str, text2: &str) -> Vec<(char, String)> {
+    let left: Vec<&str> = text1.lines().collect();
+    let right: Vec<&str> = text2.lines().collect();
+    let width = left.iter().map(|line| line.len()).max().unwrap_or(0);
+
+    (0..left.len().max(right.len()))
+        .map(|index| {
+            let (marker, before, after) = match (left.get(index), right.get(index)) {
+                (Some(before), Some(after)) if before == after => (' ', *before, *after),
+                (Some(before), Some(after)) => ('|', *before, *after),
+                (Some(before), None) => ('<', *before, ""),
+                (None, Some(after)) => ('>', "", *after),
+                (None, None) => unreachable!(),
+            };
+            let line = format!("{:width$} {} {}", before, marker, after, width = width);
+            (marker, line.trim_end().to_string())
+        })
+        .collect()
+}
+
+pub fn print_side_by_side(text1: &str, text2: &str, rule: &str) -> Result<()> {
+    let stdout = StandardStream::stdout(stdout_color_choice());
+    let mut stdout = stdout.lock();
+
+    stdout.set_color(ColorSpec::new().set_fg(Some(Color::Cyan)))?;
+    writeln!(stdout, "# rule: {}", rule)?;
+
+    for (marker, line) in format_side_by_side(text1, text2) {
+        match marker {
+            ' ' => stdout.reset()?,
+            '>' => stdout.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?,
+            '<' => stdout.set_color(ColorSpec::new().set_fg(Some(Color::Red)))?,
+            _ => stdout.set_color(ColorSpec::new().set_fg(Some(Color::Yellow)))?,
+        }
+        writeln!(stdout, "{}", line)?;
+    }
+
+    stdout.reset()?;
+    stdout.flush()?;
+
+    Ok(())
+}
+
+/// Formats a unified diff without colors, e.g. for embedding into markdown.
+pub fn format_diff(text1: &str, text2: &str) -> String {
+    let Changeset { diffs, .. } = Changeset::new(text1, text2, "\n");
+
+    let mut result = String::new();
+    for diff in diffs {
+        let (prefix, x) = match diff {
+            Difference::Same(ref x) => (' ', x),
+            Difference::Add(ref x) => ('+', x),
+            Difference::Rem(ref x) => ('-', x),
+        };
+        for line in x.lines() {
+            result.push(prefix);
+            result.push_str(line);
+            result.push('\n');
+        }
+    }
+    result
+}
+
+/// Returns true when running inside a GitLab CI job.
+pub fn is_gitlab_ci() -> bool {
+    env::var("GITLAB_CI").is_ok_and(|value| value == "true")
+}
+
+/// Formats a GitLab CI section name, which may only contain `[a-z0-9_.-]`.
+fn ci_section_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c.to_ascii_lowercase() {
+            c @ ('a'..='z' | '0'..='9' | '_' | '.' | '-') => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Formats the marker starting a collapsed GitLab CI job log section.
+pub fn format_ci_section_start(name: &str, header: &str, timestamp: u64) -> String {
+    format!(
+        "\x1b[0Ksection_start:{}:{}[collapsed=true]\r\x1b[0K{}",
+        timestamp,
+        ci_section_name(name),
+        header
+    )
+}
+
+/// Formats the marker ending a GitLab CI job log section.
+pub fn format_ci_section_end(name: &str, timestamp: u64) -> String {
+    format!(
+        "\x1b[0Ksection_end:{}:{}\r\x1b[0K",
+        timestamp,
+        ci_section_name(name)
+    )
+}
+
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Logs periodic progress lines for long running loops.
+pub struct Progress {
+    label: String,
+    total: usize,
+    done: usize,
+    last_logged: Instant,
+}
+
+impl Progress {
+    pub fn new(label: &str, total: usize) -> Progress {
+        Progress {
+            label: label.to_string(),
+            total,
+            done: 0,
+            last_logged: Instant::now(),
+        }
+    }
+
+    /// Grows the total for loops that discover more work while running.
+    pub fn add_total(&mut self, additional: usize) {
+        self.total += additional;
+    }
+
+    pub fn inc(&mut self) {
+        self.done += 1;
+        if self.last_logged.elapsed() >= PROGRESS_INTERVAL {
+            info!("{}", self.message());
+            self.last_logged = Instant::now();
+        }
+    }
+
+    fn message(&self) -> String {
+        let percent = match self.total {
+            0 => 100,
+            total => self.done * 100 / total,
+        };
+        format!(
+            "{}: {}/{} ({}%)",
+            self.label, self.done, self.total, percent
+        )
+    }
+}
+
+fn is_email_local_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "._%+-".contains(c)
+}
+
+fn is_email_domain_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || ".-".contains(c)
+}
+
+/// Masks the local part of all email addresses in the text, keeping only its
+/// first character and the domain.
+pub fn redact_emails(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut redacted = String::with_capacity(text.len());
+    let mut index = 0;
+    while index < chars.len() {
+        let start = index;
+        while index < chars.len() && is_email_local_char(chars[index]) {
+            index += 1;
+        }
+        let local_end = index;
+        if local_end > start && index < chars.len() && chars[index] == '@' {
+            let domain_start = index + 1;
+            let mut domain_end = domain_start;
+            while domain_end < chars.len() && is_email_domain_char(chars[domain_end]) {
+                domain_end += 1;
+            }
+            let domain: String = chars[domain_start..domain_end].iter().collect();
+            if domain.contains('.') {
+                redacted.push(chars[start]);
+                redacted.push_str("***@");
+                redacted.push_str(&domain);
+                index = domain_end;
+                continue;
+            }
+        }
+        redacted.extend(&chars[start..local_end]);
+        if index < chars.len() {
+            redacted.push(chars[index]);
+            index += 1;
+        }
+    }
+    redacted
+}
+
 pub fn access_level_from_u64(access_level: u64) -> AccessLevel {
     match access_level {
         60 => AccessLevel::Admin,
@@ -50,50 +276,335 @@ pub fn access_level_from_u64(access_level: u64) -> AccessLevel {
     }
 }
 
-pub fn format_gitlab_member_access(
-    namespace: &str,
-    username: &str,
-    access_level: AccessLevel,
-) -> String {
-    format!(
-        "gitlab_member_access {{\n\
-        \tnamespace    = {}\n\
-        \tusername     = {}\n\
-        \taccess_level = {}\n\
-        }}",
-        namespace,
-        username,
-        access_level.as_str()
-    )
+pub fn access_level_from_str(access_level: &str) -> Option<AccessLevel> {
+    match access_level {
+        "owner" => Some(AccessLevel::Owner),
+        "maintainer" => Some(AccessLevel::Maintainer),
+        "developer" => Some(AccessLevel::Developer),
+        "reporter" => Some(AccessLevel::Reporter),
+        "guest" => Some(AccessLevel::Guest),
+        "minimal" => Some(AccessLevel::Minimal),
+        _ => None,
+    }
 }
 
-pub fn format_gitlab_user(username: &str, admin: bool) -> String {
-    format!(
-        "gitlab_user {{\n\
-        \tusername = {}\n\
-        \tadmin    = {}\n\
-        }}",
-        username, admin,
-    )
+pub fn format_separator() -> String {
+    "-".repeat(72)
 }
 
-pub fn format_gitlab_project_settings(
-    namespace: &str,
-    request_access_enabled: bool,
-    snippets_access_level: ProjectFeatureAccessLevel,
-) -> String {
-    format!(
-        "gitlab_project_setting {{\n\
-        \tnamespace              = {}\n\
-        \trequest_access_enabled = {}\n\
-        \tsnippets_access_level  = {}\n\
-        }}",
-        namespace,
-        request_access_enabled,
-        snippets_access_level.as_str(),
-    )
+pub fn unix_timestamp() -> Result<u64> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("System time is before the unix epoch")?
+        .as_secs())
 }
 
-pub fn format_separator() -> String {
-    "-".repeat(72)
+/// Parses `historical=current` account alias pairs separated by commas into a
+/// map of current usernames to their historical usernames or emails.
+pub fn parse_account_aliases(aliases: &str) -> HashMap<String, Vec<String>> {
+    let mut result: HashMap<String, Vec<String>> = HashMap::new();
+    for (historical, current) in aliases
+        .split(',')
+        .filter_map(|alias| alias.split_once('='))
+        .map(|(historical, current)| (historical.trim(), current.trim()))
+        .filter(|(historical, current)| !historical.is_empty() && !current.is_empty())
+    {
+        result
+            .entry(current.to_string())
+            .or_default()
+            .push(historical.to_string());
+    }
+    result
+}
+
+/// Parses `group=access_level` pairs separated by commas into a map of GitLab
+/// group paths to the access level their team members get.
+pub fn parse_access_level_overrides(overrides: &str) -> Result<HashMap<String, AccessLevel>> {
+    let mut result = HashMap::new();
+    for (group, access_level) in overrides
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(group, access_level)| (group.trim(), access_level.trim()))
+        .filter(|(group, _)| !group.is_empty())
+    {
+        let access_level = access_level_from_str(access_level)
+            .with_context(|| format!("Invalid access level '{}' for {}", access_level, group))?;
+        result.insert(group.to_string(), access_level);
+    }
+    Ok(result)
+}
+
+#[cfg(feature = "keyring")]
+const KEYRING_SERVICE: &str = "gluebuddy";
+
+/// Looks up a secret in the `NAME` env var, in the file named by `NAME_FILE`
+/// or in the systemd credential `NAME` passed via `LoadCredential`, so that
+/// secrets do not have to be part of the environment. With
+/// `GLUEBUDDY_SECRET_BACKEND` set to `keyring` or `vault` the OS keyring or the
+/// secrets fetched from Vault are used last.
+pub fn secret(name: &str) -> Result<String> {
+    let value = match local_secret(name)? {
+        Some(value) => Some(value),
+        None => match env::var("GLUEBUDDY_SECRET_BACKEND").as_deref() {
+            Ok("keyring") => keyring_secret(name)?,
+            Ok("vault") => vault_secrets::get(name),
+            Ok(backend) => bail!("Unknown secret backend {}", backend),
+            Err(_) => None,
+        },
+    };
+    Ok(value.ok_or_else(|| ConfigError::MissingSecret(name.to_string()))?)
+}
+
+/// Returns the value of a required env var.
+pub fn required_env(name: &str) -> Result<String> {
+    Ok(env::var(name).map_err(|_| ConfigError::Missing(name.to_string()))?)
+}
+
+/// Reads the secret stored for the `gluebuddy` service with the variable name
+/// as username from the secret service or keyutils.
+#[cfg(feature = "keyring")]
+fn keyring_secret(name: &str) -> Result<Option<String>> {
+    match keyring::Entry::new(KEYRING_SERVICE, name).get_password() {
+        Ok(value) => Ok(Some(value)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("Failed to read {} from keyring", name)),
+    }
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_secret(_name: &str) -> Result<Option<String>> {
+    bail!("gluebuddy was built without the keyring feature")
+}
+
+/// Looks up a secret without asking the secret backend.
+pub fn local_secret(name: &str) -> Result<Option<String>> {
+    lookup_secret(name, |var| env::var(var).ok())
+}
+
+fn lookup_secret(name: &str, var: impl Fn(&str) -> Option<String>) -> Result<Option<String>> {
+    if let Some(value) = var(name) {
+        return Ok(Some(value));
+    }
+    let path = match (var(&format!("{}_FILE", name)), var("CREDENTIALS_DIRECTORY")) {
+        (Some(path), _) => Path::new(&path).to_path_buf(),
+        (None, Some(directory)) if Path::new(&directory).join(name).exists() => {
+            Path::new(&directory).join(name)
+        }
+        _ => return Ok(None),
+    };
+    let value = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read secret {} from {}", name, path.display()))?;
+    Ok(Some(value.trim_end_matches(&['\r', '\n'][..]).to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("", "")]
+    #[case("no email here", "no email here")]
+    #[case("alice@archlinux.org", "a***@archlinux.org")]
+    #[case(
+        "email = <bob.builder+aur@example.com>, @mention",
+        "email = <b***@example.com>, @mention"
+    )]
+    #[case("user@localhost stays", "user@localhost stays")]
+    fn redact_emails_test(#[case] text: &str, #[case] expected: &str) {
+        assert_eq!(redact_emails(text), expected);
+    }
+
+    #[test]
+    fn format_side_by_side_test() {
+        let lines = format_side_by_side(
+            "gitlab_user {\n\tusername = alice\n\tadmin    = true\n}",
+            "gitlab_user {\n\tusername = alice\n\tadmin    = false\n}",
+        );
+        assert_eq!(
+            lines,
+            vec![
+                (' ', "gitlab_user {       gitlab_user {".to_string()),
+                (' ', "\tusername = alice   \tusername = alice".to_string()),
+                ('|', "\tadmin    = true  | \tadmin    = false".to_string()),
+                (' ', "}                   }".to_string()),
+            ]
+        );
+
+        let lines = format_side_by_side("", "gitlab_user {\n}");
+        assert_eq!(
+            lines,
+            vec![
+                ('>', " > gitlab_user {".to_string()),
+                ('>', " > }".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn progress_message_test() {
+        let mut progress = Progress::new("Resolving GitLab users", 3);
+        assert_eq!(progress.message(), "Resolving GitLab users: 0/3 (0%)");
+        progress.inc();
+        progress.inc();
+        assert_eq!(progress.message(), "Resolving GitLab users: 2/3 (66%)");
+        progress.add_total(1);
+        assert_eq!(progress.message(), "Resolving GitLab users: 2/4 (50%)");
+        assert_eq!(
+            Progress::new("Traversing GitLab groups", 0).message(),
+            "Traversing GitLab groups: 0/0 (100%)"
+        );
+    }
+
+    #[test]
+    fn format_ci_section_test() {
+        assert_eq!(
+            format_ci_section_start(
+                "gitlab_member_access archlinux:Alice",
+                "add archlinux:Alice",
+                1650000000
+            ),
+            "\x1b[0Ksection_start:1650000000:gitlab_member_access_archlinux_alice\
+            [collapsed=true]\r\x1b[0Kadd archlinux:Alice"
+        );
+        assert_eq!(
+            format_ci_section_end("gitlab_member_access archlinux:Alice", 1650000001),
+            "\x1b[0Ksection_end:1650000001:gitlab_member_access_archlinux_alice\r\x1b[0K"
+        );
+    }
+
+    #[rstest]
+    #[case(None, None, true, ColorChoice::Auto)]
+    #[case(None, None, false, ColorChoice::Never)]
+    #[case(Some("1"), None, true, ColorChoice::Never)]
+    #[case(Some(""), None, true, ColorChoice::Auto)]
+    #[case(None, Some("1"), false, ColorChoice::Always)]
+    #[case(None, Some("0"), false, ColorChoice::Never)]
+    #[case(Some("1"), Some("1"), false, ColorChoice::Always)]
+    fn color_choice_test(
+        #[case] no_color: Option<&str>,
+        #[case] clicolor_force: Option<&str>,
+        #[case] is_tty: bool,
+        #[case] expected: ColorChoice,
+    ) {
+        assert_eq!(
+            color_choice(
+                no_color.map(str::to_string),
+                clicolor_force.map(str::to_string),
+                is_tty
+            ),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case("", vec![])]
+    #[case("old=new", vec![("new", vec!["old"])])]
+    #[case("old=new, older = new,broken", vec![("new", vec!["old", "older"])])]
+    #[case("a=b,c=d", vec![("b", vec!["a"]), ("d", vec!["c"])])]
+    fn parse_account_aliases_test(#[case] aliases: &str, #[case] expected: Vec<(&str, Vec<&str>)>) {
+        let expected: HashMap<String, Vec<String>> = expected
+            .into_iter()
+            .map(|(current, historical)| {
+                (
+                    current.to_string(),
+                    historical
+                        .into_iter()
+                        .map(|name| name.to_string())
+                        .collect(),
+                )
+            })
+            .collect();
+        assert_eq!(parse_account_aliases(aliases), expected);
+    }
+
+    #[rstest]
+    #[case("", Some(vec![]))]
+    #[case(
+        "archlinux/teams/staff=developer",
+        Some(vec![("archlinux/teams/staff", AccessLevel::Developer)])
+    )]
+    #[case(
+        "a = reporter,broken, b=minimal",
+        Some(vec![("a", AccessLevel::Reporter), ("b", AccessLevel::Minimal)])
+    )]
+    #[case("a=admin", None)]
+    #[case("a=", None)]
+    fn parse_access_level_overrides_test(
+        #[case] overrides: &str,
+        #[case] expected: Option<Vec<(&str, AccessLevel)>>,
+    ) {
+        let expected: Option<HashMap<String, AccessLevel>> = expected.map(|expected| {
+            expected
+                .into_iter()
+                .map(|(group, access_level)| (group.to_string(), access_level))
+                .collect()
+        });
+        assert_eq!(parse_access_level_overrides(overrides).ok(), expected);
+    }
+
+    #[test]
+    fn lookup_secret_test() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("token");
+        fs::write(&file, "from-file\n").unwrap();
+        fs::write(
+            directory.path().join("GLUEBUDDY_TOKEN"),
+            "from-credential\n",
+        )
+        .unwrap();
+        let file = file.to_str().unwrap().to_string();
+        let credentials = directory.path().to_str().unwrap().to_string();
+
+        let vars = |vars: Vec<(&'static str, String)>| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.clone())
+            }
+        };
+        assert_eq!(
+            lookup_secret(
+                "GLUEBUDDY_TOKEN",
+                vars(vec![
+                    ("GLUEBUDDY_TOKEN", "from-env".to_string()),
+                    ("GLUEBUDDY_TOKEN_FILE", file.clone())
+                ])
+            )
+            .unwrap(),
+            Some("from-env".to_string())
+        );
+        assert_eq!(
+            lookup_secret(
+                "GLUEBUDDY_TOKEN",
+                vars(vec![
+                    ("GLUEBUDDY_TOKEN_FILE", file),
+                    ("CREDENTIALS_DIRECTORY", credentials.clone())
+                ])
+            )
+            .unwrap(),
+            Some("from-file".to_string())
+        );
+        assert_eq!(
+            lookup_secret(
+                "GLUEBUDDY_TOKEN",
+                vars(vec![("CREDENTIALS_DIRECTORY", credentials.clone())])
+            )
+            .unwrap(),
+            Some("from-credential".to_string())
+        );
+        assert_eq!(
+            lookup_secret(
+                "GLUEBUDDY_OTHER",
+                vars(vec![("CREDENTIALS_DIRECTORY", credentials)])
+            )
+            .unwrap(),
+            None
+        );
+        assert!(lookup_secret(
+            "GLUEBUDDY_TOKEN",
+            vars(vec![("GLUEBUDDY_TOKEN_FILE", "/nonexistent".to_string())])
+        )
+        .is_err());
+    }
 }
@@ -0,0 +1,174 @@
+//! This module validates the gathered state and reports findings that
+//! gluebuddy can not fix on its own but that need a human to look at.
+//!
+//! ## Rules
+//!
+//! - staff members without a GitLab account
+//! - packagers without a PGP fingerprint attribute
+//! - external contributors without an expiry attribute
+//! - usernames not matching the GitLab username or Arch Linux email
+
+use crate::state::{State, User};
+use crate::util;
+
+use std::fmt::{self, Display, Formatter};
+
+use tracing::warn;
+
+const PGP_FINGERPRINT_ATTRIBUTE: &str = "pgp_fingerprint";
+const EXPIRY_ATTRIBUTE: &str = "expiry";
+const ARCH_LINUX_EMAIL_DOMAIN: &str = "@archlinux.org";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Finding {
+    pub rule: &'static str,
+    pub username: String,
+    pub message: String,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}: {}", self.rule, self.username, self.message)
+    }
+}
+
+impl Finding {
+    fn new(rule: &'static str, user: &User, message: String) -> Finding {
+        Finding {
+            rule,
+            username: user.username.clone(),
+            message,
+        }
+    }
+}
+
+pub fn validate(state: &State) -> Vec<Finding> {
+    let mut users: Vec<&User> = state.users.values().filter(|user| user.enabled).collect();
+    users.sort_by(|a, b| a.username.cmp(&b.username));
+
+    let mut findings = vec![];
+    for user in users {
+        if user.is_staff() && user.gitlab_id.is_none() {
+            findings.push(Finding::new(
+                "staff-without-gitlab",
+                user,
+                "staff member has no GitLab account".to_string(),
+            ));
+        }
+
+        if user.is_packager() && user.attribute(PGP_FINGERPRINT_ATTRIBUTE).is_none() {
+            findings.push(Finding::new(
+                "packager-without-pgp",
+                user,
+                format!("packager has no {} attribute", PGP_FINGERPRINT_ATTRIBUTE),
+            ));
+        }
+
+        if user.is_external_contributor()
+            && !user.is_staff()
+            && user.attribute(EXPIRY_ATTRIBUTE).is_none()
+        {
+            findings.push(Finding::new(
+                "external-without-expiry",
+                user,
+                format!("external contributor has no {} attribute", EXPIRY_ATTRIBUTE),
+            ));
+        }
+
+        if let Some(gitlab_username) = &user.gitlab_username {
+            if !user.is_known_as(gitlab_username) {
+                findings.push(Finding::new(
+                    "gitlab-username-mismatch",
+                    user,
+                    format!("GitLab username is {}", gitlab_username),
+                ));
+            }
+        }
+
+        if let Some(email) = &user.email {
+            if let Some(local_part) = email.strip_suffix(ARCH_LINUX_EMAIL_DOMAIN) {
+                if !local_part.eq(&user.username) {
+                    findings.push(Finding::new(
+                        "email-mismatch",
+                        user,
+                        format!("Arch Linux email is {}", email),
+                    ));
+                }
+            }
+        }
+    }
+    findings
+}
+
+/// Logs the findings instead of printing them, to keep stdout parseable.
+pub fn log_findings(findings: &[Finding]) {
+    for finding in findings {
+        warn!("Finding: {}", finding);
+    }
+}
+
+pub fn print_findings(findings: &[Finding]) {
+    println!("Findings:");
+    for finding in findings {
+        println!("\t{}", finding);
+    }
+    match findings.len() {
+        0 => println!("No findings. State is consistent."),
+        count => println!("{} findings need manual attention.", count),
+    }
+    println!("{}", util::format_separator());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(username: &str, groups: &[&str]) -> User {
+        let mut user = User::new(username.to_string());
+        user.groups = groups.iter().map(|group| group.to_string()).collect();
+        user
+    }
+
+    #[test]
+    fn validate_test() {
+        let mut state = State::default();
+
+        let mut consistent = user("alice", &["/Arch Linux Staff/Developers"]);
+        consistent.gitlab_id = Some(1);
+        consistent.gitlab_username = Some("alice".to_string());
+        consistent.email = Some("alice@archlinux.org".to_string());
+        consistent.attributes.insert(
+            PGP_FINGERPRINT_ATTRIBUTE.to_string(),
+            vec!["ABCDEF".to_string()],
+        );
+
+        let mut inconsistent = user("bob", &["/Arch Linux Staff/Package Maintainers"]);
+        inconsistent.gitlab_username = Some("bobby".to_string());
+        inconsistent.email = Some("robert@archlinux.org".to_string());
+
+        let external = user("carol", &["/External Contributors"]);
+
+        let mut disabled = user("dave", &["/Arch Linux Staff/DevOps"]);
+        disabled.enabled = false;
+
+        for user in [consistent, inconsistent, external, disabled] {
+            state.users.insert(user.username.clone(), user);
+        }
+
+        let findings = validate(&state);
+        let rules: Vec<(&str, &str)> = findings
+            .iter()
+            .map(|finding| (finding.rule, finding.username.as_str()))
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                ("staff-without-gitlab", "bob"),
+                ("packager-without-pgp", "bob"),
+                ("gitlab-username-mismatch", "bob"),
+                ("email-mismatch", "bob"),
+                ("external-without-expiry", "carol"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,199 @@
+//! This module fetches gluebuddy's own secrets from a Vault KV v2 secret when
+//! `GLUEBUDDY_SECRET_BACKEND=vault` is set. Gluebuddy logs in with an AppRole
+//! or a token at startup, keeps the token renewed while running and answers
+//! secret lookups from the fetched key value pairs.
+
+use crate::error::CheckStatus;
+use crate::http_client;
+use crate::util;
+
+use std::collections::HashMap;
+use std::env;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use once_cell::sync::OnceCell;
+use reqwest::{Client, Method, RequestBuilder, Url};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{debug, info, warn};
+
+const SERVICE: &str = "Vault";
+const DEFAULT_SECRETS_PATH: &str = "secret/data/gluebuddy";
+const VAULT_TOKEN: &str = "GLUEBUDDY_VAULT_TOKEN";
+
+static SECRETS: OnceCell<HashMap<String, String>> = OnceCell::new();
+
+#[derive(Debug, Deserialize)]
+struct LoginResponse {
+    auth: TokenInfo,
+}
+
+#[derive(Debug, Deserialize)]
+struct LookupResponse {
+    data: LookupData,
+}
+
+#[derive(Debug, Deserialize)]
+struct LookupData {
+    ttl: u64,
+    renewable: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenInfo {
+    client_token: String,
+    lease_duration: u64,
+    renewable: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct KvResponse {
+    data: KvData,
+}
+
+#[derive(Debug, Deserialize)]
+struct KvData {
+    data: HashMap<String, String>,
+}
+
+struct VaultSecrets {
+    client: Client,
+    url: Url,
+}
+
+/// Returns the secret fetched from Vault, if any.
+pub fn get(name: &str) -> Option<String> {
+    SECRETS.get()?.get(name).cloned()
+}
+
+/// Logs in to Vault and fetches the secrets, must be called before any
+/// component is created.
+pub async fn load(client: Client) -> Result<()> {
+    let url = util::required_env("GLUEBUDDY_VAULT_URL")
+        .context("The vault secret backend requires a Vault url")?;
+    let url = Url::parse(&url).with_context(|| format!("Invalid Vault url {}", url))?;
+    let path = env::var("GLUEBUDDY_VAULT_SECRETS_PATH")
+        .unwrap_or_else(|_| DEFAULT_SECRETS_PATH.to_string());
+    let vault = VaultSecrets { client, url };
+
+    let (token, mut secrets) = match env::var("GLUEBUDDY_VAULT_ROLE_ID") {
+        Ok(role_id) => {
+            let secret_id = util::local_secret("GLUEBUDDY_VAULT_SECRET_ID")?
+                .context("Missing secret GLUEBUDDY_VAULT_SECRET_ID for the Vault AppRole login")?;
+            let token = vault.login(&role_id, &secret_id).await?;
+            let secrets = vault.read(&token.client_token, &path).await?;
+            (token, secrets)
+        }
+        Err(_) => {
+            let client_token = util::local_secret(VAULT_TOKEN)?.with_context(|| {
+                format!(
+                    "Missing secret {} for the vault secret backend",
+                    VAULT_TOKEN
+                )
+            })?;
+            let lookup = vault.lookup_self(&client_token).await?;
+            let secrets = vault.read(&client_token, &path).await?;
+            let token = TokenInfo {
+                client_token,
+                lease_duration: lookup.ttl,
+                renewable: lookup.renewable,
+            };
+            (token, secrets)
+        }
+    };
+    info!("Fetched {} secrets from Vault {}", secrets.len(), path);
+
+    // The Vault component uses the renewed login token unless configured otherwise
+    secrets
+        .entry(VAULT_TOKEN.to_string())
+        .or_insert_with(|| token.client_token.clone());
+    SECRETS
+        .set(secrets)
+        .map_err(|_| anyhow!("Secrets were already fetched from Vault"))?;
+
+    if token.renewable && token.lease_duration > 0 {
+        tokio::spawn(async move { vault.keep_renewed(token).await });
+    }
+    Ok(())
+}
+
+impl VaultSecrets {
+    fn request(&self, method: Method, path: &str, token: Option<&str>) -> Result<RequestBuilder> {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Vault url {} can not be a base", self.url))?
+            .pop_if_empty()
+            .push("v1")
+            .extend(path.split('/').filter(|segment| !segment.is_empty()));
+        let request = self.client.request(method, url);
+        Ok(match token {
+            Some(token) => request.header("X-Vault-Token", token),
+            None => request,
+        })
+    }
+
+    async fn login(&self, role_id: &str, secret_id: &str) -> Result<TokenInfo> {
+        let request = self
+            .request(Method::POST, "auth/approle/login", None)?
+            .json(&json!({ "role_id": role_id, "secret_id": secret_id }));
+        let response: LoginResponse = http_client::send(request)
+            .await?
+            .check_status(SERVICE)
+            .context("Failed to log in to Vault with AppRole")?
+            .json()
+            .await?;
+        Ok(response.auth)
+    }
+
+    async fn lookup_self(&self, token: &str) -> Result<LookupData> {
+        let request = self.request(Method::GET, "auth/token/lookup-self", Some(token))?;
+        let response: LookupResponse = http_client::send(request)
+            .await?
+            .check_status(SERVICE)
+            .context("Failed to look up the Vault token")?
+            .json()
+            .await?;
+        Ok(response.data)
+    }
+
+    async fn read(&self, token: &str, path: &str) -> Result<HashMap<String, String>> {
+        let request = self.request(Method::GET, path, Some(token))?;
+        let response: KvResponse = http_client::send(request)
+            .await?
+            .check_status(SERVICE)
+            .with_context(|| format!("Failed to read secrets from Vault {}", path))?
+            .json()
+            .await?;
+        Ok(response.data.data)
+    }
+
+    async fn renew(&self, token: &str) -> Result<TokenInfo> {
+        let request = self.request(Method::POST, "auth/token/renew-self", Some(token))?;
+        let response: LoginResponse = http_client::send(request)
+            .await?
+            .check_status(SERVICE)
+            .context("Failed to renew the Vault token")?
+            .json()
+            .await?;
+        Ok(response.auth)
+    }
+
+    /// Renews the token halfway through each lease until renewing fails.
+    async fn keep_renewed(&self, mut token: TokenInfo) {
+        loop {
+            tokio::time::sleep(Duration::from_secs(token.lease_duration / 2)).await;
+            match self.renew(&token.client_token).await {
+                Ok(renewed) if renewed.lease_duration > 0 => {
+                    debug!("Renewed Vault token for {}s", renewed.lease_duration);
+                    token.lease_duration = renewed.lease_duration;
+                }
+                Ok(_) => return,
+                Err(err) => {
+                    warn!("{:?}", err);
+                    return;
+                }
+            }
+        }
+    }
+}